@@ -0,0 +1,47 @@
+//! Signature checks of a block worth of transactions: one at a time and in parallel, as
+//! `validate_untrusted_transactions_parallel` does.
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use crypto::transactions::{
+    transaction::{RawTransaction, SignedTransaction},
+    transaction_input::Input,
+    transaction_output::Output,
+};
+use ed25519_dalek::SigningKey;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+fn transactions(count: usize) -> Vec<SignedTransaction> {
+    (0..count)
+        .map(|index| {
+            let mut seed = [0u8; 32];
+            seed[..8].copy_from_slice(&(index as u64).to_be_bytes());
+            let key = SigningKey::from_bytes(&seed);
+            RawTransaction::new(
+                vec![Input::new(seed, 0)],
+                vec![Output::new(key.verifying_key(), 1_000)],
+                key.verifying_key(),
+            )
+            .sign(&key)
+        })
+        .collect()
+}
+
+fn signature_checks(c: &mut Criterion) {
+    let mut group = c.benchmark_group("signature_checks");
+    for count in [16, 256, 2048] {
+        let transactions = transactions(count);
+        group.bench_with_input(
+            BenchmarkId::new("serial", count),
+            &transactions,
+            |b, txs| b.iter(|| txs.iter().all(|tx| tx.check_signature().is_ok())),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("parallel", count),
+            &transactions,
+            |b, txs| b.iter(|| txs.par_iter().all(|tx| tx.check_signature().is_ok())),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, signature_checks);
+criterion_main!(benches);
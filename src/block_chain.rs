@@ -1,68 +1,214 @@
+use std::sync::Arc;
+
 use crate::{
     blocks::{
         block::{Block, BlockValidationError},
+        block_filter::BlockFilter,
+        checkpoints::{Checkpoints, Network},
+        header_chain::{HeaderChain, locator_heights},
         mining_block::MiningBlock,
     },
+    clock::{Clock, SystemClock},
     shared::Hash,
-    transactions::{merkel::get_merkel_hash, transaction::ValidatedTransaction},
-    utxo_map::UTXOMap,
+    transactions::{
+        merkel::{MerkelError, get_merkel_hash, get_witness_hash},
+        transaction::ValidatedTransaction,
+    },
+    utxo_map::{BlockUndo, UTXOMap},
 };
 
+pub const CHAIN_DIFFICULTY: u32 = 33;
+// regtest blocks are mined at once, for local chains and tests
+pub const REGTEST_DIFFICULTY: u32 = 1;
+pub const CHAIN_VERSION: u32 = 0;
+
+/// Difficulty of the blocks of `network`.
+pub fn chain_difficulty(network: Network) -> u32 {
+    match network {
+        Network::Main | Network::Test => CHAIN_DIFFICULTY,
+        Network::Regtest => REGTEST_DIFFICULTY,
+    }
+}
+
 pub struct BlockChain {
     blocks: Vec<Block>,
+    // undo data of each block, to disconnect it on a reorganization
+    undos: Vec<BlockUndo>,
+    // compact filter of each block and its header in the filter-header chain
+    filters: Vec<BlockFilter>,
+    filter_headers: Vec<Hash>,
+    // always holds the headers of `blocks`, and the ones downloaded ahead of them
+    headers: HeaderChain,
     utxos: UTXOMap,
-    difficulty: u32,
-    version: u32,
+    clock: Arc<dyn Clock>,
+}
+impl Default for BlockChain {
+    fn default() -> Self {
+        Self::new()
+    }
 }
+
 impl BlockChain {
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self::for_network(Network::Main, clock)
+    }
+    /// Empty chain with the difficulty and the checkpoints of `network`.
+    pub fn for_network(network: Network, clock: Arc<dyn Clock>) -> Self {
         Self {
             blocks: vec![],
-            difficulty: 33,
-            version: 0,
+            undos: vec![],
+            filters: vec![],
+            filter_headers: vec![],
+            headers: HeaderChain::new(chain_difficulty(network), CHAIN_VERSION)
+                .with_checkpoints(Checkpoints::for_network(network)),
             utxos: UTXOMap::new(),
+            clock,
         }
     }
+    /// To be called before the first block is added.
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints) -> Self {
+        self.headers = self.headers.with_checkpoints(checkpoints);
+        self
+    }
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+    pub fn get_clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
     pub fn len(&self) -> usize {
         self.blocks.len()
     }
-    pub fn peak<'a>(&'a self) -> &'a Block {
-        &self.blocks.last().unwrap()
+    /// Before the genesis block is added.
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+    pub fn peak(&self) -> &Block {
+        self.blocks.last().unwrap()
+    }
+    pub fn get_block(&self, height: usize) -> Option<&Block> {
+        self.blocks.get(height)
+    }
+    pub fn get_block_by_hash(&self, hash: &Hash) -> Option<&Block> {
+        let height = self.headers.get_height(hash)?;
+        self.blocks.get(height)
+    }
+    pub fn get_filter(&self, height: usize) -> Option<&BlockFilter> {
+        self.filters.get(height)
+    }
+    pub fn get_filter_header(&self, height: usize) -> Option<&Hash> {
+        self.filter_headers.get(height)
+    }
+    pub fn get_headers(&self) -> &HeaderChain {
+        &self.headers
     }
     pub fn get_coin_base_amount(&self) -> u64 {
         1_000_000
     }
-    pub fn get_mining_block(&self, transactions: &[ValidatedTransaction]) -> MiningBlock {
-        assert!(transactions.len() != 0);
-
-        let merkel_root = get_merkel_hash(transactions);
-        MiningBlock::from_black_chain(&self, merkel_root)
+    pub fn get_mining_block(
+        &self,
+        transactions: &[ValidatedTransaction],
+    ) -> Result<MiningBlock, MerkelError> {
+        let merkel_root = get_merkel_hash(transactions)?;
+        let witness_root = get_witness_hash(transactions)?;
+        Ok(MiningBlock::from_black_chain(
+            self,
+            merkel_root,
+            witness_root,
+        ))
     }
     pub fn get_version(&self) -> u32 {
-        self.version
+        self.headers.get_version()
     }
     pub fn get_difficulty(&self) -> u32 {
-        self.difficulty
+        self.headers.get_difficulty()
     }
     pub fn get_previous_hash(&self) -> Hash {
-        if self.blocks.len() == 0 {
+        if self.blocks.is_empty() {
             Hash::default()
         } else {
-            self.peak().get_hash().clone()
+            *self.peak().get_hash()
         }
     }
+    /// Median time past of the next block.
+    pub fn median_time_past(&self) -> u64 {
+        self.headers.median_time_past(self.len())
+    }
+    /// Validates and stores a header ahead of its block (headers-first sync).
+    pub fn add_header(&mut self, header: MiningBlock) -> Result<Hash, BlockValidationError> {
+        let now = self.now();
+        self.headers.add_header(header, now)
+    }
+    /// Validates headers received from a peer, see `HeaderChain::accept_headers`.
+    /// When the header chain switches to a fork, the blocks above it are disconnected.
+    /// Returns the number of new headers.
+    pub fn accept_headers(
+        &mut self,
+        headers: Vec<MiningBlock>,
+    ) -> Result<usize, BlockValidationError> {
+        let now = self.now();
+        let new_header_count = self.headers.accept_headers(&headers, now)?;
+        while let Some(block) = self.blocks.last()
+            && self.headers.get_hash(self.len() - 1) != Some(block.get_hash())
+        {
+            self.disconnect_tip();
+        }
+        Ok(new_header_count)
+    }
+    /// Locator of the block chain, from `peak()` back to the first block.
+    pub fn block_locator(&self) -> Vec<Hash> {
+        locator_heights(self.len())
+            .into_iter()
+            .map(|height| *self.blocks[height].get_hash())
+            .collect()
+    }
+    /// Height of the first block after the most recent locator hash we have,
+    /// 0 when none of them is known.
+    pub fn find_fork_point(&self, locator: &[Hash]) -> usize {
+        locator
+            .iter()
+            .find_map(|hash| {
+                self.headers
+                    .get_height(hash)
+                    .filter(|height| *height < self.len())
+            })
+            .map_or(0, |height| height + 1)
+    }
+    /// Block holding the transaction `tx_id` and the index of the transaction in it,
+    /// searching from the tip.
+    pub fn find_transaction(&self, tx_id: &Hash) -> Option<(&Block, usize)> {
+        self.blocks.iter().rev().find_map(|block| {
+            block
+                .get_transactions()
+                .iter()
+                .position(|tx| tx.get_hash() == tx_id)
+                .map(|tx_index| (block, tx_index))
+        })
+    }
+    /// Checks that `mining_block` can be the next block. When its header was already
+    /// downloaded, it only has to be the expected one.
     pub fn check_compatibility(
         &self,
         mining_block: &MiningBlock,
     ) -> Result<(), BlockValidationError> {
-        if self.get_difficulty() > mining_block.get_difficulty() {
-            return Err(BlockValidationError::DifficultyTooLow);
-        }
-        if self.version > mining_block.get_version() {
-            return Err(BlockValidationError::VersionTooLow);
-        }
-        if self.peak().get_hash() != mining_block.get_previous_hash() {
-            return Err(BlockValidationError::WrongPreviousHash);
+        let height = self.len();
+        match self.headers.get_hash(height) {
+            Some(expected) => {
+                let actual = mining_block.hash();
+                if &actual != expected {
+                    return Err(BlockValidationError::NotInHeaderChain {
+                        expected: *expected,
+                        actual,
+                    });
+                }
+            }
+            None => {
+                self.headers
+                    .check_header(mining_block, height, self.now())?;
+            }
         }
         Ok(())
     }
@@ -70,9 +216,204 @@ impl BlockChain {
         &self.utxos
     }
     pub fn update(&mut self, block: Block) {
-        for tx in block.get_transactions() {
-            self.utxos.update_transaction(tx);
+        if self.headers.len() == self.blocks.len() {
+            self.headers
+                .push(block.get_mining().clone(), *block.get_hash());
         }
+        let undo = self.utxos.connect_block(block.get_transactions());
+        self.undos.push(undo);
+        let filter = BlockFilter::from_block(&block);
+        let previous_filter_header = self.filter_headers.last().copied().unwrap_or_default();
+        self.filter_headers
+            .push(filter.header(&previous_filter_header));
+        self.filters.push(filter);
         self.blocks.push(block);
     }
+    /// Removes the last block and restores the outputs it spent. Its header stays.
+    pub fn disconnect_tip(&mut self) -> Option<Block> {
+        let block = self.blocks.pop()?;
+        let undo = self.undos.pop()?;
+        self.filters.pop();
+        self.filter_headers.pop();
+        self.utxos.disconnect_block(block.get_transactions(), undo);
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blocks::{block_filter::FilterMatcher, header_chain::MAX_FUTURE_BLOCK_TIME},
+        clock::ManualClock,
+        test_utils::{TEST_TIME, add_block, chain_of, mine_block, signing_key},
+        transactions::{
+            transaction::RawTransaction, transaction_input::Input, transaction_output::Output,
+        },
+    };
+
+    // offsets to TEST_TIME of the blocks, not always increasing
+    const BLOCK_TIMES: [u64; 12] = [
+        0, 600, 1200, 900, 1800, 2400, 2000, 3000, 3600, 3300, 4200, 4800,
+    ];
+
+    fn chain_at_times(clock: &Arc<ManualClock>) -> BlockChain {
+        let mut chain = BlockChain::for_network(Network::Regtest, clock.clone());
+        for offset in BLOCK_TIMES {
+            clock.set(TEST_TIME + offset);
+            add_block(&mut chain, &signing_key(1), vec![]);
+            assert_eq!(
+                chain.peak().get_mining().get_timestamp(),
+                TEST_TIME + offset
+            );
+        }
+        chain
+    }
+
+    fn header_at(chain: &BlockChain, timestamp: u64) -> MiningBlock {
+        let mut header = MiningBlock::new_header(
+            chain.get_previous_hash(),
+            REGTEST_DIFFICULTY,
+            [0; 32],
+            timestamp,
+        );
+        header.mine().unwrap().get_mining().clone()
+    }
+
+    #[test]
+    fn a_block_must_be_after_the_median_time_past() {
+        let clock = Arc::new(ManualClock::new(TEST_TIME));
+        let chain = chain_at_times(&clock);
+        // median of the last 11: 600 900 1200 1800 2000 [2400] 3000 3300 3600 4200 4800
+        let median_time_past = TEST_TIME + 2400;
+        assert_eq!(chain.median_time_past(), median_time_past);
+
+        let check = |timestamp| {
+            chain.get_headers().check_header(
+                &header_at(&chain, timestamp),
+                chain.len(),
+                chain.now(),
+            )
+        };
+        assert_eq!(
+            check(median_time_past).err(),
+            Some(BlockValidationError::TimestampTooOld {
+                median_time_past,
+                actual: median_time_past,
+            })
+        );
+        assert!(check(median_time_past + 1).is_ok());
+
+        // a clock behind the chain still mines a valid block
+        clock.set(TEST_TIME);
+        let block = mine_block(&chain, &signing_key(1), vec![]);
+        assert_eq!(block.get_mining().get_timestamp(), median_time_past + 1);
+        assert!(Block::valid_new_block(&chain, block.to_untrusted()).is_ok());
+    }
+
+    #[test]
+    fn a_block_from_the_future_waits_for_the_clock() {
+        let clock = Arc::new(ManualClock::new(TEST_TIME));
+        let mut chain = chain_at_times(&clock);
+        let now = chain.now();
+        let too_far = now + MAX_FUTURE_BLOCK_TIME + 1;
+        assert_eq!(
+            chain.add_header(header_at(&chain, too_far)),
+            Err(BlockValidationError::TimestampTooFarInFuture {
+                max_timestamp: now + MAX_FUTURE_BLOCK_TIME,
+                actual: too_far,
+            })
+        );
+        assert!(
+            chain
+                .get_headers()
+                .check_header(&header_at(&chain, too_far - 1), chain.len(), now)
+                .is_ok()
+        );
+
+        // a whole block mined by a node whose clock is ahead
+        clock.set(too_far + 100);
+        let block = mine_block(&chain, &signing_key(2), vec![]);
+        clock.set(now);
+        assert_eq!(
+            Block::valid_new_block(&chain, block.to_untrusted()).err(),
+            Some(BlockValidationError::TimestampTooFarInFuture {
+                max_timestamp: now + MAX_FUTURE_BLOCK_TIME,
+                actual: too_far + 100,
+            })
+        );
+        clock.advance(101);
+        let block = Block::valid_new_block(&chain, block.to_untrusted()).unwrap();
+        chain.update(block);
+        assert_eq!(chain.len(), BLOCK_TIMES.len() + 1);
+    }
+
+    #[test]
+    fn each_filter_header_commits_to_the_ones_before() {
+        let mut chain = chain_of(5, &signing_key(1));
+        let coinbase = Input::new(
+            *chain.get_block(0).unwrap().get_transactions()[0].get_hash(),
+            0,
+        );
+        let payment = RawTransaction::new(
+            vec![coinbase],
+            vec![Output::new(
+                signing_key(2).verifying_key(),
+                chain.get_coin_base_amount(),
+            )],
+            signing_key(1).verifying_key(),
+        )
+        .sign(&signing_key(1));
+        add_block(&mut chain, &signing_key(1), vec![payment]);
+        add_block(&mut chain, &signing_key(1), vec![]);
+
+        let mut previous = Hash::default();
+        for height in 0..chain.len() {
+            let block = chain.get_block(height).unwrap();
+            let filter = chain.get_filter(height).unwrap();
+            assert_eq!(filter, &BlockFilter::from_block(block));
+            assert_eq!(
+                chain.get_filter_header(height),
+                Some(&filter.header(&previous))
+            );
+            previous = filter.header(&previous);
+        }
+        assert_eq!(chain.get_filter_header(chain.len()), None);
+
+        let matching_heights = |matcher: &FilterMatcher, chain: &BlockChain| {
+            (0..chain.len())
+                .filter(|&height| {
+                    let block_hash = chain.get_block(height).unwrap().get_hash();
+                    matcher.matches(block_hash, chain.get_filter(height).unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut payee = FilterMatcher::new();
+        payee.add_pubkey(&signing_key(2).verifying_key());
+        assert_eq!(matching_heights(&payee, &chain), [5]);
+        let mut spender = FilterMatcher::new();
+        spender.add_outpoint(&coinbase);
+        assert_eq!(matching_heights(&spender, &chain), [5]);
+        let mut miner = FilterMatcher::new();
+        miner.add_pubkey(&signing_key(1).verifying_key());
+        assert_eq!(matching_heights(&miner, &chain).len(), chain.len());
+
+        // a disconnected block takes its filter header along
+        let tip_header = *chain.get_filter_header(chain.len() - 1).unwrap();
+        let tip = chain.disconnect_tip().unwrap();
+        assert_eq!(chain.get_filter_header(chain.len()), None);
+        chain.update(tip);
+        assert_eq!(chain.get_filter_header(chain.len() - 1), Some(&tip_header));
+
+        // a branch from the same first blocks shares their filter headers only
+        let mut branch = chain_of(5, &signing_key(1));
+        add_block(&mut branch, &signing_key(3), vec![]);
+        for height in 0..5 {
+            assert_eq!(
+                branch.get_filter_header(height),
+                chain.get_filter_header(height)
+            );
+        }
+        assert_ne!(branch.get_filter_header(5), chain.get_filter_header(5));
+    }
 }
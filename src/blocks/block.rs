@@ -1,23 +1,121 @@
 use std::collections::HashSet;
 
 use base64::{Engine, prelude::BASE64_STANDARD};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 
 use crate::{
     block_chain::BlockChain,
     blocks::mining_block::MiningBlock,
     shared::{Hash, meet_difficulty},
-    transactions::transaction::{
-        SignedTransaction, TransactionValidationError, ValidatedTransaction,
+    transactions::{
+        merkel::{MerkelError, get_merkel_hash_from_ids},
+        transaction::{
+            SignedTransaction, StatelessChecks, TransactionValidationError, ValidatedTransaction,
+        },
     },
     utxo_map::UTXOMap,
 };
 
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+pub const MAX_BLOCK_SIG_OPS: usize = 20_000;
+
 pub struct UntrustedBlock {
     data: MiningBlock,
     hash: Hash,
     transactions: Vec<SignedTransaction>,
 }
 
+impl UntrustedBlock {
+    pub fn new(data: MiningBlock, hash: Hash, transactions: Vec<SignedTransaction>) -> Self {
+        Self {
+            data,
+            hash,
+            transactions,
+        }
+    }
+    pub fn get_mining(&self) -> &MiningBlock {
+        &self.data
+    }
+    pub fn get_hash(&self) -> &Hash {
+        &self.hash
+    }
+    /// Size on the wire: header | transaction count (u32) | transactions
+    pub fn serialized_size(&self) -> usize {
+        MiningBlock::SERIALIZED_SIZE
+            + 4
+            + self
+                .transactions
+                .iter()
+                .map(|tx| tx.serialized_size())
+                .sum::<usize>()
+    }
+    /// Checks a block whose parent is unknown can be kept until the parent arrives:
+    /// its hash, its proof of work at `min_difficulty` and its size.
+    pub fn check_orphan(&self, min_difficulty: u32) -> Result<(), BlockValidationError> {
+        let expected_hash = self.data.hash();
+        if expected_hash != self.hash {
+            return Err(BlockValidationError::WrongHash {
+                expected: expected_hash,
+                actual: self.hash,
+            });
+        }
+        let difficulty = self.data.get_difficulty();
+        if difficulty < min_difficulty {
+            return Err(BlockValidationError::DifficultyTooLow {
+                expected: min_difficulty,
+                actual: difficulty,
+            });
+        }
+        if !meet_difficulty(&self.hash, difficulty) {
+            return Err(BlockValidationError::InsufficientProofOfWork { difficulty });
+        }
+        self.check_limits()
+    }
+    // cheap checks done before anything costly on a block coming from a peer
+    fn check_limits(&self) -> Result<(), BlockValidationError> {
+        let size = self.serialized_size();
+        if size > MAX_BLOCK_SIZE {
+            return Err(BlockValidationError::BlockTooLarge {
+                size,
+                max: MAX_BLOCK_SIZE,
+            });
+        }
+        let sig_op_count: usize = self.transactions.iter().map(|tx| tx.sig_op_count()).sum();
+        if sig_op_count > MAX_BLOCK_SIG_OPS {
+            return Err(BlockValidationError::TooManySigOps {
+                count: sig_op_count,
+                max: MAX_BLOCK_SIG_OPS,
+            });
+        }
+        Ok(())
+    }
+    fn check_commitments(&self) -> Result<(), BlockValidationError> {
+        let merkel_root =
+            get_merkel_hash_from_ids(self.transactions.iter().map(|tx| tx.get_hash()))
+                .map_err(BlockValidationError::InvalidTransactionCount)?;
+        if &merkel_root != self.data.get_merkel_root() {
+            return Err(BlockValidationError::WrongMerkelRoot {
+                expected: merkel_root,
+                actual: *self.data.get_merkel_root(),
+            });
+        }
+        let witness_hashes: Vec<Hash> = self
+            .transactions
+            .iter()
+            .map(|tx| tx.witness_hash())
+            .collect();
+        let witness_root = get_merkel_hash_from_ids(witness_hashes.iter())
+            .map_err(BlockValidationError::InvalidTransactionCount)?;
+        if &witness_root != self.data.get_witness_root() {
+            return Err(BlockValidationError::WrongWitnessRoot {
+                expected: witness_root,
+                actual: *self.data.get_witness_root(),
+            });
+        }
+        Ok(())
+    }
+}
+
 pub struct Block {
     data: MiningBlock,
     hash: Hash,
@@ -36,25 +134,52 @@ impl Block {
             None
         }
     }
+    /// Attaches the transactions the mined header commits to.
+    pub fn with_transactions(mut self, transactions: Vec<ValidatedTransaction>) -> Self {
+        self.transactions = transactions;
+        self
+    }
     pub fn get_mining(&self) -> &MiningBlock {
         &self.data
     }
     pub fn get_hash(&self) -> &Hash {
         &self.hash
     }
+    /// The block as sent to a peer.
+    pub fn to_untrusted(&self) -> UntrustedBlock {
+        UntrustedBlock::new(
+            self.data.clone(),
+            self.hash,
+            self.transactions
+                .iter()
+                .map(|tx| tx.get_signed().clone())
+                .collect(),
+        )
+    }
     pub fn valid_new_block(
         chain: &BlockChain,
         untrusted_block: UntrustedBlock,
     ) -> Result<Block, BlockValidationError> {
-        if untrusted_block.data.hash() != untrusted_block.hash {
-            return Err(BlockValidationError::WrongHash);
+        let expected_hash = untrusted_block.data.hash();
+        if expected_hash != untrusted_block.hash {
+            return Err(BlockValidationError::WrongHash {
+                expected: expected_hash,
+                actual: untrusted_block.hash,
+            });
         }
 
         chain.check_compatibility(&untrusted_block.data)?;
+        untrusted_block.check_limits()?;
+        untrusted_block.check_commitments()?;
 
-        let utxo = chain.get_utxos();
+        let context = TransactionContext {
+            utxos: chain.get_utxos(),
+            height: chain.len(),
+            coinbase_subsidy: chain.get_coin_base_amount(),
+            check_signatures: !chain.get_headers().is_assumed_valid(chain.len()),
+        };
         let validated_transactions =
-            validate_untrusted_transactions(untrusted_block.transactions, utxo)?;
+            validate_untrusted_transactions(untrusted_block.transactions, &context)?;
 
         let new_block = Self {
             data: untrusted_block.data,
@@ -69,45 +194,335 @@ impl Block {
     }
 }
 
+/// What the transactions of a block are validated against.
+pub struct TransactionContext<'a> {
+    pub utxos: &'a UTXOMap,
+    pub height: usize,
+    /// What the coinbase creates on top of the fees of the block.
+    pub coinbase_subsidy: u64,
+    /// False below the assumed valid block: the tx ids are still checked, not the signatures.
+    pub check_signatures: bool,
+}
+
+// under this size the rayon overhead is not worth it
+const PARALLEL_VALIDATION_THRESHOLD: usize = 32;
+
 fn validate_untrusted_transactions(
     untrusted_signed_transactions: Vec<SignedTransaction>,
-    utxos: &UTXOMap,
+    context: &TransactionContext,
+) -> Result<Vec<ValidatedTransaction>, BlockValidationError> {
+    if untrusted_signed_transactions.len() < PARALLEL_VALIDATION_THRESHOLD {
+        validate_untrusted_transactions_serial(untrusted_signed_transactions, context)
+    } else {
+        validate_untrusted_transactions_parallel(untrusted_signed_transactions, context)
+    }
+}
+
+pub fn validate_untrusted_transactions_serial(
+    untrusted_signed_transactions: Vec<SignedTransaction>,
+    context: &TransactionContext,
 ) -> Result<Vec<ValidatedTransaction>, BlockValidationError> {
     let mut spent_map: HashSet<(Hash, usize)> = HashSet::new();
     untrusted_signed_transactions
         .into_iter()
-        .map(|tx| validate_untrusted_transaction(utxos, &mut spent_map, tx))
+        .enumerate()
+        .map(|(tx_index, tx)| {
+            let checks = stateless_checks(&tx, context);
+            validate_untrusted_transaction(context, &mut spent_map, tx_index, tx, checks)
+        })
         .collect::<Result<Vec<ValidatedTransaction>, BlockValidationError>>()
+        .and_then(|transactions| check_coinbase_amount(transactions, context))
+}
+
+/// Runs the stateless checks of every transaction in parallel, then the UTXO dependent
+/// checks in block order. Gives the same result as `validate_untrusted_transactions_serial`.
+pub fn validate_untrusted_transactions_parallel(
+    untrusted_signed_transactions: Vec<SignedTransaction>,
+    context: &TransactionContext,
+) -> Result<Vec<ValidatedTransaction>, BlockValidationError> {
+    let stateless_checks: Vec<StatelessChecks> = untrusted_signed_transactions
+        .par_iter()
+        .map(|tx| stateless_checks(tx, context))
+        .collect();
+
+    let mut spent_map: HashSet<(Hash, usize)> = HashSet::new();
+    untrusted_signed_transactions
+        .into_iter()
+        .zip(stateless_checks)
+        .enumerate()
+        .map(|(tx_index, (tx, checks))| {
+            validate_untrusted_transaction(context, &mut spent_map, tx_index, tx, checks)
+        })
+        .collect::<Result<Vec<ValidatedTransaction>, BlockValidationError>>()
+        .and_then(|transactions| check_coinbase_amount(transactions, context))
+}
+
+// the signatures are checked one by one with verify_strict: ed25519 batch verification
+// has to rule out small order and torsioned points first, which costs more than it saves
+fn stateless_checks(tx: &SignedTransaction, context: &TransactionContext) -> StatelessChecks {
+    if context.check_signatures {
+        StatelessChecks::run(tx)
+    } else {
+        StatelessChecks::with_signature(tx, tx.check_hash())
+    }
 }
 
 fn validate_untrusted_transaction(
-    utxos: &UTXOMap,
+    context: &TransactionContext,
     spent_map: &mut HashSet<(Hash, usize)>,
+    tx_index: usize,
     untrusted_transaction: SignedTransaction,
+    checks: StatelessChecks,
 ) -> Result<ValidatedTransaction, BlockValidationError> {
-    let is_input_already_spent = untrusted_transaction
+    let already_spent_input = untrusted_transaction
         .inputs()
         .iter()
-        .any(|input| spent_map.contains(&(*input.get_tx_id(), input.get_tx_idx())));
-    if is_input_already_spent {
-        return Err(BlockValidationError::UTXOSpentMultipleTime);
+        .position(|input| spent_map.contains(&(*input.get_tx_id(), input.get_tx_idx())));
+    if let Some(input_index) = already_spent_input {
+        return Err(BlockValidationError::UTXOSpentMultipleTime {
+            tx_index,
+            input_index,
+        });
     }
 
-    let valid_transaction = ValidatedTransaction::validate(untrusted_transaction, utxos)
-        .map_err(|err| BlockValidationError::TransactionValidationError(err))?;
+    let valid_transaction = if tx_index == 0 {
+        ValidatedTransaction::validate_coinbase_with_checks(
+            untrusted_transaction,
+            checks,
+            context.height,
+        )
+    } else {
+        ValidatedTransaction::validate_with_checks(untrusted_transaction, context.utxos, checks)
+    }
+    .map_err(|error| BlockValidationError::TransactionValidationError { tx_index, error })?;
     for input in valid_transaction.inputs() {
-        spent_map.insert((input.get_tx_id().clone(), input.get_tx_idx()));
+        spent_map.insert((*input.get_tx_id(), input.get_tx_idx()));
     }
     Ok(valid_transaction)
 }
 
+// the coinbase may claim the subsidy and the fees of the block
+fn check_coinbase_amount(
+    transactions: Vec<ValidatedTransaction>,
+    context: &TransactionContext,
+) -> Result<Vec<ValidatedTransaction>, BlockValidationError> {
+    let Some((coinbase, transactions_after)) = transactions.split_first() else {
+        return Ok(transactions);
+    };
+    transactions_after
+        .iter()
+        .try_fold(context.coinbase_subsidy, |total, transaction| {
+            total.checked_add(transaction.get_fee())
+        })
+        .ok_or(TransactionValidationError::AmountOverflow)
+        .and_then(|max_amount| coinbase.check_coinbase_amount(max_amount))
+        .map_err(|error| BlockValidationError::TransactionValidationError { tx_index: 0, error })?;
+    Ok(transactions)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BlockValidationError {
-    DifficultyTooLow,
-    VersionTooLow,
-    WrongPreviousHash,
-    WrongHash,
-    UTXOSpentMultipleTime,
-    TransactionValidationError(TransactionValidationError),
+    DifficultyTooLow {
+        expected: u32,
+        actual: u32,
+    },
+    VersionTooLow {
+        expected: u32,
+        actual: u32,
+    },
+    WrongPreviousHash {
+        expected: Hash,
+        actual: Hash,
+    },
+    InsufficientProofOfWork {
+        difficulty: u32,
+    },
+    NotInHeaderChain {
+        expected: Hash,
+        actual: Hash,
+    },
+    CheckpointMismatch {
+        height: usize,
+        expected: Hash,
+        actual: Hash,
+    },
+    ForkBelowCheckpoint {
+        fork_height: usize,
+        checkpoint_height: usize,
+    },
+    TimestampTooOld {
+        median_time_past: u64,
+        actual: u64,
+    },
+    TimestampTooFarInFuture {
+        max_timestamp: u64,
+        actual: u64,
+    },
+    WrongHash {
+        expected: Hash,
+        actual: Hash,
+    },
+    WrongMerkelRoot {
+        expected: Hash,
+        actual: Hash,
+    },
+    WrongWitnessRoot {
+        expected: Hash,
+        actual: Hash,
+    },
+    InvalidTransactionCount(MerkelError),
+    BlockTooLarge {
+        size: usize,
+        max: usize,
+    },
+    TooManySigOps {
+        count: usize,
+        max: usize,
+    },
+    UTXOSpentMultipleTime {
+        tx_index: usize,
+        input_index: usize,
+    },
+    TransactionValidationError {
+        tx_index: usize,
+        error: TransactionValidationError,
+    },
+}
+impl BlockValidationError {
+    /// Stable code of the error, for the RPC and the peer scoring. Never reuse a code.
+    /// Transaction errors keep the code of the `TransactionValidationError`.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::DifficultyTooLow { .. } => 100,
+            Self::VersionTooLow { .. } => 101,
+            Self::WrongPreviousHash { .. } => 102,
+            Self::WrongHash { .. } => 103,
+            Self::WrongMerkelRoot { .. } => 104,
+            Self::WrongWitnessRoot { .. } => 105,
+            Self::InvalidTransactionCount(_) => 106,
+            Self::UTXOSpentMultipleTime { .. } => 107,
+            Self::TransactionValidationError { error, .. } => error.code(),
+            Self::TimestampTooOld { .. } => 108,
+            Self::TimestampTooFarInFuture { .. } => 109,
+            Self::BlockTooLarge { .. } => 110,
+            Self::TooManySigOps { .. } => 111,
+            Self::InsufficientProofOfWork { .. } => 112,
+            Self::NotInHeaderChain { .. } => 113,
+            Self::CheckpointMismatch { .. } => 114,
+            Self::ForkBelowCheckpoint { .. } => 115,
+        }
+    }
+    /// Whether the block can only have been sent by a faulty or malicious peer.
+    /// A block on an unknown parent may just be ahead of us,
+    /// and a block from the future may be due to our own clock.
+    pub fn is_misbehavior(&self) -> bool {
+        match self {
+            Self::WrongPreviousHash { .. }
+            | Self::NotInHeaderChain { .. }
+            | Self::TimestampTooFarInFuture { .. } => false,
+            Self::TransactionValidationError { error, .. } => error.is_misbehavior(),
+            _ => true,
+        }
+    }
+}
+impl std::fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DifficultyTooLow { expected, actual } => {
+                write!(f, "difficulty {actual} is lower than {expected}")
+            }
+            Self::VersionTooLow { expected, actual } => {
+                write!(f, "version {actual} is lower than {expected}")
+            }
+            Self::WrongPreviousHash { expected, actual } => write!(
+                f,
+                "previous hash is {} instead of {}",
+                BASE64_STANDARD.encode(actual),
+                BASE64_STANDARD.encode(expected)
+            ),
+            Self::InsufficientProofOfWork { difficulty } => {
+                write!(f, "hash does not have {difficulty} leading zeros")
+            }
+            Self::NotInHeaderChain { expected, actual } => write!(
+                f,
+                "block {} is not the block {} of the header chain",
+                BASE64_STANDARD.encode(actual),
+                BASE64_STANDARD.encode(expected)
+            ),
+            Self::CheckpointMismatch {
+                height,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "block {} at height {height} is not the checkpoint {}",
+                BASE64_STANDARD.encode(actual),
+                BASE64_STANDARD.encode(expected)
+            ),
+            Self::ForkBelowCheckpoint {
+                fork_height,
+                checkpoint_height,
+            } => write!(
+                f,
+                "fork at height {fork_height} is below the checkpoint at height {checkpoint_height}"
+            ),
+            Self::TimestampTooOld {
+                median_time_past,
+                actual,
+            } => write!(
+                f,
+                "timestamp {actual} is not after the median time past {median_time_past}"
+            ),
+            Self::TimestampTooFarInFuture {
+                max_timestamp,
+                actual,
+            } => write!(f, "timestamp {actual} is after {max_timestamp}"),
+            Self::WrongHash { expected, actual } => write!(
+                f,
+                "block hash is {} but the block hashes to {}",
+                BASE64_STANDARD.encode(actual),
+                BASE64_STANDARD.encode(expected)
+            ),
+            Self::WrongMerkelRoot { expected, actual } => write!(
+                f,
+                "merkel root is {} but the transactions give {}",
+                BASE64_STANDARD.encode(actual),
+                BASE64_STANDARD.encode(expected)
+            ),
+            Self::WrongWitnessRoot { expected, actual } => write!(
+                f,
+                "witness root is {} but the signatures give {}",
+                BASE64_STANDARD.encode(actual),
+                BASE64_STANDARD.encode(expected)
+            ),
+            Self::InvalidTransactionCount(error) => write!(f, "invalid block: {error}"),
+            Self::BlockTooLarge { size, max } => {
+                write!(f, "block of {size} bytes, at most {max} are allowed")
+            }
+            Self::TooManySigOps { count, max } => {
+                write!(f, "{count} signature checks, at most {max} are allowed")
+            }
+            Self::UTXOSpentMultipleTime {
+                tx_index,
+                input_index,
+            } => write!(
+                f,
+                "input {input_index} of transaction {tx_index} is already spent in this block"
+            ),
+            Self::TransactionValidationError { tx_index, error } => {
+                write!(f, "transaction {tx_index} is invalid: {error}")
+            }
+        }
+    }
+}
+impl std::error::Error for BlockValidationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidTransactionCount(error) => Some(error),
+            Self::TransactionValidationError { error, .. } => Some(error),
+            _ => None,
+        }
+    }
 }
 impl std::fmt::Display for Block {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -115,7 +530,661 @@ impl std::fmt::Display for Block {
             f,
             "{}\n hash:{}",
             self.get_mining(),
-            BASE64_STANDARD.encode(&self.hash),
+            BASE64_STANDARD.encode(self.hash),
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{SigningKey, ed25519::signature::Signer};
+    use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+    use super::*;
+    use crate::{
+        block_chain::REGTEST_DIFFICULTY,
+        test_utils::{TEST_TIME, add_block, mine_block, regtest_chain, signing_key},
+        transactions::{
+            transaction::{
+                MAX_INPUTS_PER_TRANSACTION, MAX_OUTPUTS_PER_TRANSACTION, MAX_TRANSACTION_SIZE,
+                RawTransaction,
+            },
+            transaction_input::Input,
+            transaction_output::Output,
+        },
+    };
+
+    // a coinbase output of the chain: the input spending it, its amount and its key
+    type Coin = (Input, u64, usize);
+
+    // a chain whose `length` blocks are mined in turn by `keys`, with their coinbases
+    fn chain_with_coins(keys: &[SigningKey], length: usize) -> (BlockChain, Vec<Coin>) {
+        let mut chain = regtest_chain();
+        let mut coins = vec![];
+        for height in 0..length {
+            let block_hash = add_block(&mut chain, &keys[height % keys.len()], vec![]);
+            let coinbase = &chain
+                .get_block_by_hash(&block_hash)
+                .unwrap()
+                .get_transactions()[0];
+            coins.push((
+                Input::new(*coinbase.get_hash(), 0),
+                coinbase.outputs()[0].get_amount(),
+                height % keys.len(),
+            ));
+        }
+        (chain, coins)
+    }
+
+    // a transaction spending some `coins` of one key, made invalid in a random way when
+    // `broken`; returns it with the fee it declares
+    fn random_transaction(
+        rng: &mut StdRng,
+        keys: &[SigningKey],
+        coins: &mut Vec<Coin>,
+        spent: &[Coin],
+        broken: bool,
+    ) -> Option<(SignedTransaction, u64)> {
+        let owner = coins.last()?.2;
+        let mut inputs = vec![];
+        let mut total = 0;
+        while inputs.len() < rng.gen_range(1..=3)
+            && let Some(position) = coins.iter().rposition(|coin| coin.2 == owner)
+        {
+            let (input, amount, _) = coins.remove(position);
+            inputs.push(input);
+            total += amount;
+        }
+        let fee = rng.gen_range(0..1_000);
+        let mut signer = owner;
+        let mut amount = total - fee;
+        let fault = if broken { rng.gen_range(0..6) } else { 6 };
+        match fault {
+            0 => signer = (owner + 1) % keys.len(),
+            1 => amount = total + 1,
+            2 => inputs.push(Input::new([0xee; 32], 0)),
+            3 => inputs.push(inputs[0]),
+            4 => {
+                if let Some(coin) = spent.choose(rng) {
+                    inputs.push(coin.0);
+                }
+            }
+            _ => {}
+        }
+        let raw = RawTransaction::new(
+            inputs,
+            vec![Output::new(
+                keys[rng.gen_range(0..keys.len())].verifying_key(),
+                amount,
+            )],
+            keys[signer].verifying_key(),
+        );
+        let transaction = if fault == 5 {
+            let signature = keys[(signer + 1) % keys.len()].sign(&raw.hash());
+            SignedTransaction::with_signatures(raw, vec![signature])
+        } else {
+            raw.sign(&keys[signer])
+        };
+        Some((transaction, fee))
+    }
+
+    #[test]
+    fn serial_and_parallel_validation_agree_on_random_blocks() {
+        let keys: Vec<SigningKey> = (1..=4).map(signing_key).collect();
+        let (chain, coins) = chain_with_coins(&keys, 80);
+        let mut rng = StdRng::seed_from_u64(27);
+        let (mut accepted, mut rejected) = (0, 0);
+        for round in 0..60 {
+            let mut unspent = coins.clone();
+            unspent.shuffle(&mut rng);
+            let mut spent = vec![];
+            let mut transactions = vec![];
+            let mut fees = 0;
+            let count = rng.gen_range(0..2 * PARALLEL_VALIDATION_THRESHOLD);
+            // half of the blocks have one broken transaction, if any
+            let broken = rng.gen_bool(0.5).then(|| rng.gen_range(0..count.max(1)));
+            for tx_index in 0..count {
+                let before = unspent.clone();
+                let Some((transaction, fee)) = random_transaction(
+                    &mut rng,
+                    &keys,
+                    &mut unspent,
+                    &spent,
+                    broken == Some(tx_index),
+                ) else {
+                    break;
+                };
+                spent.extend(before.into_iter().filter(|coin| !unspent.contains(coin)));
+                transactions.push(transaction);
+                fees += fee;
+            }
+            let claimed = fees + (rng.gen_range(0..10) == 0) as u64;
+            let coinbase = ValidatedTransaction::get_coin_base_with_fees(&chain, &keys[0], claimed);
+            transactions.insert(0, coinbase.get_signed().clone());
+
+            let context = TransactionContext {
+                utxos: chain.get_utxos(),
+                height: chain.len(),
+                coinbase_subsidy: chain.get_coin_base_amount(),
+                check_signatures: rng.gen_range(0..5) != 0,
+            };
+            let tx_ids = |transactions: Vec<ValidatedTransaction>| {
+                transactions
+                    .iter()
+                    .map(|transaction| *transaction.get_hash())
+                    .collect::<Vec<Hash>>()
+            };
+            let serial =
+                validate_untrusted_transactions_serial(transactions.clone(), &context).map(tx_ids);
+            let parallel =
+                validate_untrusted_transactions_parallel(transactions, &context).map(tx_ids);
+            assert_eq!(serial, parallel, "round {round}");
+            match serial {
+                Ok(_) => accepted += 1,
+                Err(_) => rejected += 1,
+            }
+        }
+        assert!(
+            accepted > 0 && rejected > 0,
+            "{accepted} accepted, {rejected} rejected"
+        );
+    }
+
+    #[test]
+    fn a_changed_signature_breaks_the_witness_root() {
+        let keys: Vec<SigningKey> = (1..=2).map(signing_key).collect();
+        let (mut chain, coins) = chain_with_coins(&keys, 2);
+        let (input, amount, owner) = coins[0];
+        let transaction = RawTransaction::new(
+            vec![input],
+            vec![Output::new(keys[1].verifying_key(), amount - 100)],
+            keys[owner].verifying_key(),
+        )
+        .sign(&keys[owner]);
+        let block = mine_block(&chain, &keys[1], vec![transaction]);
+        assert_eq!(block.to_untrusted().check_commitments(), Ok(()));
+
+        // same tx id, another signature: only the witness root sees it
+        let mut untrusted = block.to_untrusted();
+        let raw = untrusted.transactions[1].data().clone();
+        let forged_signature = keys[owner].sign(b"another message");
+        untrusted.transactions[1] = SignedTransaction::with_signatures(raw, vec![forged_signature]);
+        assert_eq!(
+            untrusted.transactions[1].get_hash(),
+            block.get_transactions()[1].get_hash()
+        );
+        let expected = get_merkel_hash_from_ids(
+            untrusted
+                .transactions
+                .iter()
+                .map(|transaction| transaction.witness_hash())
+                .collect::<Vec<Hash>>()
+                .iter(),
+        )
+        .unwrap();
+        let wrong_witness_root = Err(BlockValidationError::WrongWitnessRoot {
+            expected,
+            actual: *block.get_mining().get_witness_root(),
+        });
+        assert_eq!(untrusted.check_commitments(), wrong_witness_root);
+        assert_eq!(
+            Block::valid_new_block(&chain, untrusted).err(),
+            wrong_witness_root.err()
+        );
+
+        // the block as mined is still accepted
+        let block = Block::valid_new_block(&chain, block.to_untrusted()).unwrap();
+        chain.update(block);
+        assert_eq!(chain.len(), 3);
+    }
+
+    fn spend(keys: &[SigningKey], coin: Coin) -> SignedTransaction {
+        let (input, amount, owner) = coin;
+        RawTransaction::new(
+            vec![input],
+            vec![Output::new(keys[1].verifying_key(), amount - 100)],
+            keys[owner].verifying_key(),
+        )
+        .sign(&keys[owner])
+    }
+
+    #[test]
+    fn each_block_failure_has_its_own_error() {
+        use BlockValidationError::*;
+        let keys: Vec<SigningKey> = (1..=2).map(signing_key).collect();
+        let (mut chain, coins) = chain_with_coins(&keys, 3);
+        let block = mine_block(&chain, &keys[0], vec![spend(&keys, coins[0])]);
+
+        let mut wrong_hash = block.to_untrusted();
+        wrong_hash.hash = [0; 32];
+        let expected = WrongHash {
+            expected: *block.get_hash(),
+            actual: [0; 32],
+        };
+        assert_eq!(
+            wrong_hash.check_orphan(REGTEST_DIFFICULTY),
+            Err(expected.clone())
+        );
+        assert_eq!(
+            Block::valid_new_block(&chain, wrong_hash).err(),
+            Some(expected)
+        );
+        assert_eq!(
+            block.to_untrusted().check_orphan(REGTEST_DIFFICULTY + 1),
+            Err(DifficultyTooLow {
+                expected: REGTEST_DIFFICULTY + 1,
+                actual: REGTEST_DIFFICULTY,
+            })
+        );
+        let unmined = MiningBlock::new_header(*block.get_hash(), 24, [0; 32], TEST_TIME);
+        assert_eq!(
+            UntrustedBlock::new(unmined.clone(), unmined.hash(), vec![])
+                .check_orphan(REGTEST_DIFFICULTY),
+            Err(InsufficientProofOfWork { difficulty: 24 })
+        );
+
+        // transactions that are not the ones of the header
+        let mut missing_transaction = block.to_untrusted();
+        missing_transaction.transactions.pop();
+        let merkel_root = get_merkel_hash_from_ids(
+            missing_transaction
+                .transactions
+                .iter()
+                .map(|tx| tx.get_hash()),
+        )
+        .unwrap();
+        assert_eq!(
+            missing_transaction.check_commitments(),
+            Err(WrongMerkelRoot {
+                expected: merkel_root,
+                actual: *block.get_mining().get_merkel_root(),
+            })
+        );
+        let mut empty = block.to_untrusted();
+        empty.transactions.clear();
+        assert_eq!(
+            Block::valid_new_block(&chain, empty).err(),
+            Some(InvalidTransactionCount(MerkelError::NoTransaction))
+        );
+
+        // a block at the height of a downloaded header must be that one
+        let other = mine_block(&chain, &keys[1], vec![]);
+        chain.add_header(block.get_mining().clone()).unwrap();
+        assert_eq!(
+            Block::valid_new_block(&chain, other.to_untrusted()).err(),
+            Some(NotInHeaderChain {
+                expected: *block.get_hash(),
+                actual: *other.get_hash(),
+            })
+        );
+        assert!(Block::valid_new_block(&chain, block.to_untrusted()).is_ok());
+    }
+
+    #[test]
+    fn blocks_over_the_limits_are_refused() {
+        use BlockValidationError::*;
+        let keys: Vec<SigningKey> = (1..=2).map(signing_key).collect();
+        let (chain, _) = chain_with_coins(&keys, 1);
+        let block = mine_block(&chain, &keys[0], vec![]);
+        let pubkey = keys[0].verifying_key();
+
+        // unsigned transactions of many signers: many sig ops in a few bytes
+        let signed_by = |count| {
+            SignedTransaction::with_signatures(
+                RawTransaction::with_signers(vec![], vec![], vec![pubkey; count]),
+                vec![],
+            )
+        };
+        // the coinbase and 19 * 1000 + 999 signers
+        let mut too_many_sig_ops = block.to_untrusted();
+        too_many_sig_ops
+            .transactions
+            .extend(vec![signed_by(1_000); MAX_BLOCK_SIG_OPS / 1_000 - 1]);
+        too_many_sig_ops.transactions.push(signed_by(999));
+        assert_eq!(too_many_sig_ops.check_limits(), Ok(()));
+        *too_many_sig_ops.transactions.last_mut().unwrap() = signed_by(1_000);
+        assert_eq!(
+            too_many_sig_ops.check_limits(),
+            Err(TooManySigOps {
+                count: MAX_BLOCK_SIG_OPS + 1,
+                max: MAX_BLOCK_SIG_OPS,
+            })
+        );
+
+        let outputs = vec![Output::new(pubkey, 1); MAX_OUTPUTS_PER_TRANSACTION];
+        let large = RawTransaction::new(vec![], outputs, pubkey).sign(&keys[0]);
+        let mut too_large = block.to_untrusted();
+        while too_large.serialized_size() <= MAX_BLOCK_SIZE {
+            too_large.transactions.push(large.clone());
+        }
+        assert_eq!(
+            too_large.check_limits(),
+            Err(BlockTooLarge {
+                size: too_large.serialized_size(),
+                max: MAX_BLOCK_SIZE,
+            })
+        );
+    }
+
+    #[test]
+    fn transaction_failures_tell_their_transaction() {
+        use BlockValidationError::*;
+        let keys: Vec<SigningKey> = (1..=2).map(signing_key).collect();
+        let (chain, coins) = chain_with_coins(&keys, 3);
+        let context = TransactionContext {
+            utxos: chain.get_utxos(),
+            height: chain.len(),
+            coinbase_subsidy: chain.get_coin_base_amount(),
+            check_signatures: true,
+        };
+        let coinbase = |fees| {
+            ValidatedTransaction::get_coin_base_with_fees(&chain, &keys[0], fees)
+                .get_signed()
+                .clone()
+        };
+        let validate =
+            |transactions| validate_untrusted_transactions_serial(transactions, &context).err();
+
+        // the fee of each transaction is 100
+        assert!(
+            validate(vec![
+                coinbase(200),
+                spend(&keys, coins[0]),
+                spend(&keys, coins[1])
+            ])
+            .is_none()
+        );
+        assert_eq!(
+            validate(vec![
+                coinbase(201),
+                spend(&keys, coins[0]),
+                spend(&keys, coins[1])
+            ]),
+            Some(TransactionValidationError {
+                tx_index: 0,
+                error: super::TransactionValidationError::CoinbaseTooLarge {
+                    max: context.coinbase_subsidy + 200,
+                    actual: context.coinbase_subsidy + 201,
+                },
+            })
+        );
+        assert_eq!(
+            validate(vec![spend(&keys, coins[0])]),
+            Some(TransactionValidationError {
+                tx_index: 0,
+                error: super::TransactionValidationError::InvalidCoinbase,
+            })
+        );
+
+        let mut double_spend = spend(&keys, coins[0]).data().clone();
+        double_spend = RawTransaction::new(
+            vec![coins[1].0, coins[0].0],
+            double_spend.outputs().to_vec(),
+            keys[0].verifying_key(),
+        );
+        assert_eq!(
+            validate(vec![
+                coinbase(0),
+                spend(&keys, coins[0]),
+                double_spend.sign(&keys[0])
+            ]),
+            Some(UTXOSpentMultipleTime {
+                tx_index: 2,
+                input_index: 1,
+            })
+        );
+        let unknown = Input::new([0xee; 32], 0);
+        let unknown_input = RawTransaction::new(vec![unknown], vec![], keys[0].verifying_key());
+        assert_eq!(
+            validate(vec![
+                coinbase(0),
+                spend(&keys, coins[0]),
+                unknown_input.sign(&keys[0])
+            ]),
+            Some(TransactionValidationError {
+                tx_index: 2,
+                error: super::TransactionValidationError::InputInvalid {
+                    input_index: 0,
+                    input: unknown,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn error_codes_are_stable() {
+        use BlockValidationError::*;
+        let hash = Hash::default();
+        let codes = [
+            (
+                DifficultyTooLow {
+                    expected: 0,
+                    actual: 0,
+                },
+                100,
+            ),
+            (
+                VersionTooLow {
+                    expected: 0,
+                    actual: 0,
+                },
+                101,
+            ),
+            (
+                WrongPreviousHash {
+                    expected: hash,
+                    actual: hash,
+                },
+                102,
+            ),
+            (
+                WrongHash {
+                    expected: hash,
+                    actual: hash,
+                },
+                103,
+            ),
+            (
+                WrongMerkelRoot {
+                    expected: hash,
+                    actual: hash,
+                },
+                104,
+            ),
+            (
+                WrongWitnessRoot {
+                    expected: hash,
+                    actual: hash,
+                },
+                105,
+            ),
+            (InvalidTransactionCount(MerkelError::NoTransaction), 106),
+            (
+                UTXOSpentMultipleTime {
+                    tx_index: 0,
+                    input_index: 0,
+                },
+                107,
+            ),
+            (
+                TimestampTooOld {
+                    median_time_past: 0,
+                    actual: 0,
+                },
+                108,
+            ),
+            (
+                TimestampTooFarInFuture {
+                    max_timestamp: 0,
+                    actual: 0,
+                },
+                109,
+            ),
+            (BlockTooLarge { size: 0, max: 0 }, 110),
+            (TooManySigOps { count: 0, max: 0 }, 111),
+            (InsufficientProofOfWork { difficulty: 0 }, 112),
+            (
+                NotInHeaderChain {
+                    expected: hash,
+                    actual: hash,
+                },
+                113,
+            ),
+            (
+                CheckpointMismatch {
+                    height: 0,
+                    expected: hash,
+                    actual: hash,
+                },
+                114,
+            ),
+            (
+                ForkBelowCheckpoint {
+                    fork_height: 0,
+                    checkpoint_height: 0,
+                },
+                115,
+            ),
+            (
+                TransactionValidationError {
+                    tx_index: 1,
+                    error: super::TransactionValidationError::AmountOverflow,
+                },
+                207,
+            ),
+        ];
+        for (error, code) in codes {
+            assert_eq!(error.code(), code, "{error}");
+        }
+        // what an honest peer may send us
+        for error in [
+            WrongPreviousHash {
+                expected: hash,
+                actual: hash,
+            },
+            NotInHeaderChain {
+                expected: hash,
+                actual: hash,
+            },
+            TimestampTooFarInFuture {
+                max_timestamp: 0,
+                actual: 0,
+            },
+            TransactionValidationError {
+                tx_index: 1,
+                error: super::TransactionValidationError::InputInvalid {
+                    input_index: 0,
+                    input: Input::new(hash, 0),
+                },
+            },
+        ] {
+            assert!(!error.is_misbehavior(), "{error}");
+        }
+    }
+
+    // up to `max_items` inputs and outputs, `max_signers` signers and `max_signatures` signatures
+    type Shape = (usize, usize, usize);
+    // large inputs and outputs fill blocks, many signers with few signatures fill sig ops
+    const WIDE: Shape = (2 * MAX_INPUTS_PER_TRANSACTION, 400, 400);
+    const MANY_SIGNERS: Shape = (8, 3_000, 4);
+
+    fn oversized_transaction(
+        rng: &mut StdRng,
+        keys: &[SigningKey],
+        (max_items, max_signers, max_signatures): Shape,
+    ) -> SignedTransaction {
+        let input_count = rng.gen_range(0..=max_items);
+        let output_count = rng.gen_range(0..=max_items);
+        let signer_count = rng.gen_range(0..=max_signers);
+        let inputs = (0..input_count)
+            .map(|index| Input::new([rng.r#gen(); 32], index))
+            .collect();
+        let outputs = (0..output_count)
+            .map(|_| Output::new(keys[0].verifying_key(), rng.r#gen()))
+            .collect();
+        let signers = (0..signer_count)
+            .map(|index| keys[index % keys.len()].verifying_key())
+            .collect();
+        let raw = RawTransaction::with_signers(inputs, outputs, signers);
+        let signature = keys[0].sign(&raw.hash());
+        SignedTransaction::with_signatures(
+            raw,
+            vec![signature; rng.gen_range(0..=signer_count.min(max_signatures))],
+        )
+    }
+
+    #[test]
+    fn oversized_blocks_are_refused_without_panicking() {
+        let keys: Vec<SigningKey> = (1..=200).map(signing_key).collect();
+        let (chain, _) = chain_with_coins(&keys, 1);
+        let block = mine_block(&chain, &keys[0], vec![]);
+        let mut rng = StdRng::seed_from_u64(34);
+        for round in 0..40 {
+            let mut untrusted = block.to_untrusted();
+            // about 32 bytes a sig op, so 20 such transactions can pass the sig ops and not the size
+            let (shape, max_count) = if round & 1 == 0 {
+                (WIDE, 40)
+            } else {
+                (MANY_SIGNERS, 20)
+            };
+            let transaction_count = rng.gen_range(0..max_count);
+            for _ in 0..transaction_count {
+                let transaction = oversized_transaction(&mut rng, &keys, shape);
+                let limits = transaction.check_limits();
+                let size = transaction.serialized_size();
+                if transaction.inputs().len() > MAX_INPUTS_PER_TRANSACTION {
+                    assert!(matches!(
+                        limits,
+                        Err(super::TransactionValidationError::TooManyInputs { .. })
+                    ));
+                } else if transaction.outputs().len() > MAX_OUTPUTS_PER_TRANSACTION {
+                    assert!(matches!(
+                        limits,
+                        Err(super::TransactionValidationError::TooManyOutputs { .. })
+                    ));
+                } else if size > MAX_TRANSACTION_SIZE {
+                    assert!(matches!(
+                        limits,
+                        Err(super::TransactionValidationError::TransactionTooLarge { .. })
+                    ));
+                }
+                // whatever its limits, the transaction is refused without a panic
+                assert!(
+                    ValidatedTransaction::validate(transaction.clone(), chain.get_utxos()).is_err()
+                );
+                untrusted.transactions.push(transaction);
+            }
+            let size = untrusted.serialized_size();
+            let sig_ops: usize = untrusted
+                .transactions
+                .iter()
+                .map(|tx| tx.sig_op_count())
+                .sum();
+            let expected = if size > MAX_BLOCK_SIZE {
+                Err(BlockValidationError::BlockTooLarge {
+                    size,
+                    max: MAX_BLOCK_SIZE,
+                })
+            } else if sig_ops > MAX_BLOCK_SIG_OPS {
+                Err(BlockValidationError::TooManySigOps {
+                    count: sig_ops,
+                    max: MAX_BLOCK_SIG_OPS,
+                })
+            } else {
+                Ok(())
+            };
+            assert_eq!(
+                untrusted.check_orphan(REGTEST_DIFFICULTY),
+                expected,
+                "round {round}"
+            );
+            let result = Block::valid_new_block(&chain, untrusted);
+            if let Err(error) = expected {
+                assert_eq!(result.err(), Some(error), "round {round}");
+            } else {
+                // the header commits to the coinbase alone
+                assert_eq!(result.is_ok(), transaction_count == 0, "round {round}");
+            }
+        }
+    }
+}
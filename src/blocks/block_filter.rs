@@ -0,0 +1,281 @@
+//! Compact block filters: a Golomb-coded set of the items of a block (output pubkeys and
+//! spent outpoints), so a light wallet can find its blocks without telling its keys.
+//!
+//! Each item is hashed with the block hash into `[0, N * FILTER_M)`, the sorted values are
+//! stored as deltas with a Rice code of parameter `FILTER_P`. A filter has no false negative
+//! and a false positive rate of about 1 / FILTER_M per item looked for.
+use ed25519_dalek::VerifyingKey;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    blocks::block::Block,
+    shared::Hash,
+    transactions::{address::pubkey_hash, transaction_input::Input, transaction_output::Output},
+};
+
+pub const FILTER_P: u8 = 19;
+pub const FILTER_M: u64 = 784_931;
+
+/// Item of a filter spending `input`.
+pub fn outpoint_item(input: &Input) -> Vec<u8> {
+    let mut item = Vec::with_capacity(Input::SERIALIZED_SIZE);
+    item.extend_from_slice(input.get_tx_id());
+    item.extend_from_slice(&(input.get_tx_idx() as u64).to_be_bytes());
+    item
+}
+/// Item of a filter paying `output`.
+pub fn output_item(output: &Output) -> Vec<u8> {
+    output.get_payee().as_bytes().to_vec()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockFilter {
+    item_count: u32,
+    data: Vec<u8>,
+}
+impl BlockFilter {
+    pub fn new<I: AsRef<[u8]>>(block_hash: &Hash, items: impl IntoIterator<Item = I>) -> Self {
+        let mut items: Vec<I> = items.into_iter().collect();
+        items.sort_unstable_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        items.dedup_by(|a, b| a.as_ref() == b.as_ref());
+        let range = items.len() as u64 * FILTER_M;
+        let mut values: Vec<u64> = items
+            .iter()
+            .map(|item| hash_to_range(block_hash, item.as_ref(), range))
+            .collect();
+        values.sort_unstable();
+
+        let mut writer = BitWriter::default();
+        let mut last_value = 0;
+        for value in &values {
+            writer.write_rice(value - last_value);
+            last_value = *value;
+        }
+        Self {
+            item_count: values.len() as u32,
+            data: writer.finish(),
+        }
+    }
+    /// Filter of the outputs created and the outpoints spent by `block`.
+    pub fn from_block(block: &Block) -> Self {
+        let transactions = block.get_transactions();
+        let spent = transactions
+            .iter()
+            .flat_map(|tx| tx.inputs())
+            .filter(|input| !input.is_coinbase())
+            .map(outpoint_item);
+        let created = transactions
+            .iter()
+            .flat_map(|tx| tx.outputs())
+            .map(output_item);
+        Self::new(block.get_hash(), spent.chain(created))
+    }
+    pub fn get_item_count(&self) -> usize {
+        self.item_count as usize
+    }
+    /// True when any of `items` may be in the block, always true when one of them is.
+    pub fn match_any<I: AsRef<[u8]>>(
+        &self,
+        block_hash: &Hash,
+        items: impl IntoIterator<Item = I>,
+    ) -> bool {
+        if self.item_count == 0 {
+            return false;
+        }
+        let range = self.item_count as u64 * FILTER_M;
+        let mut queries: Vec<u64> = items
+            .into_iter()
+            .map(|item| hash_to_range(block_hash, item.as_ref(), range))
+            .collect();
+        if queries.is_empty() {
+            return false;
+        }
+        queries.sort_unstable();
+
+        let mut reader = BitReader::new(&self.data);
+        let mut queries = queries.into_iter().peekable();
+        let mut value = 0;
+        for _ in 0..self.item_count {
+            let Some(delta) = reader.read_rice() else {
+                return false;
+            };
+            value += delta;
+            // the queries before `value` are not in the set
+            while queries.next_if(|query| *query < value).is_some() {}
+            match queries.peek() {
+                Some(query) if *query == value => return true,
+                Some(_) => {}
+                None => return false,
+            }
+        }
+        false
+    }
+    /// item count (u32 BE) | Golomb-Rice coded deltas
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.data.len());
+        bytes.extend_from_slice(&self.item_count.to_be_bytes());
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (item_count, data) = bytes.split_first_chunk::<4>()?;
+        Some(Self {
+            item_count: u32::from_be_bytes(*item_count),
+            data: data.to_vec(),
+        })
+    }
+    pub fn hash(&self) -> Hash {
+        Sha256::digest(self.to_bytes()).into()
+    }
+    /// Header of this filter in the filter-header chain, after `previous_header`
+    /// (zero for the first block).
+    pub fn header(&self, previous_header: &Hash) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash());
+        hasher.update(previous_header);
+        hasher.finalize().into()
+    }
+}
+
+/// What a wallet looks for in the filters: its pubkeys and its unspent outpoints.
+#[derive(Default)]
+pub struct FilterMatcher {
+    items: Vec<Vec<u8>>,
+}
+impl FilterMatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Looks for the outputs paying `pubkey` directly or by its hash.
+    pub fn add_pubkey(&mut self, pubkey: &VerifyingKey) {
+        self.items.push(pubkey.as_bytes().to_vec());
+        self.items.push(pubkey_hash(pubkey).to_vec());
+    }
+    pub fn add_outpoint(&mut self, outpoint: &Input) {
+        self.items.push(outpoint_item(outpoint));
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    /// True when the block may pay or spend something of the wallet, so it should be downloaded.
+    pub fn matches(&self, block_hash: &Hash, filter: &BlockFilter) -> bool {
+        filter.match_any(block_hash, &self.items)
+    }
+}
+
+// uniform mapping of sha256(block_hash | item) into [0, range)
+fn hash_to_range(block_hash: &Hash, item: &[u8], range: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(block_hash);
+    hasher.update(item);
+    let hash: Hash = hasher.finalize().into();
+    let value = u64::from_be_bytes(hash[..8].try_into().unwrap());
+    ((value as u128 * range as u128) >> 64) as u64
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit_count: usize,
+}
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.bit_count & 7 == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bit_count % 8);
+        }
+        self.bit_count += 1;
+    }
+    // quotient in unary (ones ended by a zero), then the FILTER_P low bits
+    fn write_rice(&mut self, value: u64) {
+        for _ in 0..(value >> FILTER_P) {
+            self.write_bit(true);
+        }
+        self.write_bit(false);
+        for i in (0..FILTER_P).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+    fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.position / 8)?;
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Some(bit)
+    }
+    fn read_rice(&mut self) -> Option<u64> {
+        let mut quotient = 0u64;
+        while self.read_bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0u64;
+        for _ in 0..FILTER_P {
+            remainder = (remainder << 1) | self.read_bit()? as u64;
+        }
+        Some((quotient << FILTER_P) | remainder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    // long enough not to be drawn twice
+    fn random_items(rng: &mut StdRng, count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|_| (0..rng.gen_range(8..=72)).map(|_| rng.r#gen()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn a_filter_never_misses_one_of_its_items() {
+        let mut rng = StdRng::seed_from_u64(39);
+        let mut false_positives = 0;
+        for count in [1, 2, 3, 10, 100, 1_000] {
+            let block_hash: Hash = rng.r#gen();
+            let mut items = random_items(&mut rng, count);
+            // duplicates are stored once
+            let duplicates = items[..count / 2].to_vec();
+            items.extend(duplicates);
+            let filter = BlockFilter::new(&block_hash, &items);
+            assert_eq!(filter.get_item_count(), count);
+            let filter = BlockFilter::from_bytes(&filter.to_bytes()).unwrap();
+
+            for item in &items {
+                assert!(filter.match_any(&block_hash, [item]));
+                // among items that are not in the filter
+                let mut query = random_items(&mut rng, 5);
+                query.insert(rng.gen_range(0..=query.len()), item.clone());
+                assert!(filter.match_any(&block_hash, &query));
+            }
+            false_positives += random_items(&mut rng, 2_000)
+                .iter()
+                .filter(|other| filter.match_any(&block_hash, [other]))
+                .count();
+        }
+        // about 1 / FILTER_M of the 12 000 lookups
+        assert!(false_positives <= 1);
+
+        let empty = BlockFilter::new(&[0; 32], Vec::<Vec<u8>>::new());
+        assert_eq!(empty.get_item_count(), 0);
+        assert!(!empty.match_any(&[0; 32], [b"item"]));
+        let filter = BlockFilter::new(&[0; 32], [b"item"]);
+        assert!(!filter.match_any(&[0; 32], Vec::<Vec<u8>>::new()));
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+
+use crate::shared::Hash;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    Main,
+    Test,
+    /// Local chains for development, without checkpoints.
+    Regtest,
+}
+
+// Empty: the genesis block is not pinned, each node mines its own at its first start
+// (`MiningBlock::genesis` takes the time of the node), so no network has a block all
+// nodes share to list here. Until a genesis is pinned, main and test nodes get no
+// protection from the checkpoints and verify every signature.
+const MAIN_CHECKPOINTS: &[(usize, Hash)] = &[];
+const MAIN_ASSUME_VALID: Option<Hash> = None;
+const TEST_CHECKPOINTS: &[(usize, Hash)] = &[];
+const TEST_ASSUME_VALID: Option<Hash> = None;
+
+/// Blocks a node is told to trust: a header chain must go through the checkpoints, and the
+/// signatures of the blocks up to `assume_valid` are not verified again on initial sync.
+/// Only the mechanism is there, no network ships checkpoints yet (see `MAIN_CHECKPOINTS`).
+#[derive(Clone, Debug, Default)]
+pub struct Checkpoints {
+    checkpoints: BTreeMap<usize, Hash>,
+    assume_valid: Option<Hash>,
+}
+impl Checkpoints {
+    pub fn new(
+        checkpoints: impl IntoIterator<Item = (usize, Hash)>,
+        assume_valid: Option<Hash>,
+    ) -> Self {
+        Self {
+            checkpoints: checkpoints.into_iter().collect(),
+            assume_valid,
+        }
+    }
+    pub fn for_network(network: Network) -> Self {
+        match network {
+            Network::Main => Self::new(MAIN_CHECKPOINTS.iter().copied(), MAIN_ASSUME_VALID),
+            Network::Test => Self::new(TEST_CHECKPOINTS.iter().copied(), TEST_ASSUME_VALID),
+            Network::Regtest => Self::default(),
+        }
+    }
+    pub fn get(&self, height: usize) -> Option<&Hash> {
+        self.checkpoints.get(&height)
+    }
+    /// Height of the last checkpoint strictly below `height`.
+    pub fn last_below(&self, height: usize) -> Option<usize> {
+        self.checkpoints
+            .range(..height)
+            .next_back()
+            .map(|(height, _)| *height)
+    }
+    pub fn get_assume_valid(&self) -> Option<&Hash> {
+        self.assume_valid.as_ref()
+    }
+}
@@ -0,0 +1,618 @@
+use std::collections::HashMap;
+
+use crate::{
+    blocks::{block::BlockValidationError, checkpoints::Checkpoints, mining_block::MiningBlock},
+    shared::{Hash, meet_difficulty},
+};
+
+// number of previous blocks used for the median time past
+const MEDIAN_TIME_SPAN: usize = 11;
+// how far (in seconds) a block timestamp may be ahead of the node time
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+// number of most recent blocks listed one by one in a locator
+const LOCATOR_DENSE_SPAN: usize = 10;
+
+/// Chain of validated block headers, possibly ahead of the blocks we have the body of.
+/// The header at index i is the header of the block at height i.
+pub struct HeaderChain {
+    headers: Vec<MiningBlock>,
+    hashes: Vec<Hash>,
+    heights: HashMap<Hash, usize>,
+    difficulty: u32,
+    version: u32,
+    checkpoints: Checkpoints,
+    // validated fork without more work than the chain yet, which the peer may go on with
+    side_branch: Option<SideBranch>,
+}
+
+struct SideBranch {
+    // height of its first header
+    fork_height: usize,
+    headers: Vec<MiningBlock>,
+    hashes: Vec<Hash>,
+}
+impl SideBranch {
+    fn work(&self) -> u128 {
+        self.headers.iter().map(MiningBlock::work).sum()
+    }
+}
+impl HeaderChain {
+    pub fn new(difficulty: u32, version: u32) -> Self {
+        Self {
+            headers: vec![],
+            hashes: vec![],
+            heights: HashMap::new(),
+            difficulty,
+            version,
+            checkpoints: Checkpoints::default(),
+            side_branch: None,
+        }
+    }
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints) -> Self {
+        self.checkpoints = checkpoints;
+        self
+    }
+    pub fn get_checkpoints(&self) -> &Checkpoints {
+        &self.checkpoints
+    }
+    /// Whether the block at `height` is an ancestor of the assumed valid block
+    /// (or that block itself), so its signatures don't have to be checked.
+    pub fn is_assumed_valid(&self, height: usize) -> bool {
+        self.checkpoints
+            .get_assume_valid()
+            .and_then(|hash| self.get_height(hash))
+            .is_some_and(|assume_valid_height| height <= assume_valid_height)
+    }
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+    pub fn get_difficulty(&self) -> u32 {
+        self.difficulty
+    }
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+    /// Hash of the last header, zero for an empty chain (the genesis previous hash).
+    pub fn tip_hash(&self) -> Hash {
+        self.hashes.last().copied().unwrap_or_default()
+    }
+    pub fn get_header(&self, height: usize) -> Option<&MiningBlock> {
+        self.headers.get(height)
+    }
+    pub fn get_hash(&self, height: usize) -> Option<&Hash> {
+        self.hashes.get(height)
+    }
+    pub fn get_height(&self, hash: &Hash) -> Option<usize> {
+        self.heights.get(hash).copied()
+    }
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.heights.contains_key(hash)
+    }
+    /// Work of the headers from `height` to the tip.
+    pub fn work_from(&self, height: usize) -> u128 {
+        self.headers[height.min(self.len())..]
+            .iter()
+            .map(MiningBlock::work)
+            .sum()
+    }
+    /// Hashes of the headers at `locator_heights(self.len())`, from the tip back to the first one.
+    /// While a side branch is being downloaded, the locator starts from its tip instead so
+    /// that the peer goes on with it.
+    pub fn locator(&self) -> Vec<Hash> {
+        let Some(side) = &self.side_branch else {
+            return locator_heights(self.len())
+                .into_iter()
+                .map(|height| self.hashes[height])
+                .collect();
+        };
+        locator_heights(side.fork_height + side.hashes.len())
+            .into_iter()
+            .map(|height| match height.checked_sub(side.fork_height) {
+                Some(index) => side.hashes[index],
+                None => self.hashes[height],
+            })
+            .collect()
+    }
+    /// Median of the timestamps of the `MEDIAN_TIME_SPAN` headers before `height`,
+    /// 0 when there is none.
+    pub fn median_time_past(&self, height: usize) -> u64 {
+        median_time_past(&self.previous_timestamps(height))
+    }
+    fn previous_timestamps(&self, height: usize) -> Vec<u64> {
+        let first = height.saturating_sub(MEDIAN_TIME_SPAN);
+        self.headers[first..height]
+            .iter()
+            .map(|header| header.get_timestamp())
+            .collect()
+    }
+    fn previous_hash(&self, height: usize) -> Hash {
+        match height {
+            0 => Hash::default(),
+            _ => self.hashes[height - 1],
+        }
+    }
+    /// Validates `header` as the header of the block at `height`, on top of the headers before it.
+    /// Everything is checked but the transactions: linkage, version, difficulty, proof of work
+    /// and timestamp. Returns the hash of the header.
+    pub fn check_header(
+        &self,
+        header: &MiningBlock,
+        height: usize,
+        now: u64,
+    ) -> Result<Hash, BlockValidationError> {
+        let hash = self.check_header_on(
+            header,
+            &self.previous_hash(height),
+            &self.previous_timestamps(height),
+            now,
+        )?;
+        self.check_checkpoint(height, &hash)?;
+        Ok(hash)
+    }
+    /// Validates headers forking from the chain: `branch[0]` is at `fork_height`.
+    /// Returns their hashes.
+    pub fn check_branch(
+        &self,
+        fork_height: usize,
+        branch: &[MiningBlock],
+        now: u64,
+    ) -> Result<Vec<Hash>, BlockValidationError> {
+        self.check_headers_after(
+            fork_height,
+            self.previous_hash(fork_height),
+            self.previous_timestamps(fork_height),
+            branch,
+            now,
+        )
+    }
+    // validates `headers` from `height`, the previous header having the hash `previous_hash`
+    fn check_headers_after(
+        &self,
+        height: usize,
+        mut previous_hash: Hash,
+        mut previous_timestamps: Vec<u64>,
+        headers: &[MiningBlock],
+        now: u64,
+    ) -> Result<Vec<Hash>, BlockValidationError> {
+        let mut hashes = Vec::with_capacity(headers.len());
+        for (height, header) in (height..).zip(headers) {
+            previous_hash =
+                self.check_header_on(header, &previous_hash, &previous_timestamps, now)?;
+            self.check_checkpoint(height, &previous_hash)?;
+            hashes.push(previous_hash);
+            if previous_timestamps.len() == MEDIAN_TIME_SPAN {
+                previous_timestamps.remove(0);
+            }
+            previous_timestamps.push(header.get_timestamp());
+        }
+        Ok(hashes)
+    }
+    fn check_checkpoint(&self, height: usize, hash: &Hash) -> Result<(), BlockValidationError> {
+        match self.checkpoints.get(height) {
+            Some(expected) if expected != hash => Err(BlockValidationError::CheckpointMismatch {
+                height,
+                expected: *expected,
+                actual: *hash,
+            }),
+            _ => Ok(()),
+        }
+    }
+    fn check_header_on(
+        &self,
+        header: &MiningBlock,
+        previous_hash: &Hash,
+        previous_timestamps: &[u64],
+        now: u64,
+    ) -> Result<Hash, BlockValidationError> {
+        if self.difficulty > header.get_difficulty() {
+            return Err(BlockValidationError::DifficultyTooLow {
+                expected: self.difficulty,
+                actual: header.get_difficulty(),
+            });
+        }
+        if self.version > header.get_version() {
+            return Err(BlockValidationError::VersionTooLow {
+                expected: self.version,
+                actual: header.get_version(),
+            });
+        }
+        if previous_hash != header.get_previous_hash() {
+            return Err(BlockValidationError::WrongPreviousHash {
+                expected: *previous_hash,
+                actual: *header.get_previous_hash(),
+            });
+        }
+        let hash = header.hash();
+        if !meet_difficulty(&hash, header.get_difficulty()) {
+            return Err(BlockValidationError::InsufficientProofOfWork {
+                difficulty: header.get_difficulty(),
+            });
+        }
+        check_timestamp(header.get_timestamp(), previous_timestamps, now)?;
+        Ok(hash)
+    }
+    /// Validates and appends a header at the tip.
+    pub fn add_header(
+        &mut self,
+        header: MiningBlock,
+        now: u64,
+    ) -> Result<Hash, BlockValidationError> {
+        let hash = self.check_header(&header, self.len(), now)?;
+        self.push(header, hash);
+        Ok(hash)
+    }
+    /// Validates headers received from a peer, the first one following a header we know.
+    /// A fork without more work than the chain is kept aside, the next headers of the peer
+    /// can extend it: the chain switches to it once its total work is greater.
+    /// Returns the number of new headers, on the chain or on the side branch.
+    pub fn accept_headers(
+        &mut self,
+        headers: &[MiningBlock],
+        now: u64,
+    ) -> Result<usize, BlockValidationError> {
+        let Some(first) = headers.first() else {
+            return Ok(0);
+        };
+        // number of side branch headers the new ones follow
+        let side_length = self.side_branch.as_ref().and_then(|side| {
+            side.hashes
+                .iter()
+                .position(|hash| hash == first.get_previous_hash())
+                .map(|index| index + 1)
+        });
+        let (fork_height, new_headers, hashes) = match (&self.side_branch, side_length) {
+            (Some(side), Some(side_length)) => {
+                let mut previous_timestamps = self.previous_timestamps(side.fork_height);
+                previous_timestamps.extend(
+                    side.headers[side_length.saturating_sub(MEDIAN_TIME_SPAN)..side_length]
+                        .iter()
+                        .map(MiningBlock::get_timestamp),
+                );
+                let excess = previous_timestamps.len().saturating_sub(MEDIAN_TIME_SPAN);
+                previous_timestamps.drain(..excess);
+                let hashes = self.check_headers_after(
+                    side.fork_height + side_length,
+                    side.hashes[side_length - 1],
+                    previous_timestamps,
+                    headers,
+                    now,
+                )?;
+                (side.fork_height, headers, hashes)
+            }
+            _ => {
+                let fork_height = self.fork_height(first)?;
+                // skip the headers we already have
+                let known_count = headers
+                    .iter()
+                    .zip(fork_height..)
+                    .take_while(|(header, height)| self.get_hash(*height) == Some(&header.hash()))
+                    .count();
+                let fork_height = fork_height + known_count;
+                let branch = &headers[known_count..];
+                if branch.is_empty() {
+                    return Ok(0);
+                }
+                if let Some(checkpoint_height) = self.checkpoints.last_below(self.len())
+                    && fork_height <= checkpoint_height
+                {
+                    return Err(BlockValidationError::ForkBelowCheckpoint {
+                        fork_height,
+                        checkpoint_height,
+                    });
+                }
+                let hashes = self.check_branch(fork_height, branch, now)?;
+                (fork_height, branch, hashes)
+            }
+        };
+
+        let mut branch = match side_length {
+            Some(side_length) => {
+                let mut side = self.side_branch.take().expect("the headers follow it");
+                // the headers after the fork of the fork are replaced
+                side.headers.truncate(side_length);
+                side.hashes.truncate(side_length);
+                side
+            }
+            None => SideBranch {
+                fork_height,
+                headers: vec![],
+                hashes: vec![],
+            },
+        };
+        branch.headers.extend_from_slice(new_headers);
+        branch.hashes.extend(hashes);
+        if branch.work() <= self.work_from(fork_height) {
+            self.side_branch = Some(branch);
+            return Ok(new_headers.len());
+        }
+        self.side_branch = None;
+        self.truncate(fork_height);
+        for (header, hash) in branch.headers.into_iter().zip(branch.hashes) {
+            self.push(header, hash);
+        }
+        Ok(new_headers.len())
+    }
+    // height of `first`, following a header of the chain
+    fn fork_height(&self, first: &MiningBlock) -> Result<usize, BlockValidationError> {
+        if first.get_previous_hash() == &Hash::default() {
+            return Ok(0);
+        }
+        match self.get_height(first.get_previous_hash()) {
+            Some(previous_height) => Ok(previous_height + 1),
+            None => Err(BlockValidationError::WrongPreviousHash {
+                expected: self.tip_hash(),
+                actual: *first.get_previous_hash(),
+            }),
+        }
+    }
+    /// Appends an already validated header.
+    pub fn push(&mut self, header: MiningBlock, hash: Hash) {
+        self.heights.insert(hash, self.headers.len());
+        self.headers.push(header);
+        self.hashes.push(hash);
+    }
+    /// Drops the headers from `height`, and the side branch forking above them.
+    pub fn truncate(&mut self, height: usize) {
+        if self
+            .side_branch
+            .as_ref()
+            .is_some_and(|side| side.fork_height > height)
+        {
+            self.side_branch = None;
+        }
+        for hash in self.hashes.iter().skip(height) {
+            self.heights.remove(hash);
+        }
+        self.headers.truncate(height);
+        self.hashes.truncate(height);
+    }
+}
+
+/// Heights listed in a locator of a chain of `len` blocks: the last `LOCATOR_DENSE_SPAN`
+/// ones, then twice as far back each time, always ending with 0.
+pub fn locator_heights(len: usize) -> Vec<usize> {
+    let mut heights = Vec::new();
+    let Some(mut height) = len.checked_sub(1) else {
+        return heights;
+    };
+    let mut step = 1;
+    loop {
+        heights.push(height);
+        if height == 0 {
+            return heights;
+        }
+        if heights.len() >= LOCATOR_DENSE_SPAN {
+            step *= 2;
+        }
+        height = height.saturating_sub(step);
+    }
+}
+
+fn median_time_past(previous_timestamps: &[u64]) -> u64 {
+    let mut timestamps = previous_timestamps.to_vec();
+    if timestamps.is_empty() {
+        return 0;
+    }
+    timestamps.sort_unstable();
+    timestamps[timestamps.len() / 2]
+}
+
+/// The timestamp must be after the median time past and at most
+/// `MAX_FUTURE_BLOCK_TIME` after `now`.
+fn check_timestamp(
+    timestamp: u64,
+    previous_timestamps: &[u64],
+    now: u64,
+) -> Result<(), BlockValidationError> {
+    let median_time_past = median_time_past(previous_timestamps);
+    if timestamp <= median_time_past {
+        return Err(BlockValidationError::TimestampTooOld {
+            median_time_past,
+            actual: timestamp,
+        });
+    }
+    let max_timestamp = now.saturating_add(MAX_FUTURE_BLOCK_TIME);
+    if timestamp > max_timestamp {
+        return Err(BlockValidationError::TimestampTooFarInFuture {
+            max_timestamp,
+            actual: timestamp,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_chain::REGTEST_DIFFICULTY,
+        test_utils::{TEST_TIME, mine_headers},
+    };
+
+    const NOW: u64 = TEST_TIME + 10_000;
+
+    fn header_chain() -> HeaderChain {
+        HeaderChain::new(REGTEST_DIFFICULTY, 0)
+    }
+
+    // delivers `headers` the way `sync_headers` asks for them
+    fn accept_in_batches(chain: &mut HeaderChain, headers: &[MiningBlock]) -> usize {
+        headers
+            .chunks(2_000)
+            .map(|batch| chain.accept_headers(batch, NOW).unwrap())
+            .sum()
+    }
+
+    #[test]
+    fn a_heavier_deep_fork_is_adopted_across_batches() {
+        let main = mine_headers(None, 4_000, 0);
+        let mut chain = header_chain();
+        assert_eq!(accept_in_batches(&mut chain, &main), 4_000);
+
+        // forks at 1000 and only has more work after its second batch
+        let fork = mine_headers(Some(&main[999]), 3_500, 1);
+        assert_eq!(chain.accept_headers(&fork[..2_000], NOW), Ok(2_000));
+        assert_eq!(chain.len(), 4_000);
+        assert_eq!(chain.tip_hash(), main[3_999].hash());
+        // the next request goes on from the side branch
+        assert_eq!(chain.locator()[0], fork[1_999].hash());
+
+        assert_eq!(chain.accept_headers(&fork[2_000..], NOW), Ok(1_500));
+        assert_eq!(chain.len(), 4_500);
+        assert_eq!(chain.tip_hash(), fork[3_499].hash());
+        assert_eq!(chain.get_hash(999), Some(&main[999].hash()));
+        assert_eq!(chain.get_height(&main[1_000].hash()), None);
+        assert_eq!(chain.locator()[0], fork[3_499].hash());
+    }
+
+    #[test]
+    fn a_lighter_deep_fork_is_kept_aside() {
+        let main = mine_headers(None, 2_500, 0);
+        let mut chain = header_chain();
+        accept_in_batches(&mut chain, &main);
+
+        let fork = mine_headers(Some(&main[999]), 1_500, 1);
+        assert_eq!(accept_in_batches(&mut chain, &fork), 1_500);
+        assert_eq!(chain.len(), 2_500);
+        assert_eq!(chain.tip_hash(), main[2_499].hash());
+
+        // the chain growing above the fork doesn't lose the side branch
+        let more = mine_headers(Some(&fork[1_499]), 2, 1);
+        assert_eq!(chain.accept_headers(&more, NOW), Ok(2));
+        assert_eq!(chain.tip_hash(), more[1].hash());
+        assert_eq!(chain.len(), 2_502);
+    }
+
+    #[test]
+    fn an_invalid_continuation_keeps_the_side_branch() {
+        let main = mine_headers(None, 20, 0);
+        let mut chain = header_chain();
+        accept_in_batches(&mut chain, &main);
+        let fork = mine_headers(Some(&main[9]), 5, 1);
+        chain.accept_headers(&fork, NOW).unwrap();
+
+        let mut stale = mine_headers(Some(&fork[4]), 1, 1);
+        stale[0] = MiningBlock::new_header(fork[4].hash(), REGTEST_DIFFICULTY, [1; 32], 0);
+        assert!(matches!(
+            chain.accept_headers(&stale, NOW),
+            Err(BlockValidationError::TimestampTooOld { .. })
+        ));
+        assert_eq!(chain.locator()[0], fork[4].hash());
+    }
+
+    #[test]
+    fn each_header_failure_has_its_own_error() {
+        use BlockValidationError::*;
+        let headers = mine_headers(None, 2, 0);
+        let mut chain = header_chain();
+        chain.add_header(headers[0].clone(), NOW).unwrap();
+
+        let wrong_parent = mine_headers(None, 1, 1);
+        assert_eq!(
+            chain.add_header(wrong_parent[0].clone(), NOW),
+            Err(WrongPreviousHash {
+                expected: headers[0].hash(),
+                actual: Hash::default(),
+            })
+        );
+        let unknown_parent = mine_headers(Some(&wrong_parent[0]), 1, 1);
+        assert_eq!(
+            chain.accept_headers(&unknown_parent, NOW),
+            Err(WrongPreviousHash {
+                expected: headers[0].hash(),
+                actual: wrong_parent[0].hash(),
+            })
+        );
+        let unmined = MiningBlock::new_header(headers[0].hash(), 24, [0; 32], TEST_TIME + 1);
+        assert_eq!(
+            chain.add_header(unmined, NOW),
+            Err(InsufficientProofOfWork { difficulty: 24 })
+        );
+        assert_eq!(
+            HeaderChain::new(REGTEST_DIFFICULTY + 1, 0).add_header(headers[0].clone(), NOW),
+            Err(DifficultyTooLow {
+                expected: REGTEST_DIFFICULTY + 1,
+                actual: REGTEST_DIFFICULTY,
+            })
+        );
+        assert_eq!(
+            HeaderChain::new(REGTEST_DIFFICULTY, 1).add_header(headers[0].clone(), NOW),
+            Err(VersionTooLow {
+                expected: 1,
+                actual: 0,
+            })
+        );
+        assert_eq!(
+            chain.add_header(headers[1].clone(), NOW),
+            Ok(headers[1].hash())
+        );
+    }
+
+    #[test]
+    fn checkpoints_pin_the_header_chain() {
+        use BlockValidationError::*;
+        let main = mine_headers(None, 30, 0);
+        let checkpoints = Checkpoints::new([(10, main[10].hash()), (20, main[20].hash())], None);
+        let checkpointed = || header_chain().with_checkpoints(checkpoints.clone());
+
+        // a chain conflicting with a checkpoint, in one batch or header by header
+        let other = mine_headers(None, 15, 1);
+        let mut chain = checkpointed();
+        assert_eq!(
+            chain.accept_headers(&other, NOW),
+            Err(CheckpointMismatch {
+                height: 10,
+                expected: main[10].hash(),
+                actual: other[10].hash(),
+            })
+        );
+        assert!(chain.is_empty());
+        for header in &other[..10] {
+            chain.add_header(header.clone(), NOW).unwrap();
+        }
+        assert_eq!(
+            chain.add_header(other[10].clone(), NOW),
+            Err(CheckpointMismatch {
+                height: 10,
+                expected: main[10].hash(),
+                actual: other[10].hash(),
+            })
+        );
+
+        // before the chain reaches a checkpoint, a fork still has to go through it
+        let mut chain = checkpointed();
+        accept_in_batches(&mut chain, &main[..8]);
+        let fork = mine_headers(Some(&main[3]), 10, 1);
+        assert_eq!(
+            chain.accept_headers(&fork, NOW),
+            Err(CheckpointMismatch {
+                height: 10,
+                expected: main[10].hash(),
+                actual: fork[6].hash(),
+            })
+        );
+        assert_eq!(chain.tip_hash(), main[7].hash());
+
+        // past a checkpoint, even a heavier fork below it is refused
+        let mut chain = checkpointed();
+        accept_in_batches(&mut chain, &main);
+        for fork_height in [6, 20] {
+            let fork = mine_headers(Some(&main[fork_height - 1]), 40, 1);
+            assert_eq!(
+                chain.accept_headers(&fork, NOW),
+                Err(ForkBelowCheckpoint {
+                    fork_height,
+                    checkpoint_height: 20,
+                })
+            );
+            assert_eq!(chain.tip_hash(), main[29].hash());
+        }
+        let fork = mine_headers(Some(&main[20]), 40, 1);
+        assert_eq!(chain.accept_headers(&fork, NOW), Ok(40));
+        assert_eq!(chain.tip_hash(), fork[39].hash());
+        assert_eq!(chain.get_hash(20), Some(&main[20].hash()));
+    }
+}
@@ -7,7 +7,8 @@ use sha2::{Digest, Sha256};
 use crate::{
     block_chain::BlockChain,
     blocks::block::Block,
-    shared::{Hash, count_leading_zeros, get_now_unix},
+    clock::Clock,
+    shared::{Hash, count_leading_zeros},
 };
 
 #[derive(Clone, Debug)]
@@ -16,21 +17,26 @@ pub struct MiningBlock {
     difficulty: u32,
     previous_hash: Hash,
     merkel_root: Hash,
+    witness_root: Hash,
     nonce: u64,
     timestamp: u64,
 }
 
 impl MiningBlock {
+    // version, difficulty, previous_hash, merkel_root, witness_root, timestamp, nonce
+    pub const SERIALIZED_SIZE: usize = 4 + 4 + 32 + 32 + 32 + 8 + 8;
+
     pub fn hash(&self) -> Hash {
         let mut hasher = Sha256::new();
 
         // sérialisation manuelle (ordre important !!!)
-        hasher.update(&self.version.to_be_bytes());
-        hasher.update(&self.difficulty.to_be_bytes());
-        hasher.update(&self.previous_hash);
-        hasher.update(&self.merkel_root);
-        hasher.update(&self.timestamp.to_be_bytes());
-        hasher.update(&self.nonce.to_be_bytes());
+        hasher.update(self.version.to_be_bytes());
+        hasher.update(self.difficulty.to_be_bytes());
+        hasher.update(self.previous_hash);
+        hasher.update(self.merkel_root);
+        hasher.update(self.witness_root);
+        hasher.update(self.timestamp.to_be_bytes());
+        hasher.update(self.nonce.to_be_bytes());
 
         hasher.finalize().into()
     }
@@ -43,23 +49,55 @@ impl MiningBlock {
     pub fn get_previous_hash(&self) -> &Hash {
         &self.previous_hash
     }
-    pub fn genesis() -> Self {
+    /// Expected number of hashes to find this block.
+    pub fn work(&self) -> u128 {
+        1 << self.difficulty.min(127)
+    }
+    pub fn get_timestamp(&self) -> u64 {
+        self.timestamp
+    }
+    pub fn get_merkel_root(&self) -> &Hash {
+        &self.merkel_root
+    }
+    pub fn get_witness_root(&self) -> &Hash {
+        &self.witness_root
+    }
+    pub fn genesis(clock: &dyn Clock) -> Self {
         Self {
             version: 0,
             difficulty: 24,
             previous_hash: [0; 32],
             merkel_root: [0; 32],
+            witness_root: [0; 32],
+            nonce: 0,
+            timestamp: clock.now(),
+        }
+    }
+    /// Header of a block without transactions, not mined yet.
+    #[cfg(test)]
+    pub fn new_header(
+        previous_hash: Hash,
+        difficulty: u32,
+        merkel_root: Hash,
+        timestamp: u64,
+    ) -> Self {
+        Self {
+            version: 0,
+            difficulty,
+            previous_hash,
+            merkel_root,
+            witness_root: [0; 32],
             nonce: 0,
-            timestamp: get_now_unix(),
+            timestamp,
         }
     }
     pub fn mine(&mut self) -> Option<Block> {
         for test_nonce in 0..u64::MAX {
             self.nonce = test_nonce;
-            if count_leading_zeros(&self.hash()) >= self.get_difficulty() {
-                if let Some(block) = Block::try_new(&self) {
-                    return Some(block);
-                }
+            if count_leading_zeros(&self.hash()) >= self.get_difficulty()
+                && let Some(block) = Block::try_new(self)
+            {
+                return Some(block);
             }
         }
         None
@@ -77,14 +115,16 @@ impl MiningBlock {
         result
     }
 
-    pub fn from_black_chain(chain: &BlockChain, merkel_root: Hash) -> Self {
+    pub fn from_black_chain(chain: &BlockChain, merkel_root: Hash, witness_root: Hash) -> Self {
         Self {
             version: chain.get_version(),
             difficulty: chain.get_difficulty(),
-            previous_hash: chain.get_previous_hash().clone(),
+            previous_hash: chain.get_previous_hash(),
             merkel_root,
+            witness_root,
             nonce: 0,
-            timestamp: get_now_unix(),
+            // the clock can be behind the median time past of the chain
+            timestamp: chain.now().max(chain.median_time_past() + 1),
         }
     }
 }
@@ -92,14 +132,21 @@ impl fmt::Display for MiningBlock {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let encoded_hash = BASE64_STANDARD.encode(self.get_previous_hash());
         let encoded_merkel = BASE64_STANDARD.encode(self.merkel_root);
+        let encoded_witness = BASE64_STANDARD.encode(self.witness_root);
         let date = time::OffsetDateTime::from_unix_timestamp(self.timestamp as i64)
             .unwrap()
             .date();
 
         write!(
             f,
-            "Block:\n version:{}\n previous_hash:{}\n merkel_root:{}\n date:{}\n difficulty:{}\n nonce:{}",
-            self.version, encoded_hash, encoded_merkel, date, self.difficulty, self.nonce
+            "Block:\n version:{}\n previous_hash:{}\n merkel_root:{}\n witness_root:{}\n date:{}\n difficulty:{}\n nonce:{}",
+            self.version,
+            encoded_hash,
+            encoded_merkel,
+            encoded_witness,
+            date,
+            self.difficulty,
+            self.nonce
         )
     }
 }
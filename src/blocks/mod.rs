@@ -1,2 +1,6 @@
 pub mod block;
+pub mod block_filter;
+pub mod checkpoints;
+pub mod header_chain;
 pub mod mining_block;
+pub mod orphan_pool;
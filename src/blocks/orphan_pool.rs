@@ -0,0 +1,235 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    blocks::block::{MAX_BLOCK_SIZE, UntrustedBlock},
+    shared::Hash,
+};
+
+pub const MAX_ORPHAN_BLOCKS: usize = 100;
+pub const MAX_ORPHAN_BYTES: usize = 20 * MAX_BLOCK_SIZE;
+/// Seconds an orphan waits for its parent before being dropped.
+pub const ORPHAN_EXPIRY: u64 = 20 * 60;
+
+struct Orphan {
+    block: UntrustedBlock,
+    received_at: u64,
+    size: usize,
+}
+
+/// Blocks received before their parent, keyed by the hash of the parent.
+pub struct OrphanPool {
+    orphans: HashMap<Hash, Orphan>,
+    children: HashMap<Hash, Vec<Hash>>,
+    // hashes of the orphans, oldest first
+    arrivals: VecDeque<Hash>,
+    total_size: usize,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self {
+            orphans: HashMap::new(),
+            children: HashMap::new(),
+            arrivals: VecDeque::new(),
+            total_size: 0,
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+    pub fn get_total_size(&self) -> usize {
+        self.total_size
+    }
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.orphans.contains_key(hash)
+    }
+    /// Stores `block` until its parent arrives. Expired orphans are dropped first, then
+    /// the oldest ones while the pool is over its caps. Returns false for a known orphan.
+    pub fn insert(&mut self, block: UntrustedBlock, now: u64) -> bool {
+        let hash = *block.get_hash();
+        if self.contains(&hash) {
+            return false;
+        }
+        self.expire(now);
+
+        let parent = *block.get_mining().get_previous_hash();
+        let size = block.serialized_size();
+        self.children.entry(parent).or_default().push(hash);
+        self.arrivals.push_back(hash);
+        self.total_size += size;
+        self.orphans.insert(
+            hash,
+            Orphan {
+                block,
+                received_at: now,
+                size,
+            },
+        );
+
+        while self.orphans.len() > MAX_ORPHAN_BLOCKS || self.total_size > MAX_ORPHAN_BYTES {
+            let Some(oldest) = self.arrivals.front().copied() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+        true
+    }
+    /// Drops the orphans received more than `ORPHAN_EXPIRY` seconds before `now`.
+    pub fn expire(&mut self, now: u64) {
+        while let Some(oldest) = self.arrivals.front().copied() {
+            let received_at = self.orphans[&oldest].received_at;
+            if received_at.saturating_add(ORPHAN_EXPIRY) > now {
+                break;
+            }
+            self.remove(&oldest);
+        }
+    }
+    /// Removes and returns the orphans whose parent is `parent_hash`.
+    pub fn take_children(&mut self, parent_hash: &Hash) -> Vec<UntrustedBlock> {
+        let hashes = self.children.get(parent_hash).cloned().unwrap_or_default();
+        hashes.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+    /// First ancestor of `hash` that is not in the pool, the block to ask for.
+    pub fn missing_ancestor(&self, hash: &Hash) -> Hash {
+        let mut missing = *hash;
+        while let Some(orphan) = self.orphans.get(&missing) {
+            missing = *orphan.block.get_mining().get_previous_hash();
+        }
+        missing
+    }
+    fn remove(&mut self, hash: &Hash) -> Option<UntrustedBlock> {
+        let orphan = self.orphans.remove(hash)?;
+        let parent = orphan.block.get_mining().get_previous_hash();
+        if let Some(siblings) = self.children.get_mut(parent) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.children.remove(parent);
+            }
+        }
+        self.arrivals.retain(|arrival| arrival != hash);
+        self.total_size -= orphan.size;
+        Some(orphan.block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blocks::mining_block::MiningBlock,
+        test_utils::{TEST_TIME, mine_headers, signing_key},
+        transactions::{
+            transaction::{
+                MAX_INPUTS_PER_TRANSACTION, MAX_OUTPUTS_PER_TRANSACTION, RawTransaction,
+                SignedTransaction,
+            },
+            transaction_input::Input,
+            transaction_output::Output,
+        },
+    };
+
+    fn orphan(header: &MiningBlock, transactions: Vec<SignedTransaction>) -> UntrustedBlock {
+        UntrustedBlock::new(header.clone(), header.hash(), transactions)
+    }
+
+    // an unsigned transaction of the largest size
+    fn stuffing() -> SignedTransaction {
+        let payee = signing_key(9).verifying_key();
+        let raw = RawTransaction::new(
+            vec![Input::new([7; 32], 0); MAX_INPUTS_PER_TRANSACTION],
+            vec![Output::new(payee, 1); MAX_OUTPUTS_PER_TRANSACTION],
+            payee,
+        );
+        SignedTransaction::with_signatures(raw, vec![])
+    }
+
+    #[test]
+    fn the_oldest_orphans_make_room_for_new_ones() {
+        let headers = mine_headers(None, MAX_ORPHAN_BLOCKS + 10, 0);
+        let mut pool = OrphanPool::new();
+        for (index, header) in headers.iter().enumerate() {
+            assert!(pool.insert(orphan(header, vec![]), TEST_TIME + index as u64));
+        }
+        assert_eq!(pool.len(), MAX_ORPHAN_BLOCKS);
+        for (index, header) in headers.iter().enumerate() {
+            assert_eq!(pool.contains(&header.hash()), index >= 10);
+        }
+        // a known orphan is not stored twice
+        assert!(!pool.insert(orphan(&headers[50], vec![]), TEST_TIME + 200));
+        assert_eq!(pool.len(), MAX_ORPHAN_BLOCKS);
+    }
+
+    #[test]
+    fn orphans_are_capped_by_size() {
+        let transactions = vec![stuffing(); MAX_BLOCK_SIZE / stuffing().serialized_size()];
+        let headers = mine_headers(None, 30, 0);
+        let mut pool = OrphanPool::new();
+        for header in &headers {
+            pool.insert(orphan(header, transactions.clone()), TEST_TIME);
+            assert!(pool.get_total_size() <= MAX_ORPHAN_BYTES);
+        }
+        let size = orphan(&headers[0], transactions).serialized_size();
+        let kept = MAX_ORPHAN_BYTES / size;
+        assert!(kept < headers.len());
+        assert_eq!(pool.len(), kept);
+        assert_eq!(pool.get_total_size(), kept * size);
+        for (index, header) in headers.iter().enumerate() {
+            assert_eq!(pool.contains(&header.hash()), index >= headers.len() - kept);
+        }
+    }
+
+    #[test]
+    fn orphans_expire_after_a_while() {
+        let headers = mine_headers(None, 3, 0);
+        let mut pool = OrphanPool::new();
+        pool.insert(orphan(&headers[0], vec![]), TEST_TIME);
+        pool.insert(orphan(&headers[1], vec![]), TEST_TIME + 600);
+
+        pool.expire(TEST_TIME + ORPHAN_EXPIRY - 1);
+        assert_eq!(pool.len(), 2);
+        pool.expire(TEST_TIME + ORPHAN_EXPIRY);
+        assert!(!pool.contains(&headers[0].hash()));
+        assert!(pool.contains(&headers[1].hash()));
+
+        // inserting also drops the expired orphans
+        pool.insert(orphan(&headers[2], vec![]), TEST_TIME + 600 + ORPHAN_EXPIRY);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&headers[2].hash()));
+        assert_eq!(
+            pool.get_total_size(),
+            orphan(&headers[2], vec![]).serialized_size()
+        );
+    }
+
+    #[test]
+    fn a_branch_of_orphans_waits_for_its_first_ancestor() {
+        let headers = mine_headers(None, 5, 0);
+        let fork = mine_headers(Some(&headers[0]), 2, 1);
+        let mut pool = OrphanPool::new();
+        for header in headers[1..].iter().chain(&fork) {
+            pool.insert(orphan(header, vec![]), TEST_TIME);
+        }
+        assert_eq!(pool.missing_ancestor(&headers[4].hash()), headers[0].hash());
+        assert_eq!(pool.missing_ancestor(&fork[1].hash()), headers[0].hash());
+        assert_eq!(pool.missing_ancestor(&[3; 32]), [3; 32]);
+
+        let children: Vec<Hash> = pool
+            .take_children(&headers[0].hash())
+            .iter()
+            .map(|block| *block.get_hash())
+            .collect();
+        assert_eq!(children, [headers[1].hash(), fork[0].hash()]);
+        assert_eq!(pool.len(), 4);
+        assert!(pool.take_children(&headers[0].hash()).is_empty());
+        assert_eq!(pool.missing_ancestor(&headers[4].hash()), headers[1].hash());
+    }
+}
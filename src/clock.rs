@@ -0,0 +1,241 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use libp2p::PeerId;
+
+use crate::shared::get_now_unix;
+
+/// Source of the current unix time (in seconds) for every time dependent rule.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+    /// Records the time announced by `peer` when it connects, only the clocks following
+    /// the network use it.
+    fn add_peer_time(&self, _peer: PeerId, _peer_time: u64) {}
+}
+
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        get_now_unix()
+    }
+}
+
+/// Always gives the same time.
+pub struct FixedClock {
+    now: u64,
+}
+impl FixedClock {
+    pub fn new(now: u64) -> Self {
+        Self { now }
+    }
+}
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        self.now
+    }
+}
+
+/// Time only moves when told to.
+pub struct ManualClock {
+    now: AtomicU64,
+}
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        Self {
+            now: AtomicU64::new(now),
+        }
+    }
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+    pub fn advance(&self, seconds: u64) {
+        self.now.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+// number of peers whose offset is kept, the one heard from the longest ago is dropped first
+const MAX_OFFSET_SAMPLES: usize = 199;
+// a peer majority is not trusted to move our time further than that (in seconds)
+pub const MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+
+/// Local clock corrected by the median of the offsets reported by the peers, one offset per
+/// peer so that a peer reconnecting many times counts once.
+/// The correction is ignored when the peers disagree with us by more than `MAX_TIME_ADJUSTMENT`.
+pub struct NetworkAdjustedClock<C: Clock> {
+    local: C,
+    // the latest offset of each peer, from the peer heard from the longest ago
+    offsets: Mutex<VecDeque<(PeerId, i64)>>,
+}
+impl<C: Clock> NetworkAdjustedClock<C> {
+    pub fn new(local: C) -> Self {
+        Self {
+            local,
+            offsets: Mutex::new(VecDeque::new()),
+        }
+    }
+    pub fn get_offset(&self) -> i64 {
+        let mut offsets: Vec<i64> = self
+            .offsets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, offset)| *offset)
+            .collect();
+        if offsets.is_empty() {
+            return 0;
+        }
+        offsets.sort_unstable();
+        let median = offsets[offsets.len() / 2];
+        if median.unsigned_abs() > MAX_TIME_ADJUSTMENT.unsigned_abs() {
+            0
+        } else {
+            median
+        }
+    }
+}
+impl<C: Clock> Clock for NetworkAdjustedClock<C> {
+    fn now(&self) -> u64 {
+        self.local.now().saturating_add_signed(self.get_offset())
+    }
+    fn add_peer_time(&self, peer: PeerId, peer_time: u64) {
+        // any u64 time, the offsets too far to fit are ignored by `get_offset` anyway
+        let offset = (i128::from(peer_time) - i128::from(self.local.now()))
+            .clamp(i64::MIN.into(), i64::MAX.into()) as i64;
+        let mut offsets = self.offsets.lock().unwrap();
+        if let Some(position) = offsets.iter().position(|(known, _)| *known == peer) {
+            offsets.remove(position);
+        } else if offsets.len() == MAX_OFFSET_SAMPLES {
+            offsets.pop_front();
+        }
+        offsets.push_back((peer, offset));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn adjusted_clock() -> NetworkAdjustedClock<FixedClock> {
+        NetworkAdjustedClock::new(FixedClock::new(NOW))
+    }
+
+    #[test]
+    fn fixed_clock_never_moves() {
+        let clock = FixedClock::new(NOW);
+        clock.add_peer_time(PeerId::random(), NOW + 100);
+        assert_eq!(clock.now(), NOW);
+        assert_eq!(clock.now(), NOW);
+    }
+
+    #[test]
+    fn manual_clock_moves_when_told() {
+        let clock = ManualClock::new(NOW);
+        assert_eq!(clock.now(), NOW);
+        clock.advance(60);
+        assert_eq!(clock.now(), NOW + 60);
+        clock.set(NOW - 10);
+        assert_eq!(clock.now(), NOW - 10);
+    }
+
+    #[test]
+    fn without_peers_the_local_time_is_kept() {
+        let clock = adjusted_clock();
+        assert_eq!(clock.get_offset(), 0);
+        assert_eq!(clock.now(), NOW);
+    }
+
+    #[test]
+    fn the_median_offset_corrects_the_local_time() {
+        let clock = adjusted_clock();
+        for peer_time in [NOW + 30, NOW - 1_000, NOW + 10, NOW + 5_000, NOW + 20] {
+            clock.add_peer_time(PeerId::random(), peer_time);
+        }
+        assert_eq!(clock.get_offset(), 20);
+        assert_eq!(clock.now(), NOW + 20);
+
+        // a single outlier cannot move it far
+        clock.add_peer_time(PeerId::random(), NOW + 4_000);
+        assert_eq!(clock.get_offset(), 30);
+    }
+
+    #[test]
+    fn each_peer_counts_once() {
+        let clock = adjusted_clock();
+        let peers: Vec<PeerId> = (0..3).map(|_| PeerId::random()).collect();
+        for (peer, offset) in peers.iter().zip([10, 20, 30]) {
+            clock.add_peer_time(*peer, NOW + offset);
+        }
+        assert_eq!(clock.get_offset(), 20);
+
+        // a peer reconnecting again and again only replaces its own offset
+        for _ in 0..MAX_OFFSET_SAMPLES {
+            clock.add_peer_time(peers[2], NOW + 5_000);
+        }
+        assert_eq!(clock.get_offset(), 20);
+        clock.add_peer_time(peers[1], NOW - 100);
+        assert_eq!(clock.get_offset(), 10);
+    }
+
+    #[test]
+    fn a_median_beyond_the_max_adjustment_is_ignored() {
+        let clock = adjusted_clock();
+        let too_far = NOW + MAX_TIME_ADJUSTMENT as u64 + 1;
+        clock.add_peer_time(PeerId::random(), too_far);
+        clock.add_peer_time(PeerId::random(), too_far);
+        clock.add_peer_time(PeerId::random(), NOW);
+        assert_eq!(clock.get_offset(), 0);
+
+        let clock = adjusted_clock();
+        clock.add_peer_time(PeerId::random(), NOW + MAX_TIME_ADJUSTMENT as u64);
+        assert_eq!(clock.get_offset(), MAX_TIME_ADJUSTMENT);
+    }
+
+    #[test]
+    fn extreme_peer_times_do_not_overflow() {
+        let clock = adjusted_clock();
+        clock.add_peer_time(PeerId::random(), u64::MAX);
+        clock.add_peer_time(PeerId::random(), u64::MAX);
+        clock.add_peer_time(PeerId::random(), 0);
+        assert_eq!(clock.get_offset(), 0);
+        assert_eq!(clock.now(), NOW);
+
+        let clock = NetworkAdjustedClock::new(FixedClock::new(u64::MAX));
+        clock.add_peer_time(PeerId::random(), 0);
+        assert_eq!(clock.get_offset(), 0);
+        clock.add_peer_time(PeerId::random(), u64::MAX - 60);
+        assert_eq!(clock.get_offset(), -60);
+    }
+
+    #[test]
+    fn the_peers_heard_from_the_longest_ago_are_dropped_first() {
+        let clock = adjusted_clock();
+        let first = PeerId::random();
+        clock.add_peer_time(first, NOW + 100);
+        for _ in 1..MAX_OFFSET_SAMPLES {
+            clock.add_peer_time(PeerId::random(), NOW + 100);
+        }
+        assert_eq!(clock.get_offset(), 100);
+        // heard from again, the first peer is now the most recent one
+        clock.add_peer_time(first, NOW - 100);
+        for _ in 0..MAX_OFFSET_SAMPLES / 2 {
+            clock.add_peer_time(PeerId::random(), NOW - 100);
+        }
+        assert_eq!(clock.get_offset(), -100);
+        for _ in 0..MAX_OFFSET_SAMPLES / 2 + 1 {
+            clock.add_peer_time(PeerId::random(), NOW + 100);
+        }
+        assert_eq!(clock.get_offset(), 100);
+    }
+}
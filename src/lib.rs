@@ -0,0 +1,11 @@
+pub mod block_chain;
+pub mod blocks;
+pub mod clock;
+pub mod light_client;
+mod shared;
+pub mod sync;
+#[cfg(test)]
+mod test_utils;
+pub mod transactions;
+pub mod utxo_map;
+pub mod wallets;
@@ -0,0 +1,451 @@
+use std::{collections::HashMap, sync::Arc};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+
+use crate::{
+    block_chain::{CHAIN_VERSION, chain_difficulty},
+    blocks::{
+        block_filter::FilterMatcher,
+        checkpoints::{Checkpoints, Network},
+        header_chain::HeaderChain,
+    },
+    clock::{Clock, SystemClock},
+    shared::Hash,
+    sync::{
+        BlockSource, MAX_FILTERS_PER_REQUEST, MAX_HEADERS_PER_REQUEST, SyncError, SyncRequest,
+        SyncResponse, request_headers,
+    },
+    transactions::merkel::verify_merkel_proof,
+};
+
+/// Simplified payment verification: follows the header chain without the blocks and
+/// checks that the transactions of interest are in it with merkel proofs from full nodes.
+pub struct LightClient {
+    headers: HeaderChain,
+    // transactions of interest, with the block they were proven in
+    watched: HashMap<Hash, Option<Hash>>,
+    // verified filter-header chain, with the hash of each block
+    filter_headers: Vec<(Hash, Hash)>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for LightClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LightClient {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self::for_network(Network::Main, clock)
+    }
+    /// Follows the header chain of `network`.
+    pub fn for_network(network: Network, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            headers: HeaderChain::new(chain_difficulty(network), CHAIN_VERSION)
+                .with_checkpoints(Checkpoints::for_network(network)),
+            watched: HashMap::new(),
+            filter_headers: vec![],
+            clock,
+        }
+    }
+    /// To be called before the first sync.
+    pub fn with_checkpoints(mut self, checkpoints: Checkpoints) -> Self {
+        self.headers = self.headers.with_checkpoints(checkpoints);
+        self
+    }
+    /// To give to `sync::handshake` with each new peer.
+    pub fn get_clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+    pub fn get_headers(&self) -> &HeaderChain {
+        &self.headers
+    }
+    pub fn watch(&mut self, tx_id: Hash) {
+        self.watched.entry(tx_id).or_insert(None);
+    }
+    pub fn unwatch(&mut self, tx_id: &Hash) {
+        self.watched.remove(tx_id);
+    }
+    /// Number of blocks on top of the transaction, counting its own block,
+    /// None while it is not proven in the current header chain.
+    pub fn confirmations(&self, tx_id: &Hash) -> Option<usize> {
+        let block_hash = self.watched.get(tx_id)?.as_ref()?;
+        let height = self.headers.get_height(block_hash)?;
+        Some(self.headers.len() - height)
+    }
+    /// Syncs the headers, then asks a proof for each watched transaction not confirmed yet.
+    /// Returns the number of new headers.
+    pub fn sync(&mut self, peer: &impl BlockSource) -> Result<usize, LightClientError> {
+        let new_header_count = self.sync_headers(peer)?;
+        let unconfirmed: Vec<Hash> = self
+            .watched
+            .keys()
+            .filter(|tx_id| self.confirmations(tx_id).is_none())
+            .copied()
+            .collect();
+        for tx_id in unconfirmed {
+            match self.request_proof(&tx_id, peer) {
+                Ok(_) | Err(LightClientError::TransactionNotFound) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(new_header_count)
+    }
+    /// Returns the number of new headers.
+    pub fn sync_headers(&mut self, peer: &impl BlockSource) -> Result<usize, SyncError> {
+        let mut new_header_count = 0;
+        loop {
+            let headers = request_headers(peer, self.headers.locator())?;
+            let header_count = headers.len();
+            let accepted = self
+                .headers
+                .accept_headers(&headers, self.clock.now())
+                .map_err(SyncError::InvalidHeaders)?;
+            new_header_count += accepted;
+            if header_count < MAX_HEADERS_PER_REQUEST || accepted == 0 {
+                break;
+            }
+        }
+        Ok(new_header_count)
+    }
+    /// Asks `peer` for the proof that `tx_id` is in a block of our header chain and checks it.
+    /// The transaction is watched from then on. Returns its number of confirmations.
+    pub fn request_proof(
+        &mut self,
+        tx_id: &Hash,
+        peer: &impl BlockSource,
+    ) -> Result<usize, LightClientError> {
+        let SyncResponse::MerkelProof(proof) = peer.respond(SyncRequest::GetMerkelProof(*tx_id))
+        else {
+            return Err(SyncError::UnexpectedResponse.into());
+        };
+        let proof = proof.ok_or(LightClientError::TransactionNotFound)?;
+        let header = self
+            .headers
+            .get_height(&proof.block_hash)
+            .and_then(|height| self.headers.get_header(height))
+            .ok_or(LightClientError::UnknownBlock(proof.block_hash))?;
+        if !verify_merkel_proof(&proof.proof, tx_id, header.get_merkel_root()) {
+            return Err(LightClientError::InvalidProof {
+                block_hash: proof.block_hash,
+            });
+        }
+        self.watched.insert(*tx_id, Some(proof.block_hash));
+        Ok(self.confirmations(tx_id).unwrap_or_default())
+    }
+    /// Downloads the filters of the blocks from `from_height` and returns the hashes of the
+    /// blocks `matcher` matches, the ones to download. Each filter must follow the
+    /// filter-header chain, which is checked against the one verified before.
+    pub fn scan_filters(
+        &mut self,
+        peer: &impl BlockSource,
+        matcher: &FilterMatcher,
+        from_height: usize,
+    ) -> Result<Vec<Hash>, LightClientError> {
+        // filter headers of blocks that left the header chain
+        while let Some((block_hash, _)) = self.filter_headers.last()
+            && self.headers.get_hash(self.filter_headers.len() - 1) != Some(block_hash)
+        {
+            self.filter_headers.pop();
+        }
+
+        let mut matching_blocks = vec![];
+        // the previous filter header must be verified
+        let mut start_height = from_height.min(self.filter_headers.len());
+        while start_height < self.headers.len() {
+            let stop_height = (start_height + MAX_FILTERS_PER_REQUEST).min(self.headers.len()) - 1;
+            let stop_hash = self
+                .headers
+                .get_hash(stop_height)
+                .copied()
+                .unwrap_or_default();
+            let SyncResponse::FilterHeaders { previous, headers } =
+                peer.respond(SyncRequest::GetFilterHeaders {
+                    start_height,
+                    stop_hash,
+                })
+            else {
+                return Err(SyncError::UnexpectedResponse.into());
+            };
+            let SyncResponse::Filters(filters) = peer.respond(SyncRequest::GetFilters {
+                start_height,
+                stop_hash,
+            }) else {
+                return Err(SyncError::UnexpectedResponse.into());
+            };
+            let expected_previous = match start_height {
+                0 => Hash::default(),
+                _ => self.filter_headers[start_height - 1].1,
+            };
+            let count = stop_height + 1 - start_height;
+            if previous != expected_previous || headers.len() != count || filters.len() != count {
+                return Err(LightClientError::InvalidFilter {
+                    height: start_height,
+                });
+            }
+
+            let mut previous = previous;
+            for (height, ((block_hash, filter), filter_header)) in
+                (start_height..).zip(filters.iter().zip(headers))
+            {
+                let known_header = self.filter_headers.get(height).map(|(_, header)| header);
+                if self.headers.get_hash(height) != Some(block_hash)
+                    || filter.header(&previous) != filter_header
+                    || known_header.is_some_and(|known| known != &filter_header)
+                {
+                    return Err(LightClientError::InvalidFilter { height });
+                }
+                if known_header.is_none() {
+                    self.filter_headers.push((*block_hash, filter_header));
+                }
+                if height >= from_height && matcher.matches(block_hash, filter) {
+                    matching_blocks.push(*block_hash);
+                }
+                previous = filter_header;
+            }
+            start_height = stop_height + 1;
+        }
+        Ok(matching_blocks)
+    }
+}
+
+#[derive(Debug)]
+pub enum LightClientError {
+    Sync(SyncError),
+    TransactionNotFound,
+    /// The proof is for a block that is not in our header chain.
+    UnknownBlock(Hash),
+    InvalidProof {
+        block_hash: Hash,
+    },
+    /// The filters or filter headers sent for `height` don't match our chains.
+    InvalidFilter {
+        height: usize,
+    },
+}
+impl From<SyncError> for LightClientError {
+    fn from(error: SyncError) -> Self {
+        Self::Sync(error)
+    }
+}
+impl std::fmt::Display for LightClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sync(error) => write!(f, "{error}"),
+            Self::TransactionNotFound => write!(f, "peer does not know the transaction"),
+            Self::InvalidFilter { height } => write!(f, "invalid filter at height {height}"),
+            Self::UnknownBlock(block_hash) => write!(
+                f,
+                "proof for block {} which is not in the header chain",
+                BASE64_STANDARD.encode(block_hash)
+            ),
+            Self::InvalidProof { block_hash } => write!(
+                f,
+                "invalid merkel proof for block {}",
+                BASE64_STANDARD.encode(block_hash)
+            ),
+        }
+    }
+}
+impl std::error::Error for LightClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Sync(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_chain::BlockChain,
+        clock::FixedClock,
+        test_utils::{TEST_TIME, add_block, chain_of, extend, regtest_chain, signing_key},
+        transactions::{
+            transaction::RawTransaction, transaction_input::Input, transaction_output::Output,
+        },
+    };
+
+    fn light_client() -> LightClient {
+        LightClient::for_network(Network::Regtest, Arc::new(FixedClock::new(TEST_TIME)))
+    }
+
+    // adds a block where the key 1 pays the coinbase of the first block to the key 2,
+    // returns the id of the payment
+    fn add_payment(node: &mut BlockChain) -> Hash {
+        let coinbase = *node.get_block(0).unwrap().get_transactions()[0].get_hash();
+        let amount = node.get_coin_base_amount();
+        let payment = RawTransaction::new(
+            vec![Input::new(coinbase, 0)],
+            vec![Output::new(signing_key(2).verifying_key(), amount)],
+            signing_key(1).verifying_key(),
+        )
+        .sign(&signing_key(1));
+        let tx_id = *payment.get_hash();
+        add_block(node, &signing_key(1), vec![payment]);
+        tx_id
+    }
+
+    // a full node answering every proof with the one of another transaction
+    struct LyingNode<'a> {
+        node: &'a BlockChain,
+        proven: Hash,
+    }
+    impl BlockSource for LyingNode<'_> {
+        fn respond(&self, request: SyncRequest) -> SyncResponse {
+            match request {
+                SyncRequest::GetMerkelProof(_) => {
+                    self.node.respond(SyncRequest::GetMerkelProof(self.proven))
+                }
+                request => self.node.respond(request),
+            }
+        }
+    }
+
+    // a full node sending the filter of its first block in place of the one at `height`
+    struct SwappedFilter<'a> {
+        node: &'a BlockChain,
+        height: usize,
+    }
+    impl BlockSource for SwappedFilter<'_> {
+        fn respond(&self, request: SyncRequest) -> SyncResponse {
+            let start_height = match &request {
+                SyncRequest::GetFilters { start_height, .. } => *start_height,
+                _ => return self.node.respond(request),
+            };
+            let SyncResponse::Filters(mut filters) = self.node.respond(request) else {
+                unreachable!()
+            };
+            if let Some((_, filter)) = filters.get_mut(self.height - start_height) {
+                *filter = self.node.get_filter(0).unwrap().clone();
+            }
+            SyncResponse::Filters(filters)
+        }
+    }
+
+    #[test]
+    fn follows_a_full_node_and_confirms_its_transactions() {
+        // more headers than one answer holds
+        let mut node = chain_of(MAX_HEADERS_PER_REQUEST + 40, &signing_key(1));
+        let tx_id = add_payment(&mut node);
+        let mut client = light_client();
+        client.watch(tx_id);
+
+        assert_eq!(client.sync(&node).unwrap(), node.len());
+        assert_eq!(client.get_headers().tip_hash(), *node.peak().get_hash());
+        assert_eq!(client.confirmations(&tx_id), Some(1));
+
+        extend(&mut node, 5, &signing_key(1));
+        assert_eq!(client.sync(&node).unwrap(), 5);
+        assert_eq!(client.confirmations(&tx_id), Some(6));
+
+        // a transaction the node does not have
+        assert!(matches!(
+            client.request_proof(&[5; 32], &node),
+            Err(LightClientError::TransactionNotFound)
+        ));
+        assert_eq!(client.confirmations(&[5; 32]), None);
+    }
+
+    #[test]
+    fn a_reorganization_unconfirms_a_transaction() {
+        let mut node = chain_of(10, &signing_key(1));
+        let mut fork = chain_of(10, &signing_key(1));
+        let tx_id = add_payment(&mut node);
+        extend(&mut node, 2, &signing_key(1));
+        // a heavier branch from the same blocks, without the payment
+        extend(&mut fork, 5, &signing_key(3));
+
+        let mut client = light_client();
+        client.watch(tx_id);
+        client.sync(&node).unwrap();
+        assert_eq!(client.confirmations(&tx_id), Some(3));
+
+        client.sync(&fork).unwrap();
+        assert_eq!(client.get_headers().tip_hash(), *fork.peak().get_hash());
+        assert_eq!(client.confirmations(&tx_id), None);
+    }
+
+    #[test]
+    fn proofs_must_hold_against_the_header_chain() {
+        let mut node = chain_of(10, &signing_key(1));
+        let mut client = light_client();
+        client.sync(&node).unwrap();
+        let tx_id = add_payment(&mut node);
+
+        // proven in a block the client has not synced yet
+        assert!(matches!(
+            client.request_proof(&tx_id, &node),
+            Err(LightClientError::UnknownBlock(block_hash)) if block_hash == *node.peak().get_hash()
+        ));
+        client.sync_headers(&node).unwrap();
+
+        let coinbase = *node.peak().get_transactions()[0].get_hash();
+        let liar = LyingNode {
+            node: &node,
+            proven: coinbase,
+        };
+        assert!(matches!(
+            client.request_proof(&tx_id, &liar),
+            Err(LightClientError::InvalidProof { block_hash }) if block_hash == *node.peak().get_hash()
+        ));
+        assert_eq!(client.confirmations(&tx_id), None);
+        client.watch(tx_id);
+        assert!(matches!(
+            client.sync(&liar),
+            Err(LightClientError::InvalidProof { .. })
+        ));
+
+        assert_eq!(client.request_proof(&tx_id, &node).unwrap(), 1);
+        assert_eq!(client.confirmations(&tx_id), Some(1));
+        // a node without any block refuses to prove it
+        assert!(matches!(
+            client.request_proof(&coinbase, &regtest_chain()),
+            Err(LightClientError::TransactionNotFound)
+        ));
+    }
+
+    #[test]
+    fn filters_must_follow_the_filter_header_chain() {
+        // more filters than one answer holds
+        let mut node = chain_of(MAX_FILTERS_PER_REQUEST + 20, &signing_key(1));
+        let tx_id = add_payment(&mut node);
+        extend(&mut node, 3, &signing_key(1));
+        let payment_block = *node.find_transaction(&tx_id).unwrap().0.get_hash();
+        let mut matcher = FilterMatcher::new();
+        matcher.add_pubkey(&signing_key(2).verifying_key());
+
+        let mut client = light_client();
+        client.sync_headers(&node).unwrap();
+        let liar = SwappedFilter {
+            node: &node,
+            height: MAX_FILTERS_PER_REQUEST + 5,
+        };
+        assert!(matches!(
+            client.scan_filters(&liar, &matcher, 0),
+            Err(LightClientError::InvalidFilter { height }) if height == liar.height
+        ));
+
+        assert_eq!(
+            client.scan_filters(&node, &matcher, 0).unwrap(),
+            [payment_block]
+        );
+        // the filter headers verified before are kept
+        assert!(matches!(
+            client.scan_filters(&liar, &matcher, MAX_FILTERS_PER_REQUEST),
+            Err(LightClientError::InvalidFilter { height }) if height == liar.height
+        ));
+        assert!(
+            client
+                .scan_filters(&node, &matcher, node.len() - 2)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
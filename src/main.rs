@@ -1,27 +1,60 @@
-use std::time::Instant;
+use std::{io, path::Path, sync::Arc, time::Instant};
 
-use rand::rngs::OsRng;
+use crypto::{
+    block_chain::BlockChain,
+    blocks::checkpoints::Network,
+    clock::{NetworkAdjustedClock, SystemClock},
+    transactions::{address::Address, transaction::ValidatedTransaction},
+    wallets::wallet::Wallet,
+};
 
-use crate::{block_chain::BlockChain, transactions::transaction::ValidatedTransaction};
+const WALLET_PATH: &str = "wallet.keystore";
+const NETWORK: Network = Network::Main;
 
-mod block_chain;
-pub mod blocks;
-mod shared;
-pub mod transactions;
-pub mod utxo_map;
-
-use ed25519_dalek::SigningKey;
+// the password comes from WALLET_PASSWORD, or is asked for
+fn wallet_password() -> String {
+    if let Ok(password) = std::env::var("WALLET_PASSWORD") {
+        return password;
+    }
+    println!("Mot de passe du portefeuille:");
+    let mut password = String::new();
+    io::stdin()
+        .read_line(&mut password)
+        .expect("cannot read the password");
+    password.trim_end_matches(['\r', '\n']).to_string()
+}
 
 fn main() {
-    let mut rng = OsRng {};
-    let mut sign_key = SigningKey::generate(&mut rng);
-    let mut block_chain = BlockChain::new();
-    let coin_base = ValidatedTransaction::get_coin_base(&block_chain, &mut sign_key);
-    let transactions = [coin_base];
-    let mining_block = block_chain.get_mining_block(&transactions);
+    let wallet = match Wallet::load_or_create(Path::new(WALLET_PATH), &wallet_password()) {
+        Ok((wallet, None)) => wallet,
+        Ok((wallet, Some(mnemonic))) => {
+            println!(
+                "Nouveau portefeuille, notez ces mots pour le restaurer:\n{}",
+                *mnemonic
+            );
+            wallet
+        }
+        Err(error) => {
+            eprintln!("{error}");
+            return;
+        }
+    };
+    let sign_key = wallet.get_mining_key().expect("a new wallet has a key");
+    println!(
+        "Adresse de minage: {}",
+        Address::from_pubkey(NETWORK, &sign_key.verifying_key())
+    );
+    // corrected by the time of each peer on its handshake
+    let clock = Arc::new(NetworkAdjustedClock::new(SystemClock));
+    let mut block_chain = BlockChain::for_network(NETWORK, clock);
+    let coin_base = ValidatedTransaction::get_coin_base(&block_chain, sign_key);
+    let transactions = vec![coin_base];
+    let mining_block = block_chain
+        .get_mining_block(&transactions)
+        .expect("the coinbase is always there");
     let now = Instant::now();
     if let Some(mined_block) = mining_block.mine_multithread() {
-        block_chain.update(mined_block);
+        block_chain.update(mined_block.with_transactions(transactions));
         println!("Block miné en {}s", now.elapsed().as_secs_f32());
         println!("Voici le block miné:\n{}", block_chain.peak())
     } else {
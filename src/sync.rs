@@ -0,0 +1,664 @@
+use libp2p::PeerId;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    block_chain::BlockChain,
+    blocks::{
+        block::{Block, BlockValidationError, UntrustedBlock},
+        block_filter::BlockFilter,
+        mining_block::MiningBlock,
+        orphan_pool::{MAX_ORPHAN_BLOCKS, OrphanPool},
+    },
+    clock::Clock,
+    shared::Hash,
+    transactions::merkel::{MerkelProof, MerkelTree},
+};
+
+pub const MAX_HEADERS_PER_REQUEST: usize = 2_000;
+pub const MAX_BLOCKS_PER_INVENTORY: usize = 500;
+pub const MAX_FILTERS_PER_REQUEST: usize = 1_000;
+// number of block bodies downloaded in parallel before being connected
+const BLOCK_DOWNLOAD_WINDOW: usize = 128;
+
+/// What a syncing node asks a peer for. The locator lets the peer find the last block
+/// both share, the answer starts right after it and ends at `stop_hash` when given.
+#[derive(Clone, Debug)]
+pub enum SyncRequest {
+    GetHeaders {
+        locator: Vec<Hash>,
+        stop_hash: Option<Hash>,
+    },
+    GetBlocks {
+        locator: Vec<Hash>,
+        stop_hash: Option<Hash>,
+    },
+    GetBlock(Hash),
+    /// Proof that a transaction is in a block, for light clients.
+    GetMerkelProof(Hash),
+    /// Filters of the blocks from `start_height` to `stop_hash` included.
+    GetFilters {
+        start_height: usize,
+        stop_hash: Hash,
+    },
+    GetFilterHeaders {
+        start_height: usize,
+        stop_hash: Hash,
+    },
+    /// Time of the peer, asked once when it connects.
+    GetTime,
+}
+
+pub enum SyncResponse {
+    Headers(Vec<MiningBlock>),
+    /// Hashes of the blocks following the fork point.
+    Inventory(Vec<Hash>),
+    Block(Option<UntrustedBlock>),
+    MerkelProof(Option<TransactionProof>),
+    /// Each filter with the hash of its block.
+    Filters(Vec<(Hash, BlockFilter)>),
+    /// `previous` is the filter header before `start_height`.
+    FilterHeaders {
+        previous: Hash,
+        headers: Vec<Hash>,
+    },
+    Time(u64),
+}
+
+/// Inclusion proof of a transaction in the block `block_hash`.
+pub struct TransactionProof {
+    pub block_hash: Hash,
+    pub proof: MerkelProof,
+}
+
+pub trait BlockSource: Sync {
+    fn respond(&self, request: SyncRequest) -> SyncResponse;
+}
+
+/// A full node serves the blocks it has.
+impl BlockSource for BlockChain {
+    fn respond(&self, request: SyncRequest) -> SyncResponse {
+        match request {
+            SyncRequest::GetHeaders { locator, stop_hash } => SyncResponse::Headers(
+                blocks_after(self, &locator, stop_hash, MAX_HEADERS_PER_REQUEST)
+                    .map(|block| block.get_mining().clone())
+                    .collect(),
+            ),
+            SyncRequest::GetBlocks { locator, stop_hash } => SyncResponse::Inventory(
+                blocks_after(self, &locator, stop_hash, MAX_BLOCKS_PER_INVENTORY)
+                    .map(|block| *block.get_hash())
+                    .collect(),
+            ),
+            SyncRequest::GetBlock(hash) => {
+                SyncResponse::Block(self.get_block_by_hash(&hash).map(Block::to_untrusted))
+            }
+            SyncRequest::GetMerkelProof(tx_id) => SyncResponse::MerkelProof(
+                self.find_transaction(&tx_id).and_then(|(block, tx_index)| {
+                    let proof = MerkelTree::new(block.get_transactions())
+                        .ok()?
+                        .prove(tx_index)?;
+                    Some(TransactionProof {
+                        block_hash: *block.get_hash(),
+                        proof,
+                    })
+                }),
+            ),
+            SyncRequest::GetFilters {
+                start_height,
+                stop_hash,
+            } => SyncResponse::Filters(
+                filter_heights(self, start_height, &stop_hash)
+                    .filter_map(|height| {
+                        let block_hash = *self.get_block(height)?.get_hash();
+                        Some((block_hash, self.get_filter(height)?.clone()))
+                    })
+                    .collect(),
+            ),
+            SyncRequest::GetFilterHeaders {
+                start_height,
+                stop_hash,
+            } => SyncResponse::FilterHeaders {
+                previous: start_height
+                    .checked_sub(1)
+                    .and_then(|height| self.get_filter_header(height))
+                    .copied()
+                    .unwrap_or_default(),
+                headers: filter_heights(self, start_height, &stop_hash)
+                    .filter_map(|height| self.get_filter_header(height).copied())
+                    .collect(),
+            },
+            SyncRequest::GetTime => SyncResponse::Time(self.now()),
+        }
+    }
+}
+
+// at most `max` blocks after the fork point of `locator`, up to `stop_hash` included
+fn blocks_after<'a>(
+    chain: &'a BlockChain,
+    locator: &[Hash],
+    stop_hash: Option<Hash>,
+    max: usize,
+) -> impl Iterator<Item = &'a Block> {
+    let mut stopped = false;
+    (chain.find_fork_point(locator)..chain.len())
+        .filter_map(|height| chain.get_block(height))
+        .take(max)
+        .take_while(move |block| {
+            let take = !stopped;
+            stopped = stop_hash.as_ref() == Some(block.get_hash());
+            take
+        })
+}
+
+// heights from `start_height` to the block `stop_hash`, at most `MAX_FILTERS_PER_REQUEST`
+fn filter_heights(
+    chain: &BlockChain,
+    start_height: usize,
+    stop_hash: &Hash,
+) -> std::ops::Range<usize> {
+    match chain.get_headers().get_height(stop_hash) {
+        Some(stop_height) if stop_height < chain.len() && stop_height >= start_height => {
+            start_height..(stop_height + 1).min(start_height + MAX_FILTERS_PER_REQUEST)
+        }
+        _ => 0..0,
+    }
+}
+
+#[derive(Debug)]
+pub enum SyncError {
+    InvalidHeaders(BlockValidationError),
+    UnexpectedResponse,
+    MissingBlock {
+        height: usize,
+    },
+    InvalidOrphan(BlockValidationError),
+    InvalidBlock {
+        height: usize,
+        error: BlockValidationError,
+    },
+}
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidHeaders(error) => write!(f, "invalid headers: {error}"),
+            Self::UnexpectedResponse => write!(f, "peer answered another request"),
+            Self::InvalidOrphan(error) => write!(f, "invalid orphan block: {error}"),
+            Self::MissingBlock { height } => {
+                write!(f, "peer did not send the block at height {height}")
+            }
+            Self::InvalidBlock { height, error } => {
+                write!(f, "invalid block at height {height}: {error}")
+            }
+        }
+    }
+}
+impl std::error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::InvalidHeaders(error)
+            | Self::InvalidOrphan(error)
+            | Self::InvalidBlock { error, .. } => Some(error),
+            Self::MissingBlock { .. } | Self::UnexpectedResponse => None,
+        }
+    }
+}
+
+/// First exchange with a newly connected peer: its time is given to `clock`, which
+/// corrects itself with the times of the peers when it follows the network.
+pub fn handshake(
+    clock: &dyn Clock,
+    peer_id: PeerId,
+    peer: &impl BlockSource,
+) -> Result<(), SyncError> {
+    match peer.respond(SyncRequest::GetTime) {
+        SyncResponse::Time(peer_time) => {
+            clock.add_peer_time(peer_id, peer_time);
+            Ok(())
+        }
+        _ => Err(SyncError::UnexpectedResponse),
+    }
+}
+
+/// Headers-first synchronization: downloads and validates the headers of the peer from
+/// our fork point, then downloads the block bodies and connects them.
+/// Returns the number of new blocks.
+pub fn sync_from(chain: &mut BlockChain, peer: &impl BlockSource) -> Result<usize, SyncError> {
+    sync_headers(chain, peer)?;
+    sync_blocks(chain, peer)
+}
+
+/// Asks for headers after the locator of our header chain until the peer has no more.
+/// A branch with more work than ours replaces it, however many requests it takes to get it.
+/// Returns the number of new headers.
+pub fn sync_headers(chain: &mut BlockChain, peer: &impl BlockSource) -> Result<usize, SyncError> {
+    let mut new_header_count = 0;
+    loop {
+        let headers = request_headers(peer, chain.get_headers().locator())?;
+        let header_count = headers.len();
+        let accepted = chain
+            .accept_headers(headers)
+            .map_err(SyncError::InvalidHeaders)?;
+        new_header_count += accepted;
+        if header_count < MAX_HEADERS_PER_REQUEST || accepted == 0 {
+            break;
+        }
+    }
+    Ok(new_header_count)
+}
+
+/// Asks `peer` for the headers after `locator`.
+pub fn request_headers(
+    peer: &impl BlockSource,
+    locator: Vec<Hash>,
+) -> Result<Vec<MiningBlock>, SyncError> {
+    let request = SyncRequest::GetHeaders {
+        locator,
+        stop_hash: None,
+    };
+    match peer.respond(request) {
+        SyncResponse::Headers(headers) => Ok(headers),
+        _ => Err(SyncError::UnexpectedResponse),
+    }
+}
+
+/// Downloads the bodies of the headers ahead of the blocks, `BLOCK_DOWNLOAD_WINDOW`
+/// at a time in parallel, and connects them in order. Returns the number of new blocks.
+pub fn sync_blocks(chain: &mut BlockChain, peer: &impl BlockSource) -> Result<usize, SyncError> {
+    let start = chain.len();
+    while chain.len() < chain.get_headers().len() {
+        let first_height = chain.len();
+        let last_height = (first_height + BLOCK_DOWNLOAD_WINDOW).min(chain.get_headers().len());
+        let hashes: Vec<Hash> = (first_height..last_height)
+            .filter_map(|height| chain.get_headers().get_hash(height).copied())
+            .collect();
+        let bodies: Vec<Option<UntrustedBlock>> = hashes
+            .par_iter()
+            .map(|hash| match peer.respond(SyncRequest::GetBlock(*hash)) {
+                SyncResponse::Block(body) => body.filter(|body| body.get_hash() == hash),
+                _ => None,
+            })
+            .collect();
+
+        for (height, body) in (first_height..).zip(bodies) {
+            let body = body.ok_or(SyncError::MissingBlock { height })?;
+            let block = Block::valid_new_block(chain, body)
+                .map_err(|error| SyncError::InvalidBlock { height, error })?;
+            chain.update(block);
+        }
+    }
+    Ok(chain.len() - start)
+}
+
+/// Handles a block announced by `sender`. A block whose parent we don't have waits in
+/// `orphans` while its first missing ancestor is asked to the sender; a connected block
+/// also connects the orphans waiting for it. Returns the number of connected blocks.
+pub fn receive_block(
+    chain: &mut BlockChain,
+    orphans: &mut OrphanPool,
+    block: UntrustedBlock,
+    sender: &impl BlockSource,
+) -> Result<usize, SyncError> {
+    let mut next = Some(block);
+    // bounded, the sender could walk us back along an endless fake branch
+    for _ in 0..=MAX_ORPHAN_BLOCKS {
+        let Some(block) = next.take() else {
+            break;
+        };
+        let hash = *block.get_hash();
+        if chain.get_block_by_hash(&hash).is_some() || orphans.contains(&hash) {
+            break;
+        }
+        let parent = *block.get_mining().get_previous_hash();
+        if parent == Hash::default() || chain.get_block_by_hash(&parent).is_some() {
+            return connect_with_orphans(chain, orphans, block);
+        }
+
+        block
+            .check_orphan(chain.get_difficulty())
+            .map_err(SyncError::InvalidOrphan)?;
+        let missing = orphans.missing_ancestor(&parent);
+        orphans.insert(block, chain.now());
+        next = match sender.respond(SyncRequest::GetBlock(missing)) {
+            SyncResponse::Block(Some(ancestor)) if ancestor.get_hash() == &missing => {
+                Some(ancestor)
+            }
+            _ => None,
+        };
+    }
+    Ok(0)
+}
+
+fn connect_with_orphans(
+    chain: &mut BlockChain,
+    orphans: &mut OrphanPool,
+    block: UntrustedBlock,
+) -> Result<usize, SyncError> {
+    let height = chain.len();
+    let block = Block::valid_new_block(chain, block)
+        .map_err(|error| SyncError::InvalidBlock { height, error })?;
+    let mut parents = vec![*block.get_hash()];
+    chain.update(block);
+
+    let mut connected_count = 1;
+    while let Some(parent) = parents.pop() {
+        for orphan in orphans.take_children(&parent) {
+            // of competing orphans only the first valid one extends the chain
+            if let Ok(block) = Block::valid_new_block(chain, orphan) {
+                parents.push(*block.get_hash());
+                chain.update(block);
+                connected_count += 1;
+            }
+        }
+    }
+    Ok(connected_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ed25519_dalek::Signer;
+
+    use super::*;
+    use crate::{
+        blocks::{
+            block::MAX_BLOCK_SIZE,
+            checkpoints::{Checkpoints, Network},
+        },
+        clock::{FixedClock, NetworkAdjustedClock},
+        test_utils::{TEST_TIME, chain_of, extend, mine_headers, regtest_chain, signing_key},
+        transactions::{
+            transaction::{
+                MAX_INPUTS_PER_TRANSACTION, MAX_OUTPUTS_PER_TRANSACTION, RawTransaction,
+                SignedTransaction, StatelessChecks, TransactionValidationError,
+                ValidatedTransaction,
+            },
+            transaction_input::Input,
+            transaction_output::Output,
+        },
+    };
+
+    // a peer serving the headers of its chain, and nothing else
+    struct HeaderPeer {
+        headers: Vec<MiningBlock>,
+        hashes: Vec<Hash>,
+    }
+    impl HeaderPeer {
+        fn new(headers: Vec<MiningBlock>) -> Self {
+            let hashes = headers.iter().map(MiningBlock::hash).collect();
+            Self { headers, hashes }
+        }
+    }
+    impl BlockSource for HeaderPeer {
+        fn respond(&self, request: SyncRequest) -> SyncResponse {
+            let SyncRequest::GetHeaders { locator, .. } = request else {
+                return SyncResponse::Block(None);
+            };
+            let start = locator
+                .iter()
+                .find_map(|hash| self.hashes.iter().position(|known| known == hash))
+                .map_or(0, |height| height + 1);
+            SyncResponse::Headers(
+                self.headers[start..]
+                    .iter()
+                    .take(MAX_HEADERS_PER_REQUEST)
+                    .cloned()
+                    .collect(),
+            )
+        }
+    }
+
+    #[test]
+    fn fresh_node_syncs_every_block_of_its_peer() {
+        let peer = chain_of(300, &signing_key(1));
+        let mut node = regtest_chain();
+
+        assert_eq!(sync_from(&mut node, &peer).unwrap(), 300);
+        assert_eq!(node.len(), 300);
+        assert_eq!(node.peak().get_hash(), peer.peak().get_hash());
+        assert_eq!(
+            node.get_utxos().iter().count(),
+            peer.get_utxos().iter().count()
+        );
+        // nothing left to download
+        assert_eq!(sync_from(&mut node, &peer).unwrap(), 0);
+    }
+
+    #[test]
+    fn sync_switches_to_a_heavier_fork_deeper_than_a_request() {
+        let main = mine_headers(None, 3_000, 0);
+        let mut node = regtest_chain();
+        assert_eq!(
+            sync_headers(&mut node, &HeaderPeer::new(main.clone())).unwrap(),
+            3_000
+        );
+
+        let mut fork = main[..500].to_vec();
+        fork.extend(mine_headers(Some(&main[499]), 2_600, 1));
+        let peer = HeaderPeer::new(fork.clone());
+        assert_eq!(sync_headers(&mut node, &peer).unwrap(), 2_600);
+        assert_eq!(node.get_headers().len(), 3_100);
+        assert_eq!(node.get_headers().tip_hash(), fork[3_099].hash());
+    }
+
+    #[test]
+    fn handshake_adjusts_the_clock_to_the_peers() {
+        let clock = Arc::new(NetworkAdjustedClock::new(FixedClock::new(TEST_TIME)));
+        let node = BlockChain::for_network(Network::Regtest, clock.clone());
+        let ahead = PeerId::random();
+        for (peer_id, offset) in [
+            (PeerId::random(), 60),
+            (PeerId::random(), 90),
+            (ahead, 3_000),
+        ] {
+            let peer = BlockChain::for_network(
+                Network::Regtest,
+                Arc::new(FixedClock::new(TEST_TIME + offset)),
+            );
+            handshake(node.get_clock().as_ref(), peer_id, &peer).unwrap();
+        }
+        assert_eq!(node.now(), TEST_TIME + 90);
+
+        // reconnecting does not give a peer more weight
+        let peer = BlockChain::for_network(
+            Network::Regtest,
+            Arc::new(FixedClock::new(TEST_TIME + 3_000)),
+        );
+        for _ in 0..3 {
+            handshake(node.get_clock().as_ref(), ahead, &peer).unwrap();
+        }
+        assert_eq!(node.now(), TEST_TIME + 90);
+
+        // a peer answering something else is not counted
+        let peer = HeaderPeer::new(vec![]);
+        assert!(matches!(
+            handshake(node.get_clock().as_ref(), PeerId::random(), &peer),
+            Err(SyncError::UnexpectedResponse)
+        ));
+        assert_eq!(clock.get_offset(), 90);
+    }
+
+    #[test]
+    fn a_chain_received_backwards_ends_at_its_tip() {
+        let peer = chain_of(40, &signing_key(1));
+        let mut node = regtest_chain();
+        let mut orphans = OrphanPool::new();
+        // a sender answering no block, the orphans wait for their parent to be announced
+        let silent = HeaderPeer::new(vec![]);
+        for height in (1..peer.len()).rev() {
+            let block = peer.get_block(height).unwrap().to_untrusted();
+            assert_eq!(
+                receive_block(&mut node, &mut orphans, block, &silent).unwrap(),
+                0
+            );
+            assert_eq!(orphans.len(), peer.len() - height);
+        }
+        let genesis = peer.get_block(0).unwrap().to_untrusted();
+        assert_eq!(
+            receive_block(&mut node, &mut orphans, genesis, &silent).unwrap(),
+            peer.len()
+        );
+        assert!(orphans.is_empty());
+        assert_eq!(node.len(), peer.len());
+        assert_eq!(node.peak().get_hash(), peer.peak().get_hash());
+    }
+
+    #[test]
+    fn an_announced_tip_pulls_its_missing_ancestors() {
+        let mut peer = chain_of(10, &signing_key(1));
+        let mut node = regtest_chain();
+        sync_from(&mut node, &peer).unwrap();
+        extend(&mut peer, 30, &signing_key(2));
+
+        let mut orphans = OrphanPool::new();
+        let tip = peer.peak().to_untrusted();
+        assert_eq!(
+            receive_block(&mut node, &mut orphans, tip, &peer).unwrap(),
+            30
+        );
+        assert!(orphans.is_empty());
+        assert_eq!(node.peak().get_hash(), peer.peak().get_hash());
+
+        // announced again, the tip is known
+        let tip = peer.peak().to_untrusted();
+        assert_eq!(
+            receive_block(&mut node, &mut orphans, tip, &peer).unwrap(),
+            0
+        );
+    }
+
+    // `block` as announced by a peer stuffing it with unsigned transactions of the largest size
+    fn oversized(block: &Block) -> UntrustedBlock {
+        let payee = signing_key(9).verifying_key();
+        let stuffing = RawTransaction::new(
+            vec![Input::new([7; 32], 0); MAX_INPUTS_PER_TRANSACTION],
+            vec![Output::new(payee, 1); MAX_OUTPUTS_PER_TRANSACTION],
+            payee,
+        );
+        let count = MAX_BLOCK_SIZE / stuffing.serialized_size() + 1;
+        let mut transactions: Vec<SignedTransaction> = block
+            .get_transactions()
+            .iter()
+            .map(|tx| tx.get_signed().clone())
+            .collect();
+        transactions.extend(vec![
+            SignedTransaction::with_signatures(stuffing, vec![]);
+            count
+        ]);
+        UntrustedBlock::new(block.get_mining().clone(), *block.get_hash(), transactions)
+    }
+
+    #[test]
+    fn oversized_blocks_are_refused_and_the_node_goes_on() {
+        let peer = chain_of(3, &signing_key(1));
+        let mut node = regtest_chain();
+        let mut orphans = OrphanPool::new();
+        let block_at = |height| peer.get_block(height).unwrap();
+        let announce = |node: &mut BlockChain, orphans: &mut OrphanPool, block| {
+            receive_block(node, orphans, block, &peer)
+        };
+
+        let too_large = |error: &BlockValidationError| {
+            matches!(
+                error,
+                BlockValidationError::BlockTooLarge { size, max: MAX_BLOCK_SIZE }
+                    if *size > MAX_BLOCK_SIZE
+            )
+        };
+        assert!(matches!(
+            announce(&mut node, &mut orphans, oversized(block_at(0))),
+            Err(SyncError::InvalidBlock { height: 0, error }) if too_large(&error)
+        ));
+        // an oversized orphan is refused before it is stored
+        assert!(matches!(
+            announce(&mut node, &mut orphans, oversized(block_at(2))),
+            Err(SyncError::InvalidOrphan(error)) if too_large(&error)
+        ));
+        assert!(orphans.is_empty());
+        assert!(node.is_empty());
+
+        // the same blocks as mined are still taken
+        assert_eq!(
+            announce(&mut node, &mut orphans, block_at(0).to_untrusted()).unwrap(),
+            1
+        );
+        assert_eq!(
+            announce(&mut node, &mut orphans, block_at(2).to_untrusted()).unwrap(),
+            2
+        );
+        assert_eq!(node.peak().get_hash(), peer.peak().get_hash());
+    }
+
+    // adds a block where the key 1 spends the coinbase of the first block with a signature
+    // of the key 7, which only a node skipping the signatures accepts
+    fn add_forged_block(chain: &mut BlockChain) {
+        let coinbase = *chain.get_block(0).unwrap().get_transactions()[0].get_hash();
+        let raw = RawTransaction::new(
+            vec![Input::new(coinbase, 0)],
+            vec![Output::new(
+                signing_key(2).verifying_key(),
+                chain.get_coin_base_amount(),
+            )],
+            signing_key(1).verifying_key(),
+        );
+        let signature = signing_key(7).sign(&raw.hash());
+        let forged = SignedTransaction::with_signatures(raw, vec![signature]);
+        let checks = StatelessChecks::with_signature(&forged, forged.check_hash());
+        let forged =
+            ValidatedTransaction::validate_with_checks(forged, chain.get_utxos(), checks).unwrap();
+        let transactions = vec![
+            ValidatedTransaction::get_coin_base_with_fees(chain, &signing_key(1), 0),
+            forged,
+        ];
+        let block = chain
+            .get_mining_block(&transactions)
+            .unwrap()
+            .mine()
+            .unwrap()
+            .with_transactions(transactions);
+        chain.update(block);
+    }
+
+    #[test]
+    fn signatures_are_skipped_up_to_the_assumed_valid_block_only() {
+        let mut peer = chain_of(2, &signing_key(1));
+        add_forged_block(&mut peer);
+        extend(&mut peer, 3, &signing_key(1));
+        let assume_valid =
+            |height: usize| Checkpoints::new([], Some(*peer.get_block(height).unwrap().get_hash()));
+        let forged = BlockValidationError::TransactionValidationError {
+            tx_index: 1,
+            error: TransactionValidationError::SignatureIncorrect,
+        };
+
+        // the forged block is an ancestor of the assumed valid block
+        let mut node = regtest_chain().with_checkpoints(assume_valid(4));
+        assert_eq!(sync_from(&mut node, &peer).unwrap(), peer.len());
+        assert_eq!(node.peak().get_hash(), peer.peak().get_hash());
+        assert!(node.get_headers().is_assumed_valid(4));
+        assert!(!node.get_headers().is_assumed_valid(5));
+
+        // above it, or without it, the signature is checked
+        for checkpoints in [assume_valid(1), Checkpoints::default()] {
+            let mut node = regtest_chain().with_checkpoints(checkpoints);
+            assert!(matches!(
+                sync_from(&mut node, &peer),
+                Err(SyncError::InvalidBlock { height: 2, error }) if error == forged
+            ));
+            assert_eq!(node.len(), 2);
+        }
+
+        // so it is when the headers leading to the assumed valid block are not known yet
+        let mut node = regtest_chain().with_checkpoints(assume_valid(4));
+        let mut orphans = OrphanPool::new();
+        for height in 0..2 {
+            let block = peer.get_block(height).unwrap().to_untrusted();
+            receive_block(&mut node, &mut orphans, block, &peer).unwrap();
+        }
+        assert!(!node.get_headers().is_assumed_valid(2));
+        let block = peer.get_block(2).unwrap().to_untrusted();
+        assert!(matches!(
+            receive_block(&mut node, &mut orphans, block, &peer),
+            Err(SyncError::InvalidBlock { height: 2, error }) if error == forged
+        ));
+    }
+}
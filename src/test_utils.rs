@@ -0,0 +1,98 @@
+//! Regtest chains for the tests: their blocks are mined at once, against a fixed clock.
+use std::sync::Arc;
+
+use ed25519_dalek::SigningKey;
+
+use crate::{
+    block_chain::{BlockChain, REGTEST_DIFFICULTY},
+    blocks::{block::Block, checkpoints::Network, mining_block::MiningBlock},
+    clock::FixedClock,
+    shared::Hash,
+    transactions::transaction::{SignedTransaction, ValidatedTransaction},
+};
+
+pub const TEST_TIME: u64 = 1_700_000_000;
+
+pub fn regtest_chain() -> BlockChain {
+    BlockChain::for_network(Network::Regtest, Arc::new(FixedClock::new(TEST_TIME)))
+}
+
+pub fn signing_key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32])
+}
+
+/// Next block of `chain`: the coinbase paying `miner`, then `transactions`.
+pub fn mine_block(
+    chain: &BlockChain,
+    miner: &SigningKey,
+    transactions: Vec<SignedTransaction>,
+) -> Block {
+    let mut validated = vec![];
+    let mut fees = 0;
+    for transaction in transactions {
+        let transaction = ValidatedTransaction::validate(transaction, chain.get_utxos())
+            .expect("the test transactions are valid");
+        fees += transaction.get_fee();
+        validated.push(transaction);
+    }
+    validated.insert(
+        0,
+        ValidatedTransaction::get_coin_base_with_fees(chain, miner, fees),
+    );
+    chain
+        .get_mining_block(&validated)
+        .unwrap()
+        .mine()
+        .unwrap()
+        .with_transactions(validated)
+}
+
+/// Mines the next block of `chain` and connects it after validating it as a peer would.
+pub fn add_block(
+    chain: &mut BlockChain,
+    miner: &SigningKey,
+    transactions: Vec<SignedTransaction>,
+) -> Hash {
+    let block = mine_block(chain, miner, transactions);
+    let block = Block::valid_new_block(chain, block.to_untrusted()).expect("a valid block");
+    let hash = *block.get_hash();
+    chain.update(block);
+    hash
+}
+
+/// Regtest chain of `length` empty blocks paying `miner`.
+pub fn chain_of(length: usize, miner: &SigningKey) -> BlockChain {
+    let mut chain = regtest_chain();
+    extend(&mut chain, length, miner);
+    chain
+}
+
+pub fn extend(chain: &mut BlockChain, count: usize, miner: &SigningKey) {
+    for _ in 0..count {
+        add_block(chain, miner, vec![]);
+    }
+}
+
+/// `count` headers without transactions mined on top of `previous`, `salt` tells the
+/// branches apart.
+pub fn mine_headers(previous: Option<&MiningBlock>, count: usize, salt: u8) -> Vec<MiningBlock> {
+    let mut headers: Vec<MiningBlock> = vec![];
+    for _ in 0..count {
+        let previous = headers.last().or(previous);
+        let (previous_hash, timestamp) = previous.map_or((Hash::default(), TEST_TIME), |p| {
+            (p.hash(), p.get_timestamp() + 1)
+        });
+        let mut header =
+            MiningBlock::new_header(previous_hash, REGTEST_DIFFICULTY, [salt; 32], timestamp);
+        headers.push(header.mine().unwrap().get_mining().clone());
+    }
+    headers
+}
+
+/// Bytes of the published test vectors.
+pub fn from_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("a hex string"))
+        .collect()
+}
@@ -0,0 +1,467 @@
+//! Text form of what an output pays to: `<prefix of the network>1<data><checksum>`, the
+//! data being the version then the payload in base 32, with a bech32m checksum (BIP 350)
+//! over the prefix and the data, so that any typo of up to 4 characters is detected.
+use std::{fmt, str::FromStr};
+
+use ed25519_dalek::VerifyingKey;
+use sha2::{Digest, Sha256};
+
+use crate::{blocks::checkpoints::Network, shared::Hash};
+
+pub const PUBKEY_VERSION: u8 = 0;
+pub const PUBKEY_HASH_VERSION: u8 = 1;
+
+const SEPARATOR: char = '1';
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const CHECKSUM_LENGTH: usize = 6;
+const BECH32M_CONSTANT: u32 = 0x2bc8_30a3;
+// longest string the checksum guarantees to check
+const MAX_LENGTH: usize = 90;
+
+/// Hash of a pubkey, what a pay-to-pubkey-hash address commits to.
+pub fn pubkey_hash(pubkey: &VerifyingKey) -> Hash {
+    Sha256::digest(pubkey.as_bytes()).into()
+}
+
+fn prefix(network: Network) -> &'static str {
+    match network {
+        Network::Main => "cr",
+        Network::Test => "tcr",
+        Network::Regtest => "rcr",
+    }
+}
+
+/// What an address, and an output, pays to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AddressPayload {
+    PubKey(VerifyingKey),
+    PubKeyHash(Hash),
+}
+impl AddressPayload {
+    /// The pubkey or the pubkey hash.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        match self {
+            Self::PubKey(pubkey) => pubkey.as_bytes(),
+            Self::PubKeyHash(hash) => hash,
+        }
+    }
+    /// Whether `pubkey` is the key paid.
+    pub fn is_owned_by(&self, pubkey: &VerifyingKey) -> bool {
+        match self {
+            Self::PubKey(payee) => payee == pubkey,
+            Self::PubKeyHash(hash) => hash == &pubkey_hash(pubkey),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Address {
+    network: Network,
+    payload: AddressPayload,
+}
+impl Address {
+    pub fn new(network: Network, payload: AddressPayload) -> Self {
+        Self { network, payload }
+    }
+    pub fn from_pubkey(network: Network, pubkey: &VerifyingKey) -> Self {
+        Self::new(network, AddressPayload::PubKey(*pubkey))
+    }
+    pub fn from_pubkey_hash(network: Network, pubkey: &VerifyingKey) -> Self {
+        Self::new(network, AddressPayload::PubKeyHash(pubkey_hash(pubkey)))
+    }
+    /// Parses `address`, which must be of `network`.
+    pub fn parse(address: &str, network: Network) -> Result<Self, AddressError> {
+        let parsed: Self = address.parse()?;
+        if parsed.network != network {
+            return Err(AddressError::WrongNetwork {
+                expected: network,
+                actual: parsed.network,
+            });
+        }
+        Ok(parsed)
+    }
+    pub fn get_network(&self) -> Network {
+        self.network
+    }
+    pub fn get_payload(&self) -> &AddressPayload {
+        &self.payload
+    }
+    pub fn get_version(&self) -> u8 {
+        match self.payload {
+            AddressPayload::PubKey(_) => PUBKEY_VERSION,
+            AddressPayload::PubKeyHash(_) => PUBKEY_HASH_VERSION,
+        }
+    }
+    /// Whether `pubkey` is the key this address pays to.
+    pub fn is_owned_by(&self, pubkey: &VerifyingKey) -> bool {
+        self.payload.is_owned_by(pubkey)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut data = vec![self.get_version()];
+        data.extend(convert_bits(self.payload.as_bytes(), 8, 5, true).expect("padding is allowed"));
+        let prefix = prefix(self.network);
+        let checksum = checksum(prefix, &data);
+        f.write_str(prefix)?;
+        write!(f, "{SEPARATOR}")?;
+        for value in data.iter().chain(&checksum) {
+            write!(f, "{}", CHARSET[*value as usize] as char)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses an address of any network.
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        if address.len() > MAX_LENGTH {
+            return Err(AddressError::TooLong(address.len()));
+        }
+        if address.bytes().any(|byte| byte.is_ascii_lowercase())
+            && address.bytes().any(|byte| byte.is_ascii_uppercase())
+        {
+            return Err(AddressError::MixedCase);
+        }
+        let address = address.to_ascii_lowercase();
+        let (prefix_part, data_part) = address
+            .rsplit_once(SEPARATOR)
+            .ok_or(AddressError::MissingSeparator)?;
+        let mut data = Vec::with_capacity(data_part.len());
+        for (position, character) in data_part.bytes().enumerate() {
+            let value = CHARSET
+                .iter()
+                .position(|charset_character| *charset_character == character)
+                .ok_or(AddressError::InvalidCharacter {
+                    position: prefix_part.len() + 1 + position,
+                })?;
+            data.push(value as u8);
+        }
+        if data.len() <= CHECKSUM_LENGTH || !verify_checksum(prefix_part, &data) {
+            return Err(AddressError::InvalidChecksum);
+        }
+        let network = [Network::Main, Network::Test, Network::Regtest]
+            .into_iter()
+            .find(|network| prefix(*network) == prefix_part)
+            .ok_or_else(|| AddressError::UnknownPrefix(prefix_part.to_string()))?;
+
+        let (version, payload) = data[..data.len() - CHECKSUM_LENGTH]
+            .split_first()
+            .ok_or(AddressError::InvalidPayload)?;
+        let payload: Hash = convert_bits(payload, 5, 8, false)
+            .and_then(|payload| payload.try_into().ok())
+            .ok_or(AddressError::InvalidPayload)?;
+        let payload = match *version {
+            PUBKEY_VERSION => AddressPayload::PubKey(
+                VerifyingKey::from_bytes(&payload).map_err(|_| AddressError::InvalidPayload)?,
+            ),
+            PUBKEY_HASH_VERSION => AddressPayload::PubKeyHash(payload),
+            version => return Err(AddressError::UnknownVersion(version)),
+        };
+        Ok(Self { network, payload })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    TooLong(usize),
+    MixedCase,
+    MissingSeparator,
+    InvalidCharacter {
+        position: usize,
+    },
+    /// A typo, or not an address.
+    InvalidChecksum,
+    UnknownPrefix(String),
+    UnknownVersion(u8),
+    InvalidPayload,
+    WrongNetwork {
+        expected: Network,
+        actual: Network,
+    },
+}
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLong(length) => {
+                write!(f, "address of {length} characters, at most {MAX_LENGTH}")
+            }
+            Self::MixedCase => write!(f, "address mixes upper and lower case"),
+            Self::MissingSeparator => write!(f, "address has no '{SEPARATOR}' separator"),
+            Self::InvalidCharacter { position } => {
+                write!(f, "invalid character at position {position}")
+            }
+            Self::InvalidChecksum => write!(f, "invalid checksum, the address has a typo"),
+            Self::UnknownPrefix(prefix) => write!(f, "unknown address prefix {prefix}"),
+            Self::UnknownVersion(version) => write!(f, "unknown address version {version}"),
+            Self::InvalidPayload => write!(f, "invalid address payload"),
+            Self::WrongNetwork { expected, actual } => {
+                write!(f, "address of the {actual:?} network, not {expected:?}")
+            }
+        }
+    }
+}
+impl std::error::Error for AddressError {}
+
+// BCH checksum of the bech32 specification
+fn polymod(values: impl IntoIterator<Item = u8>) -> u32 {
+    const GENERATOR: [u32; 5] = [
+        0x3b6a_57b2,
+        0x2650_8e6d,
+        0x1ea1_19fa,
+        0x3d42_33dd,
+        0x2a14_62b3,
+    ];
+    let mut checksum = 1u32;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ff_ffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+// the prefix characters split in their high and low bits
+fn expand_prefix(prefix: &str) -> impl Iterator<Item = u8> + '_ {
+    let high = prefix.bytes().map(|byte| byte >> 5);
+    let low = prefix.bytes().map(|byte| byte & 31);
+    high.chain([0]).chain(low)
+}
+
+fn checksum(prefix: &str, data: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+    let values = expand_prefix(prefix)
+        .chain(data.iter().copied())
+        .chain([0; CHECKSUM_LENGTH]);
+    let polymod = polymod(values) ^ BECH32M_CONSTANT;
+    std::array::from_fn(|i| ((polymod >> (5 * (CHECKSUM_LENGTH - 1 - i))) & 31) as u8)
+}
+
+fn verify_checksum(prefix: &str, data: &[u8]) -> bool {
+    polymod(expand_prefix(prefix).chain(data.iter().copied())) == BECH32M_CONSTANT
+}
+
+// regroups `from`-bit values in `to`-bit values, None for non zero padding when not `pad`
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut accumulator = 0u32;
+    let mut bit_count = 0;
+    let mut converted = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+    let max_value = (1 << to) - 1;
+    // keeps the bits not converted yet
+    let max_accumulator = (1 << (from + to - 1)) - 1;
+    for value in data {
+        accumulator = ((accumulator << from) | *value as u32) & max_accumulator;
+        bit_count += from;
+        while bit_count >= to {
+            bit_count -= to;
+            converted.push(((accumulator >> bit_count) & max_value) as u8);
+        }
+    }
+    if pad {
+        if bit_count > 0 {
+            converted.push(((accumulator << (to - bit_count)) & max_value) as u8);
+        }
+    } else if bit_count >= from || (accumulator << (to - bit_count)) & max_value != 0 {
+        return None;
+    }
+    Some(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+    use crate::test_utils::signing_key;
+
+    const NETWORKS: [Network; 3] = [Network::Main, Network::Test, Network::Regtest];
+
+    fn addresses() -> Vec<Address> {
+        let pubkey = signing_key(1).verifying_key();
+        NETWORKS
+            .into_iter()
+            .flat_map(|network| {
+                [
+                    Address::from_pubkey(network, &pubkey),
+                    Address::from_pubkey_hash(network, &pubkey),
+                ]
+            })
+            .collect()
+    }
+
+    // the string of `prefix` and the base 32 `data` with their checksum
+    fn encode(prefix: &str, data: &[u8]) -> String {
+        let checksum = checksum(prefix, data);
+        let characters = data
+            .iter()
+            .chain(&checksum)
+            .map(|value| CHARSET[*value as usize] as char);
+        format!("{prefix}{SEPARATOR}{}", characters.collect::<String>())
+    }
+
+    #[test]
+    fn bip350_valid_strings() {
+        for valid in [
+            "a1lqfn3a",
+            "abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx",
+            "split1checkupstagehandshakeupstreamerranterredcaperredlc445v",
+            "?1v759aa",
+        ] {
+            let (prefix, data) = valid.rsplit_once(SEPARATOR).unwrap();
+            let data: Vec<u8> = data
+                .bytes()
+                .map(|character| CHARSET.iter().position(|c| *c == character).unwrap() as u8)
+                .collect();
+            assert!(verify_checksum(prefix, &data), "{valid}");
+            assert_eq!(encode(prefix, &data[..data.len() - CHECKSUM_LENGTH]), valid);
+        }
+    }
+
+    #[test]
+    fn every_typo_of_a_character_is_detected() {
+        // every character of the charset and of the prefixes, the separator, an invalid one
+        let replacements: Vec<char> = CHARSET
+            .iter()
+            .map(|c| *c as char)
+            .chain("abtio".chars())
+            .collect();
+        for address in addresses() {
+            let text = address.to_string();
+            assert_eq!(text.parse(), Ok(address));
+            assert_eq!(text.to_ascii_uppercase().parse(), Ok(address));
+            let characters: Vec<char> = text.chars().collect();
+            let parse =
+                |characters: &[char]| characters.iter().collect::<String>().parse::<Address>();
+
+            for position in 0..characters.len() {
+                for replacement in &replacements {
+                    if *replacement == characters[position] {
+                        continue;
+                    }
+                    let mut changed = characters.clone();
+                    changed[position] = *replacement;
+                    assert!(parse(&changed).is_err(), "{text} at {position}");
+                }
+                let mut changed = characters.clone();
+                changed[position] = changed[position].to_ascii_uppercase();
+                if changed != characters {
+                    assert_eq!(parse(&changed), Err(AddressError::MixedCase));
+                }
+
+                let mut deleted = characters.clone();
+                deleted.remove(position);
+                assert!(parse(&deleted).is_err(), "{text} without {position}");
+                for inserted_character in ['q', 'l', '1'] {
+                    let mut inserted = characters.clone();
+                    inserted.insert(position, inserted_character);
+                    assert!(parse(&inserted).is_err(), "{text} with {position}");
+                }
+                if position + 1 < characters.len()
+                    && characters[position] != characters[position + 1]
+                {
+                    let mut swapped = characters.clone();
+                    swapped.swap(position, position + 1);
+                    assert!(parse(&swapped).is_err(), "{text} swapped at {position}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn up_to_four_typos_are_detected() {
+        let mut rng = StdRng::seed_from_u64(45);
+        for address in addresses() {
+            let text = address.to_string();
+            // only the data part, for the typos to stay in the charset
+            let data_start = text.rfind(SEPARATOR).unwrap() + 1;
+            for _ in 0..500 {
+                let mut changed = text.clone().into_bytes();
+                for _ in 0..rng.gen_range(1..=4) {
+                    let position = rng.gen_range(data_start..changed.len());
+                    let replacement = CHARSET[rng.gen_range(0..CHARSET.len())];
+                    changed[position] = replacement;
+                }
+                let changed = String::from_utf8(changed).unwrap();
+                if changed != text {
+                    assert_eq!(
+                        changed.parse::<Address>(),
+                        Err(AddressError::InvalidChecksum)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn an_address_of_another_network_is_refused() {
+        for address in addresses() {
+            for network in NETWORKS {
+                let parsed = Address::parse(&address.to_string(), network);
+                if network == address.get_network() {
+                    assert_eq!(parsed, Ok(address));
+                } else {
+                    assert_eq!(
+                        parsed,
+                        Err(AddressError::WrongNetwork {
+                            expected: network,
+                            actual: address.get_network(),
+                        })
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn each_malformed_address_has_its_own_error() {
+        let address = addresses()[0].to_string();
+        let payload = convert_bits(&[7; 32], 8, 5, true).unwrap();
+        let with_version = |version: u8| [&[version][..], &payload].concat();
+
+        assert_eq!(
+            format!("{address}{}", "q".repeat(40)).parse::<Address>(),
+            Err(AddressError::TooLong(address.len() + 40))
+        );
+        assert_eq!(
+            address.replacen("1", "", 1).parse::<Address>(),
+            Err(AddressError::MissingSeparator)
+        );
+        assert_eq!(
+            format!("{}b", &address[..address.len() - 1]).parse::<Address>(),
+            Err(AddressError::InvalidCharacter {
+                position: address.len() - 1
+            })
+        );
+        assert_eq!("cr1".parse::<Address>(), Err(AddressError::InvalidChecksum));
+        assert_eq!(
+            encode("xcr", &with_version(PUBKEY_HASH_VERSION)).parse::<Address>(),
+            Err(AddressError::UnknownPrefix("xcr".to_string()))
+        );
+        assert_eq!(
+            encode("cr", &with_version(2)).parse::<Address>(),
+            Err(AddressError::UnknownVersion(2))
+        );
+        assert_eq!(
+            encode("cr", &with_version(PUBKEY_HASH_VERSION)[..20]).parse::<Address>(),
+            Err(AddressError::InvalidPayload)
+        );
+        assert_eq!(
+            encode("cr", &[PUBKEY_HASH_VERSION]).parse::<Address>(),
+            Err(AddressError::InvalidPayload)
+        );
+        let hash_address = encode("cr", &with_version(PUBKEY_HASH_VERSION));
+        assert_eq!(
+            hash_address.parse(),
+            Ok(Address::new(
+                Network::Main,
+                AddressPayload::PubKeyHash([7; 32])
+            ))
+        );
+    }
+}
@@ -1,3 +1,11 @@
+//! Merkel tree of the transactions of a block.
+//!
+//! The tree follows RFC 6962: for n transactions, with k the biggest power of two smaller than n,
+//! the left subtree holds the first k transactions and the right subtree the n - k others.
+//! Odd nodes are never duplicated, so two different transaction lists always give two
+//! different roots (no CVE-2012-2459 style malleability).
+//! Leaves are `sha256(0x00 | tx_id)` and inner nodes `sha256(0x01 | left | right)`, so an inner
+//! node can never be presented as a leaf.
 use std::ops::Index;
 use std::ops::IndexMut;
 
@@ -6,25 +14,212 @@ use sha2::Sha256;
 
 use crate::{shared::Hash, transactions::transaction::ValidatedTransaction};
 
-struct _MerkelTree {
-    transactions: Vec<ValidatedTransaction>,
-}
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
 //cela limit le nombre maximum de transaction en un coup de 1.048.575 soit 2^BUFFER_SIZE -1
 const BUFFER_SIZE: usize = 20;
-const MAX_TRANSACTION_PER_BLOCK: usize = (1 << BUFFER_SIZE) - 1;
+pub const MAX_TRANSACTION_PER_BLOCK: usize = (1 << BUFFER_SIZE) - 1;
 
-pub fn get_merkel_hash(transactions: &[ValidatedTransaction]) -> Hash {
-    assert!(transactions.len() != 0);
-    assert!(transactions.len() < MAX_TRANSACTION_PER_BLOCK);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MerkelError {
+    NoTransaction,
+    TooManyTransactions,
+}
+impl std::fmt::Display for MerkelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoTransaction => write!(f, "no transaction"),
+            Self::TooManyTransactions => {
+                write!(f, "more than {MAX_TRANSACTION_PER_BLOCK} transactions")
+            }
+        }
+    }
+}
+impl std::error::Error for MerkelError {}
 
-    let mut merkel_hash_builder = MerkelHashBuilder::new();
-    for hash in transactions
+fn check_leaf_count(leaf_count: usize) -> Result<(), MerkelError> {
+    if leaf_count == 0 {
+        return Err(MerkelError::NoTransaction);
+    }
+    if leaf_count > MAX_TRANSACTION_PER_BLOCK {
+        return Err(MerkelError::TooManyTransactions);
+    }
+    Ok(())
+}
+
+pub fn get_merkel_hash(transactions: &[ValidatedTransaction]) -> Result<Hash, MerkelError> {
+    get_merkel_hash_from_ids(
+        transactions
+            .iter()
+            .map(|transaction| transaction.get_hash()),
+    )
+}
+
+/// Root of the tree of the witness hashes, so the block also commits to the signatures
+/// while the tx ids stay independent of them.
+pub fn get_witness_hash(transactions: &[ValidatedTransaction]) -> Result<Hash, MerkelError> {
+    let witness_hashes: Vec<Hash> = transactions
         .iter()
-        .map(|transaction| transaction.get_hash())
-    {
-        merkel_hash_builder.insert_hash(hash);
+        .map(|transaction| transaction.get_witness_hash())
+        .collect();
+    get_merkel_hash_from_ids(witness_hashes.iter())
+}
+
+/// Streaming computation of the merkel root, without keeping the whole tree in memory.
+pub fn get_merkel_hash_from_ids<'a>(
+    tx_ids: impl ExactSizeIterator<Item = &'a Hash>,
+) -> Result<Hash, MerkelError> {
+    check_leaf_count(tx_ids.len())?;
+
+    let mut merkel_hash_builder = MerkelHashBuilder::new();
+    for tx_id in tx_ids {
+        merkel_hash_builder.insert_hash(&leaf_hash(tx_id));
+    }
+    Ok(merkel_hash_builder.resume_hashs())
+}
+
+/// Reference (recursive) implementation of the tree, used to build inclusion proofs.
+pub struct MerkelTree {
+    leaves: Vec<Hash>,
+}
+impl MerkelTree {
+    pub fn new(transactions: &[ValidatedTransaction]) -> Result<Self, MerkelError> {
+        Self::from_ids(transactions.iter().map(|tx| *tx.get_hash()).collect())
+    }
+    pub fn from_ids(tx_ids: Vec<Hash>) -> Result<Self, MerkelError> {
+        check_leaf_count(tx_ids.len())?;
+        Ok(Self {
+            leaves: tx_ids.iter().map(leaf_hash).collect(),
+        })
+    }
+    pub fn root(&self) -> Hash {
+        subtree_root(&self.leaves)
+    }
+    pub fn prove(&self, tx_index: usize) -> Option<MerkelProof> {
+        if tx_index >= self.leaves.len() {
+            return None;
+        }
+        Some(MerkelProof {
+            path: subtree_path(&self.leaves, tx_index),
+        })
+    }
+}
+
+// biggest power of two strictly smaller than leaf_count (leaf_count > 1)
+fn split_point(leaf_count: usize) -> usize {
+    1 << (usize::BITS - 1 - (leaf_count - 1).leading_zeros())
+}
+fn subtree_root(leaves: &[Hash]) -> Hash {
+    if leaves.len() == 1 {
+        return leaves[0];
     }
-    merkel_hash_builder.resume_hashs()
+    let (left, right) = leaves.split_at(split_point(leaves.len()));
+    node_hash(&subtree_root(left), &subtree_root(right))
+}
+fn subtree_path(leaves: &[Hash], index: usize) -> Vec<MerkelProofStep> {
+    if leaves.len() == 1 {
+        return vec![];
+    }
+    let split = split_point(leaves.len());
+    let (left, right) = leaves.split_at(split);
+    if index < split {
+        let mut path = subtree_path(left, index);
+        path.push(MerkelProofStep::new(
+            subtree_root(right),
+            SiblingSide::Right,
+        ));
+        path
+    } else {
+        let mut path = subtree_path(right, index - split);
+        path.push(MerkelProofStep::new(subtree_root(left), SiblingSide::Left));
+        path
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SiblingSide {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkelProofStep {
+    sibling: Hash,
+    side: SiblingSide,
+}
+impl MerkelProofStep {
+    fn new(sibling: Hash, side: SiblingSide) -> Self {
+        Self { sibling, side }
+    }
+}
+
+// the side of each step is one bit of a u64
+const MAX_PROOF_LEN: usize = 64;
+
+/// Proof that a transaction is part of the merkel tree of a block.
+///
+/// It does not tell the position of the transaction: the header does not commit to the
+/// transaction count, and without it the sides of the path fit several positions.
+///
+/// Serialized as: path length (u8) | sides bitmask (u64 BE, bit i set when the sibling of
+/// step i is on the left) | siblings (32 bytes each, from the leaf up).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkelProof {
+    path: Vec<MerkelProofStep>,
+}
+impl MerkelProof {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut sides: u64 = 0;
+        for (i, step) in self.path.iter().enumerate() {
+            if step.side == SiblingSide::Left {
+                sides |= 1 << i;
+            }
+        }
+        let mut bytes = Vec::with_capacity(1 + 8 + 32 * self.path.len());
+        bytes.push(self.path.len() as u8);
+        bytes.extend_from_slice(&sides.to_be_bytes());
+        for step in &self.path {
+            bytes.extend_from_slice(&step.sibling);
+        }
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (path_len, rest) = bytes.split_first()?;
+        let (sides, rest) = rest.split_first_chunk::<8>()?;
+        let path_len = *path_len as usize;
+        if path_len > MAX_PROOF_LEN || rest.len() != path_len * 32 {
+            return None;
+        }
+        let sides = u64::from_be_bytes(*sides);
+        if path_len < MAX_PROOF_LEN && sides >> path_len != 0 {
+            return None;
+        }
+        let path = rest
+            .chunks_exact(32)
+            .enumerate()
+            .map(|(i, sibling)| {
+                let side = if sides & (1 << i) != 0 {
+                    SiblingSide::Left
+                } else {
+                    SiblingSide::Right
+                };
+                MerkelProofStep::new(sibling.try_into().unwrap(), side)
+            })
+            .collect();
+        Some(Self { path })
+    }
+}
+
+pub fn verify_merkel_proof(proof: &MerkelProof, tx_id: &Hash, root: &Hash) -> bool {
+    let computed_root = proof
+        .path
+        .iter()
+        .fold(leaf_hash(tx_id), |hash, step| match step.side {
+            SiblingSide::Left => node_hash(&step.sibling, &hash),
+            SiblingSide::Right => node_hash(&hash, &step.sibling),
+        });
+    &computed_root == root
 }
 
 #[repr(transparent)]
@@ -51,16 +246,16 @@ impl MerkelHashBuilder {
     }
     fn insert_hash(&mut self, hash: &Hash) {
         if let Some(hash2) = &self[0] {
-            self[0] = Some(combine_hash(hash2, hash));
+            self[0] = Some(node_hash(hash2, hash));
         } else {
-            self[0] = Some(hash.clone());
+            self[0] = Some(*hash);
             return;
         }
 
         for i in 1..(BUFFER_SIZE) {
             if let Some(hash2) = &self[i] {
                 //can safely unwrap as bubble up because the case contained Some()
-                self[i] = Some(combine_hash(hash2, &self[i - 1].unwrap()));
+                self[i] = Some(node_hash(hash2, &self[i - 1].unwrap()));
                 self[i - 1] = None;
             } else {
                 self[i] = self[i - 1];
@@ -73,18 +268,17 @@ impl MerkelHashBuilder {
         let first_index = self.get_first_buffer_index();
         let final_index = self.get_last_buffer_index();
         assert!(
-            !(first_index > final_index),
+            first_index <= final_index,
             "Hash builder empty should not be possible"
         );
 
+        // the biggest subtree holds the first transactions, so it is on the left:
+        // root = node(T_final, node(..., node(T_second, T_first)))
         let mut hash_accumulator = self[first_index].unwrap();
-        for i in (first_index + 1)..final_index {
-            let to_add = if let Some(hash) = &self[i] {
-                hash
-            } else {
-                &hash_accumulator
-            };
-            hash_accumulator = combine_hash(&hash_accumulator, to_add);
+        for i in (first_index + 1)..=final_index {
+            if let Some(hash) = &self[i] {
+                hash_accumulator = node_hash(hash, &hash_accumulator);
+            }
         }
         hash_accumulator
     }
@@ -109,9 +303,138 @@ impl MerkelHashBuilder {
     }
 }
 
-fn combine_hash(hash1: &Hash, hash2: &Hash) -> Hash {
+fn leaf_hash(tx_id: &Hash) -> Hash {
     let mut hasher = Sha256::new();
-    hasher.update(hash1);
-    hasher.update(hash2);
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(tx_id);
     hasher.finalize().into()
 }
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx_ids(count: usize) -> Vec<Hash> {
+        (0..count)
+            .map(|i| {
+                let mut tx_id = [0u8; 32];
+                tx_id[..8].copy_from_slice(&(i as u64).to_be_bytes());
+                tx_id
+            })
+            .collect()
+    }
+
+    #[test]
+    fn streaming_root_matches_the_tree() {
+        for count in (1..=300).chain([1023, 1024, 1025, 4097]) {
+            let tx_ids = tx_ids(count);
+            let streamed = get_merkel_hash_from_ids(tx_ids.iter()).unwrap();
+            assert_eq!(
+                streamed,
+                MerkelTree::from_ids(tx_ids).unwrap().root(),
+                "{count}"
+            );
+        }
+        // RFC 6962 shape on 3 leaves: node(node(a, b), c)
+        let tx_ids = tx_ids(3);
+        let [a, b, c] = [0, 1, 2].map(|i| leaf_hash(&tx_ids[i]));
+        assert_eq!(
+            get_merkel_hash_from_ids(tx_ids.iter()).unwrap(),
+            node_hash(&node_hash(&a, &b), &c)
+        );
+        // a single transaction is its own leaf
+        assert_eq!(get_merkel_hash_from_ids(tx_ids[..1].iter()).unwrap(), a);
+    }
+
+    #[test]
+    fn transaction_count_is_bounded() {
+        assert_eq!(
+            get_merkel_hash_from_ids([].iter()),
+            Err(MerkelError::NoTransaction)
+        );
+        assert_eq!(get_merkel_hash(&[]), Err(MerkelError::NoTransaction));
+        assert!(matches!(
+            MerkelTree::from_ids(vec![]),
+            Err(MerkelError::NoTransaction)
+        ));
+
+        let tx_id = Hash::default();
+        assert_eq!(
+            get_merkel_hash_from_ids(std::iter::repeat_n(&tx_id, MAX_TRANSACTION_PER_BLOCK + 1)),
+            Err(MerkelError::TooManyTransactions)
+        );
+        assert!(matches!(
+            MerkelTree::from_ids(vec![tx_id; MAX_TRANSACTION_PER_BLOCK + 1]),
+            Err(MerkelError::TooManyTransactions)
+        ));
+    }
+
+    #[test]
+    fn every_transaction_is_proven_in_trees_of_any_size() {
+        // every shape up to 2^7 leaves, then around a power of two
+        for count in (1..=130).chain([255, 256, 257, 300]) {
+            let tx_ids = tx_ids(count);
+            let tree = MerkelTree::from_ids(tx_ids.clone()).unwrap();
+            let root = tree.root();
+            for (tx_index, tx_id) in tx_ids.iter().enumerate() {
+                let proof = tree.prove(tx_index).unwrap();
+                let decoded = MerkelProof::from_bytes(&proof.to_bytes()).unwrap();
+                assert_eq!(decoded, proof);
+                assert!(
+                    verify_merkel_proof(&decoded, tx_id, &root),
+                    "{tx_index} of {count}"
+                );
+                // another transaction of the block, or a leaf of the tree as a tx id
+                let other_id = &tx_ids[(tx_index + 1) % count];
+                assert_eq!(
+                    verify_merkel_proof(&proof, other_id, &root),
+                    count == 1 && other_id == tx_id
+                );
+                assert!(!verify_merkel_proof(&proof, &leaf_hash(tx_id), &root));
+            }
+            assert!(tree.prove(count).is_none());
+        }
+    }
+
+    #[test]
+    fn tampered_proofs_are_refused() {
+        let tx_ids = tx_ids(13);
+        let tree = MerkelTree::from_ids(tx_ids.clone()).unwrap();
+        let root = tree.root();
+        let bytes = tree.prove(6).unwrap().to_bytes();
+        // each side bit and each byte of each sibling
+        for bit in 0..bytes[0] {
+            let mut tampered = bytes.clone();
+            tampered[8 - bit as usize / 8] ^= 1 << (bit % 8);
+            let proof = MerkelProof::from_bytes(&tampered).unwrap();
+            assert!(
+                !verify_merkel_proof(&proof, &tx_ids[6], &root),
+                "side {bit}"
+            );
+        }
+        for index in 9..bytes.len() {
+            let mut tampered = bytes.clone();
+            tampered[index] ^= 1;
+            let proof = MerkelProof::from_bytes(&tampered).unwrap();
+            assert!(
+                !verify_merkel_proof(&proof, &tx_ids[6], &root),
+                "byte {index}"
+            );
+        }
+        // a side past the path, a missing sibling, a trailing byte
+        let mut extra_side = bytes.clone();
+        extra_side[1] ^= 0x80;
+        assert!(MerkelProof::from_bytes(&extra_side).is_none());
+        assert!(MerkelProof::from_bytes(&bytes[..bytes.len() - 32]).is_none());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(MerkelProof::from_bytes(&trailing).is_none());
+    }
+}
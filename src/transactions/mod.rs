@@ -1,4 +1,6 @@
+pub mod address;
 pub mod merkel;
+pub mod partially_signed;
 pub mod transaction;
 pub mod transaction_input;
 pub mod transaction_output;
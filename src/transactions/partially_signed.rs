@@ -0,0 +1,609 @@
+//! A transaction on its way between the machines that sign it: the raw transaction, the
+//! outputs it spends when known (so an offline signer can check the amounts and the fee),
+//! the signatures of its signers collected so far and free metadata. Each signer signs
+//! its own copy, the copies are combined and the transaction is finalized once every
+//! signer signed.
+//!
+//! Binary layout: magic "CPT" | format version (u8) | raw transaction length (u32 BE) |
+//! raw transaction | records, each: tag (u8) | length (u32 BE) | data. The text form is
+//! the base64 of the binary one.
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey, ed25519::signature::Signer};
+
+use crate::{
+    shared::Hash,
+    transactions::{
+        transaction::{RawTransaction, SignedTransaction},
+        transaction_output::Output,
+    },
+    utxo_map::UTXOMap,
+};
+
+const MAGIC: &[u8; 3] = b"CPT";
+pub const PARTIAL_TRANSACTION_VERSION: u8 = 1;
+// input index (u32 BE) | output
+const UTXO_TAG: u8 = 1;
+// pubkey | signature
+const SIGNATURE_TAG: u8 = 2;
+// key length (u16 BE) | key | value, both utf-8
+const METADATA_TAG: u8 = 3;
+// the signatures, in the order of the signers
+const FINAL_SIGNATURES_TAG: u8 = 4;
+pub const MAX_METADATA_KEY_LENGTH: usize = u16::MAX as usize;
+
+#[derive(Clone)]
+pub struct PartiallySignedTransaction {
+    raw: RawTransaction,
+    utxos: Vec<Option<Output>>,
+    signatures: BTreeMap<[u8; 32], Signature>,
+    metadata: BTreeMap<String, String>,
+    // only ever set to signatures checked against the signers
+    final_signatures: Option<Vec<Signature>>,
+}
+impl PartiallySignedTransaction {
+    pub fn new(raw: RawTransaction) -> Self {
+        Self {
+            utxos: vec![None; raw.inputs().len()],
+            raw,
+            signatures: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+            final_signatures: None,
+        }
+    }
+    pub fn get_raw(&self) -> &RawTransaction {
+        &self.raw
+    }
+    /// Output spent by the input `input_index`, when known.
+    pub fn get_utxo(&self, input_index: usize) -> Option<&Output> {
+        self.utxos.get(input_index)?.as_ref()
+    }
+    pub fn set_utxo(&mut self, input_index: usize, output: Output) {
+        if let Some(utxo) = self.utxos.get_mut(input_index) {
+            *utxo = Some(output);
+        }
+    }
+    /// Looks up the outputs spent in `utxos`, returns the number found.
+    pub fn fill_utxos(&mut self, utxos: &UTXOMap) -> usize {
+        let mut found = 0;
+        for (input, utxo) in self.raw.inputs().iter().zip(&mut self.utxos) {
+            if let Some(output) = utxos.try_find_matching_output(input) {
+                *utxo = Some(output.clone());
+                found += 1;
+            }
+        }
+        found
+    }
+    pub fn get_metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+    /// Keys are at most `MAX_METADATA_KEY_LENGTH` bytes long, their length is encoded on a u16.
+    pub fn set_metadata(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), PartialTransactionError> {
+        let key = key.into();
+        if key.len() > MAX_METADATA_KEY_LENGTH {
+            return Err(PartialTransactionError::MetadataKeyTooLong(key.len()));
+        }
+        self.metadata.insert(key, value.into());
+        Ok(())
+    }
+    /// Inputs minus outputs, when all the spent outputs are known.
+    pub fn fee(&self) -> Option<u64> {
+        let total_input = self.utxos.iter().try_fold(0u64, |total, utxo| {
+            total.checked_add(utxo.as_ref()?.get_amount())
+        })?;
+        let total_output = self
+            .raw
+            .outputs()
+            .iter()
+            .try_fold(0u64, |total, output| total.checked_add(output.get_amount()))?;
+        total_input.checked_sub(total_output)
+    }
+    /// Keys whose signatures finalize the transaction, each owning some of its inputs.
+    pub fn required_signers(&self) -> Vec<VerifyingKey> {
+        self.raw.get_signers().to_vec()
+    }
+    pub fn get_signature(&self, pubkey: &VerifyingKey) -> Option<&Signature> {
+        self.signatures.get(pubkey.as_bytes())
+    }
+    /// Signers whose signature is still missing.
+    pub fn missing_signers(&self) -> Vec<VerifyingKey> {
+        let hash = self.raw.hash();
+        self.raw
+            .get_signers()
+            .iter()
+            .filter(|pubkey| !self.has_valid_signature(pubkey, &hash))
+            .copied()
+            .collect()
+    }
+    /// Adds the signature of `key`, after checking that every known spent output is owned
+    /// by a signer.
+    pub fn sign(&mut self, key: &SigningKey) -> Result<(), PartialTransactionError> {
+        let pubkey = key.verifying_key();
+        if !self.raw.get_signers().contains(&pubkey) {
+            return Err(PartialTransactionError::NotASigner(pubkey.to_bytes()));
+        }
+        if let Some(input_index) = self.utxos.iter().position(|utxo| {
+            utxo.as_ref().is_some_and(|output| {
+                !self
+                    .raw
+                    .get_signers()
+                    .iter()
+                    .any(|signer| output.is_owned_by(signer))
+            })
+        }) {
+            return Err(PartialTransactionError::ForeignInput { input_index });
+        }
+        let signature = key.sign(&self.raw.hash());
+        self.signatures.insert(pubkey.to_bytes(), signature);
+        Ok(())
+    }
+    fn has_valid_signature(&self, pubkey: &VerifyingKey, hash: &Hash) -> bool {
+        self.signatures
+            .get(pubkey.as_bytes())
+            .is_some_and(|signature| pubkey.verify_strict(hash, signature).is_ok())
+    }
+    /// Merges what another signer added to the same transaction.
+    pub fn combine(&mut self, other: &Self) -> Result<(), PartialTransactionError> {
+        if self.raw.hash() != other.raw.hash() {
+            return Err(PartialTransactionError::DifferentTransaction);
+        }
+        for (input_index, (utxo, other_utxo)) in self.utxos.iter_mut().zip(&other.utxos).enumerate()
+        {
+            match (&utxo, other_utxo) {
+                (Some(output), Some(other_output))
+                    if output.to_bytes() != other_output.to_bytes() =>
+                {
+                    return Err(PartialTransactionError::ConflictingUtxo { input_index });
+                }
+                (None, Some(other_output)) => *utxo = Some(other_output.clone()),
+                _ => {}
+            }
+        }
+        // only valid signatures of the signers are taken, a wrong one received before
+        // is replaced so that it cannot hide the right one
+        let hash = self.raw.hash();
+        for pubkey in self.raw.get_signers() {
+            if let Some(signature) = other.signatures.get(pubkey.as_bytes())
+                && !self.has_valid_signature(pubkey, &hash)
+                && pubkey.verify_strict(&hash, signature).is_ok()
+            {
+                self.signatures.insert(pubkey.to_bytes(), *signature);
+            }
+        }
+        for (key, value) in &other.metadata {
+            self.metadata
+                .entry(key.clone())
+                .or_insert_with(|| value.clone());
+        }
+        if self.final_signatures.is_none()
+            && let Some(signatures) = &other.final_signatures
+            && self.check_final_signatures(signatures).is_ok()
+        {
+            self.final_signatures = Some(signatures.clone());
+        }
+        Ok(())
+    }
+    pub fn is_finalized(&self) -> bool {
+        self.final_signatures.is_some()
+    }
+    /// Checks the signatures of all the signers and turns them into the final ones.
+    pub fn finalize(&mut self) -> Result<(), PartialTransactionError> {
+        let hash = self.raw.hash();
+        let mut final_signatures = Vec::with_capacity(self.raw.get_signers().len());
+        for pubkey in self.raw.get_signers() {
+            let signature = self
+                .signatures
+                .get(pubkey.as_bytes())
+                .ok_or(PartialTransactionError::MissingSignature(pubkey.to_bytes()))?;
+            pubkey
+                .verify_strict(&hash, signature)
+                .map_err(|_| PartialTransactionError::InvalidSignature(pubkey.to_bytes()))?;
+            final_signatures.push(*signature);
+        }
+        self.final_signatures = Some(final_signatures);
+        self.signatures.clear();
+        Ok(())
+    }
+    /// The transaction to broadcast, once finalized.
+    pub fn extract(&self) -> Result<SignedTransaction, PartialTransactionError> {
+        let signatures = self
+            .final_signatures
+            .clone()
+            .ok_or(PartialTransactionError::NotFinalized)?;
+        Ok(SignedTransaction::with_signatures(
+            self.raw.clone(),
+            signatures,
+        ))
+    }
+    // checks that `signatures` are the ones of the signers, in their order
+    fn check_final_signatures(
+        &self,
+        signatures: &[Signature],
+    ) -> Result<(), PartialTransactionError> {
+        let signers = self.raw.get_signers();
+        if signatures.len() != signers.len() {
+            return Err(PartialTransactionError::InvalidEncoding);
+        }
+        let hash = self.raw.hash();
+        for (pubkey, signature) in signers.iter().zip(signatures) {
+            pubkey
+                .verify_strict(&hash, signature)
+                .map_err(|_| PartialTransactionError::InvalidSignature(pubkey.to_bytes()))?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let raw = self.raw.to_bytes();
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + 4 + raw.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(PARTIAL_TRANSACTION_VERSION);
+        bytes.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&raw);
+        for (input_index, utxo) in self.utxos.iter().enumerate() {
+            if let Some(output) = utxo {
+                let mut data = (input_index as u32).to_be_bytes().to_vec();
+                data.extend_from_slice(&output.to_bytes());
+                push_record(&mut bytes, UTXO_TAG, &data);
+            }
+        }
+        for (pubkey, signature) in &self.signatures {
+            let mut data = pubkey.to_vec();
+            data.extend_from_slice(&signature.to_bytes());
+            push_record(&mut bytes, SIGNATURE_TAG, &data);
+        }
+        for (key, value) in &self.metadata {
+            let mut data = (key.len() as u16).to_be_bytes().to_vec();
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(value.as_bytes());
+            push_record(&mut bytes, METADATA_TAG, &data);
+        }
+        if let Some(signatures) = &self.final_signatures {
+            let data: Vec<u8> = signatures.iter().flat_map(Signature::to_bytes).collect();
+            push_record(&mut bytes, FINAL_SIGNATURES_TAG, &data);
+        }
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PartialTransactionError> {
+        use PartialTransactionError::InvalidEncoding;
+        let rest = bytes.strip_prefix(MAGIC).ok_or(InvalidEncoding)?;
+        let (version, rest) = rest.split_first().ok_or(InvalidEncoding)?;
+        if *version != PARTIAL_TRANSACTION_VERSION {
+            return Err(PartialTransactionError::UnsupportedVersion(*version));
+        }
+        let (raw, mut rest) = read_chunk(rest).ok_or(InvalidEncoding)?;
+        let mut partial = Self::new(RawTransaction::from_bytes(raw).ok_or(InvalidEncoding)?);
+
+        while let Some((tag, after_tag)) = rest.split_first() {
+            let (data, after_record) = read_chunk(after_tag).ok_or(InvalidEncoding)?;
+            rest = after_record;
+            match *tag {
+                UTXO_TAG => {
+                    let (input_index, output) =
+                        data.split_first_chunk::<4>().ok_or(InvalidEncoding)?;
+                    let utxo = partial
+                        .utxos
+                        .get_mut(u32::from_be_bytes(*input_index) as usize)
+                        .ok_or(InvalidEncoding)?;
+                    let output = output.try_into().map_err(|_| InvalidEncoding)?;
+                    *utxo = Some(Output::from_bytes(output).ok_or(InvalidEncoding)?);
+                }
+                SIGNATURE_TAG => {
+                    let (pubkey, signature) =
+                        data.split_first_chunk::<32>().ok_or(InvalidEncoding)?;
+                    let signature =
+                        Signature::from_slice(signature).map_err(|_| InvalidEncoding)?;
+                    partial.signatures.insert(*pubkey, signature);
+                }
+                METADATA_TAG => {
+                    let (key_length, rest) =
+                        data.split_first_chunk::<2>().ok_or(InvalidEncoding)?;
+                    let (key, value) = rest
+                        .split_at_checked(u16::from_be_bytes(*key_length) as usize)
+                        .ok_or(InvalidEncoding)?;
+                    let key = String::from_utf8(key.to_vec()).map_err(|_| InvalidEncoding)?;
+                    let value = String::from_utf8(value.to_vec()).map_err(|_| InvalidEncoding)?;
+                    partial.metadata.insert(key, value);
+                }
+                FINAL_SIGNATURES_TAG => {
+                    if data.len() % Signature::BYTE_SIZE != 0 {
+                        return Err(InvalidEncoding);
+                    }
+                    let signatures = data
+                        .chunks_exact(Signature::BYTE_SIZE)
+                        .map(Signature::from_slice)
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| InvalidEncoding)?;
+                    partial.final_signatures = Some(signatures);
+                }
+                _ => return Err(InvalidEncoding),
+            }
+        }
+        // `extract` trusts them
+        if let Some(signatures) = &partial.final_signatures {
+            partial.check_final_signatures(signatures)?;
+        }
+        Ok(partial)
+    }
+}
+
+impl fmt::Display for PartiallySignedTransaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&BASE64_STANDARD.encode(self.to_bytes()))
+    }
+}
+impl FromStr for PartiallySignedTransaction {
+    type Err = PartialTransactionError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let bytes = BASE64_STANDARD
+            .decode(text.trim())
+            .map_err(|_| PartialTransactionError::InvalidEncoding)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+fn push_record(bytes: &mut Vec<u8>, tag: u8, data: &[u8]) {
+    bytes.push(tag);
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(data);
+}
+
+// length (u32 BE) | data, returns the data and what follows
+fn read_chunk(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (length, rest) = bytes.split_first_chunk::<4>()?;
+    rest.split_at_checked(u32::from_be_bytes(*length) as usize)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartialTransactionError {
+    InvalidEncoding,
+    UnsupportedVersion(u8),
+    /// Combined with another transaction.
+    DifferentTransaction,
+    ConflictingUtxo {
+        input_index: usize,
+    },
+    /// Compressed pubkey of a key that has nothing to sign.
+    NotASigner([u8; 32]),
+    /// The known output spent by the input is owned by none of the signers.
+    ForeignInput {
+        input_index: usize,
+    },
+    MissingSignature([u8; 32]),
+    InvalidSignature([u8; 32]),
+    NotFinalized,
+    /// Length in bytes of a metadata key longer than `MAX_METADATA_KEY_LENGTH`.
+    MetadataKeyTooLong(usize),
+}
+impl fmt::Display for PartialTransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidEncoding => write!(f, "not a partially signed transaction"),
+            Self::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported partially signed transaction version {version}"
+                )
+            }
+            Self::DifferentTransaction => write!(f, "cannot combine different transactions"),
+            Self::ConflictingUtxo { input_index } => {
+                write!(f, "conflicting outputs spent by input {input_index}")
+            }
+            Self::NotASigner(pubkey) => write!(
+                f,
+                "{} does not sign this transaction",
+                BASE64_STANDARD.encode(pubkey)
+            ),
+            Self::ForeignInput { input_index } => {
+                write!(f, "input {input_index} is not owned by a signer")
+            }
+            Self::MissingSignature(pubkey) => {
+                write!(
+                    f,
+                    "missing the signature of {}",
+                    BASE64_STANDARD.encode(pubkey)
+                )
+            }
+            Self::InvalidSignature(pubkey) => {
+                write!(f, "invalid signature of {}", BASE64_STANDARD.encode(pubkey))
+            }
+            Self::NotFinalized => write!(f, "the transaction is not finalized"),
+            Self::MetadataKeyTooLong(length) => write!(
+                f,
+                "metadata key of {length} bytes, at most {MAX_METADATA_KEY_LENGTH} are allowed"
+            ),
+        }
+    }
+}
+impl std::error::Error for PartialTransactionError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_chain::BlockChain,
+        test_utils::{add_block, regtest_chain, signing_key},
+        transactions::transaction_input::Input,
+    };
+
+    const FEE: u64 = 1_000;
+
+    // a chain where each of `keys` mined a block, and the transaction spending their three
+    // coinbases together to the key 9
+    fn shared_payment(keys: &[SigningKey]) -> (BlockChain, PartiallySignedTransaction) {
+        let mut chain = regtest_chain();
+        let mut inputs = vec![];
+        let mut total = 0;
+        for key in keys {
+            let block_hash = add_block(&mut chain, key, vec![]);
+            let coinbase = &chain
+                .get_block_by_hash(&block_hash)
+                .unwrap()
+                .get_transactions()[0];
+            inputs.push(Input::new(*coinbase.get_hash(), 0));
+            total += coinbase.outputs()[0].get_amount();
+        }
+        let raw = RawTransaction::with_signers(
+            inputs,
+            vec![Output::new(signing_key(9).verifying_key(), total - FEE)],
+            keys.iter().map(SigningKey::verifying_key).collect(),
+        );
+        let mut partial = PartiallySignedTransaction::new(raw);
+        partial.fill_utxos(chain.get_utxos());
+        (chain, partial)
+    }
+
+    fn keys() -> Vec<SigningKey> {
+        (1..=3).map(signing_key).collect()
+    }
+
+    #[test]
+    fn each_signer_signs_its_copy_and_the_combined_transaction_is_mined() {
+        let keys = keys();
+        let (mut chain, partial) = shared_payment(&keys);
+        assert_eq!(partial.fee(), Some(FEE));
+        let copies: Vec<PartiallySignedTransaction> = keys
+            .iter()
+            .map(|key| {
+                let mut copy: PartiallySignedTransaction = partial.to_string().parse().unwrap();
+                copy.sign(key).unwrap();
+                copy
+            })
+            .collect();
+        assert_eq!(
+            copies[0].missing_signers(),
+            vec![keys[1].verifying_key(), keys[2].verifying_key()]
+        );
+
+        let mut combined = copies[0].clone();
+        assert_eq!(
+            combined.finalize(),
+            Err(PartialTransactionError::MissingSignature(
+                keys[1].verifying_key().to_bytes()
+            ))
+        );
+        for copy in &copies[1..] {
+            combined.combine(copy).unwrap();
+        }
+        assert!(combined.missing_signers().is_empty());
+        combined.finalize().unwrap();
+        let transaction = combined.extract().unwrap();
+        assert_eq!(transaction.get_signatures().len(), 3);
+        assert_eq!(transaction.check_signature(), Ok(()));
+
+        add_block(&mut chain, &keys[0], vec![transaction]);
+        assert_eq!(
+            chain
+                .get_utxos()
+                .balance_of(&signing_key(9).verifying_key()),
+            partial.get_raw().outputs()[0].get_amount()
+        );
+    }
+
+    #[test]
+    fn a_key_outside_the_signers_cannot_sign() {
+        let (_, mut partial) = shared_payment(&keys());
+        let outsider = signing_key(9);
+        assert_eq!(
+            partial.sign(&outsider),
+            Err(PartialTransactionError::NotASigner(
+                outsider.verifying_key().to_bytes()
+            ))
+        );
+        // an input of the outsider is refused by every signer
+        partial.set_utxo(1, Output::new(outsider.verifying_key(), 1));
+        assert_eq!(
+            partial.sign(&keys()[0]),
+            Err(PartialTransactionError::ForeignInput { input_index: 1 })
+        );
+    }
+
+    #[test]
+    fn combine_keeps_only_valid_signatures() {
+        let keys = keys();
+        let (_, partial) = shared_payment(&keys);
+        let wrong_signature = keys[1].sign(b"another message");
+        let mut forged = partial.clone();
+        forged
+            .signatures
+            .insert(keys[1].verifying_key().to_bytes(), wrong_signature);
+
+        // a wrong signature received first is replaced by the right one
+        let mut combined = partial.clone();
+        combined.combine(&forged).unwrap();
+        assert!(combined.get_signature(&keys[1].verifying_key()).is_none());
+        combined.signatures = forged.signatures.clone();
+        let mut signed = partial.clone();
+        signed.sign(&keys[1]).unwrap();
+        combined.combine(&signed).unwrap();
+        assert_eq!(
+            combined.get_signature(&keys[1].verifying_key()),
+            signed.get_signature(&keys[1].verifying_key())
+        );
+
+        // and a wrong one never replaces the right one
+        signed.combine(&forged).unwrap();
+        assert!(!signed.missing_signers().contains(&keys[1].verifying_key()));
+    }
+
+    #[test]
+    fn forged_final_signatures_are_refused() {
+        let keys = keys();
+        let (_, mut partial) = shared_payment(&keys);
+        for key in &keys {
+            partial.sign(key).unwrap();
+        }
+        let unfinalized = partial.clone();
+        partial.finalize().unwrap();
+        let mut bytes = partial.to_bytes();
+        assert!(
+            PartiallySignedTransaction::from_bytes(&bytes)
+                .unwrap()
+                .is_finalized()
+        );
+
+        // the final signatures are the last record, the one of the last signer at the end
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(
+            PartiallySignedTransaction::from_bytes(&bytes).err(),
+            Some(PartialTransactionError::InvalidSignature(
+                keys[2].verifying_key().to_bytes()
+            ))
+        );
+
+        let mut forged = unfinalized.clone();
+        forged.final_signatures = Some(vec![keys[0].sign(b"another message"); 3]);
+        let mut combined = unfinalized.clone();
+        combined.combine(&forged).unwrap();
+        assert!(!combined.is_finalized());
+        assert_eq!(
+            combined.extract().err(),
+            Some(PartialTransactionError::NotFinalized)
+        );
+        combined.combine(&partial).unwrap();
+        assert_eq!(combined.extract().unwrap().check_signature(), Ok(()));
+    }
+
+    #[test]
+    fn metadata_keys_must_fit_their_length_prefix() {
+        let (_, mut partial) = shared_payment(&keys());
+        let longest = "k".repeat(MAX_METADATA_KEY_LENGTH);
+        partial.set_metadata(longest.clone(), "value").unwrap();
+        assert_eq!(
+            partial.set_metadata(longest.clone() + "k", "other"),
+            Err(PartialTransactionError::MetadataKeyTooLong(
+                MAX_METADATA_KEY_LENGTH + 1
+            ))
+        );
+        let decoded: PartiallySignedTransaction = partial.to_string().parse().unwrap();
+        assert_eq!(decoded.get_metadata(&longest), Some("value"));
+        assert_eq!(decoded.get_metadata(&(longest + "k")), None);
+    }
+}
@@ -1,4 +1,7 @@
-use ed25519_dalek::{Signature, SigningKey, VerifyingKey, ed25519::signature::SignerMut};
+use std::{collections::HashSet, fmt};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey, ed25519::signature::Signer};
 use sha2::{Digest, Sha256};
 
 use crate::{
@@ -8,48 +11,146 @@ use crate::{
     utxo_map::UTXOMap,
 };
 
+pub const MAX_TRANSACTION_SIZE: usize = 100_000;
+pub const MAX_INPUTS_PER_TRANSACTION: usize = 1_000;
+pub const MAX_OUTPUTS_PER_TRANSACTION: usize = 1_000;
+// hashed before the encoding of a transaction without the legacy tx id
+const TRANSACTION_HASH_PREFIX: &[u8] = b"Crypto Transaction:\n";
+
+#[derive(Clone)]
 pub struct RawTransaction {
     inputs: Vec<Input>,
     outputs: Vec<Output>,
-    pubkey: VerifyingKey,
+    // every input is owned by one of them, and each of them signs
+    signers: Vec<VerifyingKey>,
 }
 impl RawTransaction {
+    /// Transaction spending `inputs`, all owned by `pubkey`.
+    pub fn new(inputs: Vec<Input>, outputs: Vec<Output>, pubkey: VerifyingKey) -> Self {
+        Self::with_signers(inputs, outputs, vec![pubkey])
+    }
+    /// Transaction spending the coins of several keys, e.g. of parties paying together:
+    /// each input is owned by one of `signers`, and all of them sign.
+    pub fn with_signers(
+        inputs: Vec<Input>,
+        outputs: Vec<Output>,
+        signers: Vec<VerifyingKey>,
+    ) -> Self {
+        Self {
+            inputs,
+            outputs,
+            signers,
+        }
+    }
     pub fn inputs(&self) -> &[Input] {
         &self.inputs
     }
     pub fn outputs(&self) -> &[Output] {
         &self.outputs
     }
+    /// The tx id. A transaction of a single signer paying pubkeys only hashes as
+    /// inputs | outputs | pubkey, like before pubkey hashes and several signers. Any other
+    /// hashes its encoding, with the counts, so that the outputs of different sizes and the
+    /// signers can never be read as one another.
     pub fn hash(&self) -> Hash {
         let mut hasher = Sha256::new();
+        let pays_pubkeys_only = self
+            .outputs
+            .iter()
+            .all(|output| output.get_pubkey().is_some());
+        let ([pubkey], true) = (self.signers.as_slice(), pays_pubkeys_only) else {
+            hasher.update(TRANSACTION_HASH_PREFIX);
+            hasher.update(self.to_bytes());
+            return hasher.finalize().into();
+        };
         for input in &self.inputs {
             input.add_to_hash(&mut hasher);
         }
         for output in &self.outputs {
             output.add_to_hash(&mut hasher);
         }
-        hasher.update(self.pubkey.as_bytes());
+        hasher.update(pubkey.as_bytes());
         hasher.finalize().into()
     }
-    pub fn sign(self, sign_key: &mut SigningKey) -> SignedTransaction {
+    /// Signs a transaction of a single signer, the ones of several signers are signed
+    /// through a `PartiallySignedTransaction`.
+    pub fn sign(self, sign_key: &SigningKey) -> SignedTransaction {
         SignedTransaction::from_raw(self, sign_key)
     }
-    pub fn get_pubkey(&self) -> &VerifyingKey {
-        &self.pubkey
+    pub fn get_signers(&self) -> &[VerifyingKey] {
+        &self.signers
     }
-    fn coinbase(pubkey: VerifyingKey, amount: u64) -> Self {
-        Self {
-            inputs: vec![],
-            outputs: vec![Output::new(pubkey, amount)],
-            pubkey,
+    /// Size on the wire: input count (u32) | inputs | output count (u32) | outputs |
+    /// signer count (u32) | signers
+    pub fn serialized_size(&self) -> usize {
+        Self::size_for(self.inputs.len(), self.outputs.len(), self.signers.len())
+    }
+    /// Size on the wire of a transaction with that many inputs, outputs and signers.
+    pub fn size_for(input_count: usize, output_count: usize, signer_count: usize) -> usize {
+        4 + input_count * Input::SERIALIZED_SIZE
+            + 4
+            + output_count * Output::SERIALIZED_SIZE
+            + 4
+            + signer_count * 32
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.serialized_size());
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_be_bytes());
+        for input in &self.inputs {
+            bytes.extend_from_slice(&input.to_bytes());
+        }
+        bytes.extend_from_slice(&(self.outputs.len() as u32).to_be_bytes());
+        for output in &self.outputs {
+            bytes.extend_from_slice(&output.to_bytes());
+        }
+        bytes.extend_from_slice(&(self.signers.len() as u32).to_be_bytes());
+        for signer in &self.signers {
+            bytes.extend_from_slice(signer.as_bytes());
         }
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (input_count, rest) = bytes.split_first_chunk::<4>()?;
+        let input_count = u32::from_be_bytes(*input_count) as usize;
+        let (inputs, rest) =
+            rest.split_at_checked(input_count.checked_mul(Input::SERIALIZED_SIZE)?)?;
+        let (output_count, rest) = rest.split_first_chunk::<4>()?;
+        let output_count = u32::from_be_bytes(*output_count) as usize;
+        let (outputs, rest) =
+            rest.split_at_checked(output_count.checked_mul(Output::SERIALIZED_SIZE)?)?;
+        let (signer_count, signers) = rest.split_first_chunk::<4>()?;
+        if signers.len() != (u32::from_be_bytes(*signer_count) as usize).checked_mul(32)? {
+            return None;
+        }
+        let inputs = inputs
+            .chunks_exact(Input::SERIALIZED_SIZE)
+            .map(|input| Input::from_bytes(input.try_into().unwrap()))
+            .collect::<Option<_>>()?;
+        let outputs = outputs
+            .chunks_exact(Output::SERIALIZED_SIZE)
+            .map(|output| Output::from_bytes(output.try_into().unwrap()))
+            .collect::<Option<_>>()?;
+        let signers = signers
+            .chunks_exact(32)
+            .map(|signer| VerifyingKey::from_bytes(signer.try_into().unwrap()).ok())
+            .collect::<Option<_>>()?;
+        Some(Self::with_signers(inputs, outputs, signers))
+    }
+    fn coinbase(pubkey: VerifyingKey, amount: u64, height: usize) -> Self {
+        Self::new(
+            vec![Input::coinbase(height)],
+            vec![Output::new(pubkey, amount)],
+            pubkey,
+        )
     }
 }
 
+#[derive(Clone)]
 pub struct SignedTransaction {
     raw: RawTransaction,
     hash: Hash,
-    signature: Signature,
+    // in the order of the signers
+    signatures: Vec<Signature>,
 }
 impl SignedTransaction {
     pub fn data(&self) -> &RawTransaction {
@@ -65,45 +166,270 @@ impl SignedTransaction {
     pub fn get_hash(&self) -> &Hash {
         &self.hash
     }
-    fn from_raw(raw: RawTransaction, sign_key: &mut SigningKey) -> Self {
+    fn from_raw(raw: RawTransaction, sign_key: &SigningKey) -> Self {
         let hash = raw.hash();
-        let signature = sign_key.sign(&hash);
+        let signatures = vec![sign_key.sign(&hash)];
         Self {
             raw,
             hash,
-            signature,
+            signatures,
+        }
+    }
+    /// `raw` with the signatures of its signers made elsewhere, in their order, not checked.
+    pub fn with_signatures(raw: RawTransaction, signatures: Vec<Signature>) -> Self {
+        Self {
+            hash: raw.hash(),
+            raw,
+            signatures,
+        }
+    }
+    pub fn get_signers(&self) -> &[VerifyingKey] {
+        self.raw.get_signers()
+    }
+    pub fn get_signatures(&self) -> &[Signature] {
+        &self.signatures
+    }
+    /// Whether there is a signature for each signer, and at least one signer.
+    pub fn has_all_signatures(&self) -> bool {
+        !self.signatures.is_empty() && self.signatures.len() == self.get_signers().len()
+    }
+    /// Hash of the tx id and the signatures, committed in the block witness root.
+    pub fn witness_hash(&self) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash);
+        for signature in &self.signatures {
+            hasher.update(signature.to_bytes());
+        }
+        hasher.finalize().into()
+    }
+    pub fn check_hash(&self) -> Result<(), TransactionValidationError> {
+        let expected = self.raw.hash();
+        if expected != self.hash {
+            return Err(TransactionValidationError::HashIncorrect {
+                expected,
+                actual: self.hash,
+            });
+        }
+        Ok(())
+    }
+    pub fn check_signature(&self) -> Result<(), TransactionValidationError> {
+        self.check_hash()?;
+        self.verify_signature()
+    }
+    pub fn verify_signature(&self) -> Result<(), TransactionValidationError> {
+        if !self.has_all_signatures() {
+            return Err(TransactionValidationError::SignatureIncorrect);
+        }
+        for (pubkey, signature) in self.get_signers().iter().zip(&self.signatures) {
+            pubkey
+                .verify_strict(&self.hash, signature)
+                .map_err(|_| TransactionValidationError::SignatureIncorrect)?;
         }
+        Ok(())
     }
-    fn get_pubkey(&self) -> &VerifyingKey {
-        self.raw.get_pubkey()
+    /// Size on the wire: the raw transaction and the signatures, the hash is recomputed.
+    pub fn serialized_size(&self) -> usize {
+        self.raw.serialized_size() + self.signatures.len() * Signature::BYTE_SIZE
     }
-    fn check_signature(&self) -> Result<(), TransactionValidationError> {
-        if self.raw.hash() != self.hash {
-            return Err(TransactionValidationError::HashIncorrect);
+    /// Size on the wire of a signed transaction with that many inputs, outputs and
+    /// signers, what its fee is computed from.
+    pub fn size_for(input_count: usize, output_count: usize, signer_count: usize) -> usize {
+        RawTransaction::size_for(input_count, output_count, signer_count)
+            + signer_count * Signature::BYTE_SIZE
+    }
+    /// Number of signature checks needed to validate the transaction.
+    pub fn sig_op_count(&self) -> usize {
+        self.get_signers().len()
+    }
+    pub fn check_limits(&self) -> Result<(), TransactionValidationError> {
+        if self.inputs().len() > MAX_INPUTS_PER_TRANSACTION {
+            return Err(TransactionValidationError::TooManyInputs {
+                count: self.inputs().len(),
+                max: MAX_INPUTS_PER_TRANSACTION,
+            });
+        }
+        if self.outputs().len() > MAX_OUTPUTS_PER_TRANSACTION {
+            return Err(TransactionValidationError::TooManyOutputs {
+                count: self.outputs().len(),
+                max: MAX_OUTPUTS_PER_TRANSACTION,
+            });
+        }
+        let size = self.serialized_size();
+        if size > MAX_TRANSACTION_SIZE {
+            return Err(TransactionValidationError::TransactionTooLarge {
+                size,
+                max: MAX_TRANSACTION_SIZE,
+            });
+        }
+        if self.get_signers().is_empty() {
+            return Err(TransactionValidationError::NoSigner);
         }
-        match self.get_pubkey().verify_strict(&self.hash, &self.signature) {
-            Ok(()) => Ok(()),
-            Err(_) => Err(TransactionValidationError::SignatureIncorrect),
+        let mut seen_signers = HashSet::with_capacity(self.get_signers().len());
+        if let Some(signer_index) = self
+            .get_signers()
+            .iter()
+            .position(|signer| !seen_signers.insert(signer.as_bytes()))
+        {
+            return Err(TransactionValidationError::DuplicateSigner { signer_index });
         }
+        Ok(())
+    }
+    /// Returns the total output amount, after checking that no input is spent twice
+    /// by this transaction and that the amounts do not overflow.
+    pub fn check_amounts(&self) -> Result<u64, TransactionValidationError> {
+        let mut seen_inputs = HashSet::with_capacity(self.inputs().len());
+        if let Some(input_index) = self
+            .inputs()
+            .iter()
+            .position(|input| !seen_inputs.insert(input))
+        {
+            return Err(TransactionValidationError::DuplicateInput { input_index });
+        }
+        self.outputs()
+            .iter()
+            .try_fold(0u64, |total, output| total.checked_add(output.get_amount()))
+            .ok_or(TransactionValidationError::AmountOverflow)
     }
-    fn coinbase(sign_key: &mut SigningKey, amount: u64) -> Self {
-        let raw = RawTransaction::coinbase(sign_key.verifying_key(), amount);
+    fn coinbase(sign_key: &SigningKey, amount: u64, height: usize) -> Self {
+        let raw = RawTransaction::coinbase(sign_key.verifying_key(), amount, height);
         Self::from_raw(raw, sign_key)
     }
 }
 
-#[repr(transparent)]
 pub struct ValidatedTransaction {
     transaction: SignedTransaction,
+    // inputs minus outputs, 0 for a coinbase
+    fee: u64,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum TransactionValidationError {
     SignatureIncorrect,
-    HashIncorrect,
-    InsufficientInput,
-    InsufficientOutput,
-    InputInvalid,
-    UnauthorizedInput,
+    HashIncorrect { expected: Hash, actual: Hash },
+    InsufficientInput { total_input: u64, total_output: u64 },
+    InputInvalid { input_index: usize, input: Input },
+    UnauthorizedInput { input_index: usize },
+    DuplicateInput { input_index: usize },
+    AmountOverflow,
+    TooManyInputs { count: usize, max: usize },
+    TooManyOutputs { count: usize, max: usize },
+    TransactionTooLarge { size: usize, max: usize },
+    InvalidCoinbase,
+    CoinbaseTooLarge { max: u64, actual: u64 },
+    NoSigner,
+    DuplicateSigner { signer_index: usize },
+}
+impl TransactionValidationError {
+    /// Stable code of the error, for the RPC and the peer scoring. Never reuse a code.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::SignatureIncorrect => 200,
+            Self::HashIncorrect { .. } => 201,
+            Self::InsufficientInput { .. } => 202,
+            // 203 was InsufficientOutput, the excess of the inputs is now the fee
+            Self::InputInvalid { .. } => 204,
+            Self::UnauthorizedInput { .. } => 205,
+            Self::DuplicateInput { .. } => 206,
+            Self::AmountOverflow => 207,
+            Self::TooManyInputs { .. } => 208,
+            Self::TooManyOutputs { .. } => 209,
+            Self::TransactionTooLarge { .. } => 210,
+            Self::InvalidCoinbase => 211,
+            Self::CoinbaseTooLarge { .. } => 212,
+            Self::NoSigner => 213,
+            Self::DuplicateSigner { .. } => 214,
+        }
+    }
+    /// Whether the transaction can only have been built by a faulty or malicious peer.
+    /// An unknown input may just be spent in a block we do not have yet.
+    pub fn is_misbehavior(&self) -> bool {
+        !matches!(self, Self::InputInvalid { .. })
+    }
+}
+impl fmt::Display for TransactionValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SignatureIncorrect => write!(f, "signature does not match the transaction"),
+            Self::HashIncorrect { expected, actual } => write!(
+                f,
+                "transaction hash is {} but the transaction hashes to {}",
+                BASE64_STANDARD.encode(actual),
+                BASE64_STANDARD.encode(expected)
+            ),
+            Self::InsufficientInput {
+                total_input,
+                total_output,
+            } => write!(
+                f,
+                "inputs ({total_input}) are lower than the outputs ({total_output})"
+            ),
+            Self::InputInvalid { input_index, input } => write!(
+                f,
+                "input {input_index} ({}:{}) is not an unspent output",
+                BASE64_STANDARD.encode(input.get_tx_id()),
+                input.get_tx_idx()
+            ),
+            Self::UnauthorizedInput { input_index } => {
+                write!(
+                    f,
+                    "input {input_index} is not owned by a signer of the transaction"
+                )
+            }
+            Self::DuplicateInput { input_index } => {
+                write!(
+                    f,
+                    "input {input_index} is already spent by this transaction"
+                )
+            }
+            Self::AmountOverflow => write!(f, "amounts overflow"),
+            Self::TooManyInputs { count, max } => {
+                write!(f, "{count} inputs, at most {max} are allowed")
+            }
+            Self::TooManyOutputs { count, max } => {
+                write!(f, "{count} outputs, at most {max} are allowed")
+            }
+            Self::TransactionTooLarge { size, max } => {
+                write!(f, "transaction of {size} bytes, at most {max} are allowed")
+            }
+            Self::InvalidCoinbase => write!(
+                f,
+                "the first transaction must only spend the coinbase input of the block height"
+            ),
+            Self::CoinbaseTooLarge { max, actual } => {
+                write!(f, "coinbase of {actual}, at most {max} is allowed")
+            }
+            Self::NoSigner => write!(f, "the transaction has no signer"),
+            Self::DuplicateSigner { signer_index } => {
+                write!(f, "signer {signer_index} is already a signer")
+            }
+        }
+    }
+}
+impl std::error::Error for TransactionValidationError {}
+
+/// Checks of a transaction that do not need the UTXO set,
+/// so they can be done ahead of the UTXO pass (e.g. in parallel over a whole block).
+pub struct StatelessChecks {
+    total_output: Result<u64, TransactionValidationError>,
+    signature: Result<(), TransactionValidationError>,
+}
+impl StatelessChecks {
+    pub fn run(transaction: &SignedTransaction) -> Self {
+        Self::with_signature(transaction, transaction.check_signature())
+    }
+    /// For when the signature is checked separately, or not at all below the assumed valid
+    /// block.
+    pub fn with_signature(
+        transaction: &SignedTransaction,
+        signature: Result<(), TransactionValidationError>,
+    ) -> Self {
+        Self {
+            total_output: transaction
+                .check_limits()
+                .and_then(|_| transaction.check_amounts()),
+            signature,
+        }
+    }
 }
 
 impl ValidatedTransaction {
@@ -111,59 +437,122 @@ impl ValidatedTransaction {
         signed_transaction: SignedTransaction,
         utxo_map: &UTXOMap,
     ) -> Result<Self, TransactionValidationError> {
+        let checks = StatelessChecks::run(&signed_transaction);
+        Self::validate_with_checks(signed_transaction, utxo_map, checks)
+    }
+    /// Errors are reported in the same order whatever the way `checks` were computed:
+    /// limits and amounts, then inputs, then balance, then signature.
+    /// What the inputs have above the outputs is the fee, claimed by the coinbase.
+    pub fn validate_with_checks(
+        signed_transaction: SignedTransaction,
+        utxo_map: &UTXOMap,
+        checks: StatelessChecks,
+    ) -> Result<Self, TransactionValidationError> {
+        let total_output = checks.total_output?;
         let total_input = Self::sum_and_validat_inputs(
             signed_transaction.inputs(),
-            signed_transaction.get_pubkey(),
+            signed_transaction.get_signers(),
             utxo_map,
         )?;
-        let total_output: u64 = signed_transaction
-            .outputs()
-            .iter()
-            .map(|output| output.get_amount())
-            .sum();
-        Self::check_balance(total_input, total_output)?;
-        signed_transaction.check_signature()?;
+        let fee = Self::check_balance(total_input, total_output)?;
+        checks.signature?;
 
         Ok(Self {
             transaction: signed_transaction,
+            fee,
         })
     }
+    /// Validation of the first transaction of the block at `height`, which creates coins
+    /// from nothing. Its only input is `Input::coinbase(height)`, so that two coinbases never
+    /// have the same tx id. Its amount is checked by `check_coinbase_amount` once the fees
+    /// of the block are known.
+    pub fn validate_coinbase_with_checks(
+        signed_transaction: SignedTransaction,
+        checks: StatelessChecks,
+        height: usize,
+    ) -> Result<Self, TransactionValidationError> {
+        checks.total_output?;
+        if signed_transaction.inputs() != [Input::coinbase(height)] {
+            return Err(TransactionValidationError::InvalidCoinbase);
+        }
+        checks.signature?;
+
+        Ok(Self {
+            transaction: signed_transaction,
+            fee: 0,
+        })
+    }
+    /// The coinbase creates at most `max_amount`: the subsidy and the fees of its block.
+    pub fn check_coinbase_amount(&self, max_amount: u64) -> Result<(), TransactionValidationError> {
+        // cannot overflow, checked by `check_amounts`
+        let actual: u64 = self.outputs().iter().map(Output::get_amount).sum();
+        if actual > max_amount {
+            return Err(TransactionValidationError::CoinbaseTooLarge {
+                max: max_amount,
+                actual,
+            });
+        }
+        Ok(())
+    }
     fn sum_and_validat_inputs(
         inputs: &[Input],
-        pubkey: &VerifyingKey,
+        signers: &[VerifyingKey],
         utxo_map: &UTXOMap,
     ) -> Result<u64, TransactionValidationError> {
-        let mut input_sum = 0;
-        for input in inputs {
+        let mut input_sum: u64 = 0;
+        for (input_index, input) in inputs.iter().enumerate() {
             let Some(output) = utxo_map.try_find_matching_output(input) else {
-                return Err(TransactionValidationError::InputInvalid);
+                return Err(TransactionValidationError::InputInvalid {
+                    input_index,
+                    input: *input,
+                });
             };
-            if output.get_pubkey() != pubkey {
-                return Err(TransactionValidationError::UnauthorizedInput);
+            // a pay-to-pubkey-hash output is spent by revealing its key as a signer
+            if !signers.iter().any(|signer| output.is_owned_by(signer)) {
+                return Err(TransactionValidationError::UnauthorizedInput { input_index });
             }
-            input_sum += output.get_amount()
+            input_sum = input_sum
+                .checked_add(output.get_amount())
+                .ok_or(TransactionValidationError::AmountOverflow)?;
         }
         Ok(input_sum)
     }
+    // returns the fee
     fn check_balance(
         total_input: u64,
         total_output: u64,
-    ) -> Result<(), TransactionValidationError> {
-        use std::cmp::Ordering;
-        match total_input.cmp(&total_output) {
-            Ordering::Equal => Ok(()),
-            Ordering::Less => Err(TransactionValidationError::InsufficientInput),
-            Ordering::Greater => Err(TransactionValidationError::InsufficientOutput),
-        }
+    ) -> Result<u64, TransactionValidationError> {
+        total_input
+            .checked_sub(total_output)
+            .ok_or(TransactionValidationError::InsufficientInput {
+                total_input,
+                total_output,
+            })
     }
     pub fn get_hash(&self) -> &Hash {
         self.transaction.get_hash()
     }
-    pub fn get_coin_base(block_chain: &BlockChain, sign_key: &mut SigningKey) -> Self {
-        let amount = block_chain.get_coin_base_amount();
+    pub fn get_witness_hash(&self) -> Hash {
+        self.transaction.witness_hash()
+    }
+    pub fn get_fee(&self) -> u64 {
+        self.fee
+    }
+    pub fn get_coin_base(block_chain: &BlockChain, sign_key: &SigningKey) -> Self {
+        Self::get_coin_base_with_fees(block_chain, sign_key, 0)
+    }
+    /// Coinbase of the next block, claiming the `fees` of its transactions on top of the subsidy.
+    pub fn get_coin_base_with_fees(
+        block_chain: &BlockChain,
+        sign_key: &SigningKey,
+        fees: u64,
+    ) -> Self {
+        let amount = block_chain.get_coin_base_amount() + fees;
+        let height = block_chain.len();
 
         Self {
-            transaction: SignedTransaction::coinbase(sign_key, amount),
+            transaction: SignedTransaction::coinbase(sign_key, amount, height),
+            fee: 0,
         }
     }
     pub fn inputs(&self) -> &[Input] {
@@ -172,4 +561,502 @@ impl ValidatedTransaction {
     pub fn outputs(&self) -> &[Output] {
         self.transaction.outputs()
     }
+    pub fn get_signed(&self) -> &SignedTransaction {
+        &self.transaction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{add_block, regtest_chain, signing_key},
+        transactions::{
+            address::{PUBKEY_HASH_VERSION, PUBKEY_VERSION, pubkey_hash},
+            partially_signed::{PartialTransactionError, PartiallySignedTransaction},
+        },
+    };
+
+    // a chain where the key 1 mined two blocks, with the inputs spending their coinbases
+    fn funded_chain() -> (BlockChain, Vec<Input>, u64) {
+        let mut chain = regtest_chain();
+        let mut inputs = vec![];
+        for _ in 0..2 {
+            let block_hash = add_block(&mut chain, &signing_key(1), vec![]);
+            let coinbase = &chain
+                .get_block_by_hash(&block_hash)
+                .unwrap()
+                .get_transactions()[0];
+            inputs.push(Input::new(*coinbase.get_hash(), 0));
+        }
+        let amount = chain.get_coin_base_amount();
+        (chain, inputs, amount)
+    }
+
+    fn pay(inputs: Vec<Input>, amounts: &[u64], signer: u8) -> SignedTransaction {
+        let outputs = amounts
+            .iter()
+            .map(|&amount| Output::new(signing_key(9).verifying_key(), amount))
+            .collect();
+        RawTransaction::new(inputs, outputs, signing_key(signer).verifying_key())
+            .sign(&signing_key(signer))
+    }
+
+    fn raw_of(signers: &[u8]) -> RawTransaction {
+        RawTransaction::with_signers(
+            vec![Input::new([1; 32], 0), Input::new([2; 32], 1)],
+            vec![Output::new(signing_key(9).verifying_key(), 10)],
+            signers
+                .iter()
+                .map(|&seed| signing_key(seed).verifying_key())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn single_signer_tx_ids_are_unchanged() {
+        let raw = raw_of(&[1]);
+        let mut hasher = Sha256::new();
+        for input in raw.inputs() {
+            input.add_to_hash(&mut hasher);
+        }
+        for output in raw.outputs() {
+            output.add_to_hash(&mut hasher);
+        }
+        hasher.update(signing_key(1).verifying_key().as_bytes());
+        let expected: Hash = hasher.finalize().into();
+        assert_eq!(raw.hash(), expected);
+
+        let shared = raw_of(&[1, 2]);
+        assert_ne!(shared.hash(), expected);
+        assert_ne!(shared.hash(), raw_of(&[2, 1]).hash());
+        let decoded = RawTransaction::from_bytes(&shared.to_bytes()).unwrap();
+        assert_eq!(decoded.hash(), shared.hash());
+        assert_eq!(decoded.get_signers(), shared.get_signers());
+    }
+
+    #[test]
+    fn every_signer_must_sign() {
+        let raw = raw_of(&[1, 2]);
+        let hash = raw.hash();
+        let first = signing_key(1).sign(&hash);
+        let second = signing_key(2).sign(&hash);
+
+        let signed = SignedTransaction::with_signatures(raw.clone(), vec![first, second]);
+        assert_eq!(signed.check_signature(), Ok(()));
+        assert_eq!(signed.sig_op_count(), 2);
+        assert_eq!(
+            signed.serialized_size(),
+            SignedTransaction::size_for(2, 1, 2)
+        );
+        for signatures in [vec![first], vec![second, first], vec![first, first]] {
+            let signed = SignedTransaction::with_signatures(raw.clone(), signatures);
+            assert_eq!(
+                signed.check_signature(),
+                Err(TransactionValidationError::SignatureIncorrect)
+            );
+        }
+    }
+
+    #[test]
+    fn small_order_signatures_are_refused() {
+        // the identity as key and nonce with s = 0: the plain equation holds for any message
+        let mut identity = [0; 32];
+        identity[0] = 1;
+        let pubkey = VerifyingKey::from_bytes(&identity).unwrap();
+        let raw = RawTransaction::with_signers(
+            vec![Input::new([1; 32], 0)],
+            vec![Output::new(signing_key(9).verifying_key(), 10)],
+            vec![pubkey],
+        );
+        let signature = Signature::from_components(identity, [0; 32]);
+        assert!(ed25519_dalek::Verifier::verify(&pubkey, &raw.hash(), &signature).is_ok());
+        let forged = SignedTransaction::with_signatures(raw, vec![signature]);
+        assert_eq!(
+            forged.check_signature(),
+            Err(TransactionValidationError::SignatureIncorrect)
+        );
+    }
+
+    #[test]
+    fn signers_are_present_and_distinct() {
+        let no_signer = SignedTransaction::with_signatures(raw_of(&[]), vec![]);
+        assert_eq!(
+            no_signer.check_limits(),
+            Err(TransactionValidationError::NoSigner)
+        );
+        assert_eq!(
+            no_signer.check_signature(),
+            Err(TransactionValidationError::SignatureIncorrect)
+        );
+        let duplicate = SignedTransaction::with_signatures(raw_of(&[1, 2, 1]), vec![]);
+        assert_eq!(
+            duplicate.check_limits(),
+            Err(TransactionValidationError::DuplicateSigner { signer_index: 2 })
+        );
+    }
+
+    #[test]
+    fn a_pubkey_hash_output_is_spent_by_revealing_its_key() {
+        use TransactionValidationError::*;
+        let (mut chain, inputs, amount) = funded_chain();
+        let owner = signing_key(2).verifying_key();
+        let to_hash = RawTransaction::new(
+            vec![inputs[0]],
+            vec![Output::new_pubkey_hash(pubkey_hash(&owner), amount)],
+            signing_key(1).verifying_key(),
+        )
+        .sign(&signing_key(1));
+        let hashed = Input::new(to_hash.hash, 0);
+        add_block(&mut chain, &signing_key(1), vec![to_hash]);
+        let validate = |transaction: SignedTransaction| {
+            ValidatedTransaction::validate(transaction, chain.get_utxos()).err()
+        };
+        let sign_by = |inputs: Vec<Input>, signers: &[u8]| {
+            let raw = RawTransaction::with_signers(
+                inputs,
+                vec![Output::new(signing_key(9).verifying_key(), amount)],
+                signers
+                    .iter()
+                    .map(|&seed| signing_key(seed).verifying_key())
+                    .collect(),
+            );
+            let signatures = signers
+                .iter()
+                .map(|&seed| signing_key(seed).sign(&raw.hash()))
+                .collect();
+            SignedTransaction::with_signatures(raw, signatures)
+        };
+
+        // another key, even one signing well, doesn't match the hash
+        assert_eq!(
+            validate(pay(vec![hashed], &[amount], 3)),
+            Some(UnauthorizedInput { input_index: 0 })
+        );
+        assert_eq!(
+            validate(pay(vec![inputs[1], hashed], &[amount], 1)),
+            Some(UnauthorizedInput { input_index: 1 })
+        );
+        // the revealed key must also sign
+        let raw = pay(vec![hashed], &[amount], 2).raw;
+        let signature = signing_key(3).sign(&raw.hash());
+        assert_eq!(
+            validate(SignedTransaction::with_signatures(raw, vec![signature])),
+            Some(SignatureIncorrect)
+        );
+
+        assert_eq!(validate(pay(vec![hashed], &[amount], 2)), None);
+        assert_eq!(validate(sign_by(vec![hashed], &[3, 2])), None);
+        assert_eq!(validate(sign_by(vec![inputs[1], hashed], &[1, 2])), None);
+        assert_eq!(
+            validate(sign_by(vec![inputs[1], hashed], &[1, 3])),
+            Some(UnauthorizedInput { input_index: 1 })
+        );
+    }
+
+    #[test]
+    fn pubkey_outputs_keep_their_encoding_and_tx_ids() {
+        let payee = signing_key(9).verifying_key();
+        let signer = signing_key(1).verifying_key();
+        let raw = RawTransaction::new(
+            vec![Input::new([1; 32], 3)],
+            vec![Output::new(payee, 10)],
+            signer,
+        );
+        // tx id | output index | payee | amount | signer, as before the pubkey hashes
+        let mut bytes = vec![1; 32];
+        bytes.extend_from_slice(&3usize.to_be_bytes());
+        bytes.extend_from_slice(payee.as_bytes());
+        bytes.extend_from_slice(&10u64.to_be_bytes());
+        bytes.extend_from_slice(signer.as_bytes());
+        assert_eq!(raw.hash(), <Hash>::from(Sha256::digest(&bytes)));
+
+        // paying the same 32 bytes as a hash is another output
+        let by_hash = RawTransaction::new(
+            vec![Input::new([1; 32], 3)],
+            vec![Output::new_pubkey_hash(*payee.as_bytes(), 10)],
+            signer,
+        );
+        assert_ne!(by_hash.hash(), raw.hash());
+        for output in [raw.outputs(), by_hash.outputs()].concat() {
+            let decoded = Output::from_bytes(&output.to_bytes()).unwrap();
+            assert_eq!(decoded.get_payee(), output.get_payee());
+        }
+        assert_eq!(raw.outputs()[0].to_bytes()[0], PUBKEY_VERSION);
+        assert_eq!(by_hash.outputs()[0].to_bytes()[0], PUBKEY_HASH_VERSION);
+    }
+
+    #[test]
+    fn swapping_pubkey_and_pubkey_hash_outputs_changes_the_tx_id() {
+        // a key whose encoding starts with the version of the pubkey hashes
+        let pubkey = (0u64..)
+            .map(|seed| {
+                let mut bytes = [0; 32];
+                bytes[..8].copy_from_slice(&seed.to_be_bytes());
+                SigningKey::from_bytes(&bytes).verifying_key()
+            })
+            .find(|pubkey| pubkey.as_bytes()[0] == PUBKEY_HASH_VERSION)
+            .unwrap();
+        let other = signing_key(9).verifying_key();
+        let signer = signing_key(1).verifying_key();
+        // P2PK(pubkey) | 10_000 | P2PKH(other) | 5 and P2PKH(pubkey[1..] | 0) | 10_000 << 8 | 1 |
+        // P2PK(other) | 5 lay out the same bytes, the amounts holding the version of the hash
+        let mut hash = [0; 32];
+        hash[..31].copy_from_slice(&pubkey.as_bytes()[1..]);
+        let first = RawTransaction::new(
+            vec![Input::new([1; 32], 0)],
+            vec![
+                Output::new(pubkey, 10_000),
+                Output::new_pubkey_hash(*other.as_bytes(), 5),
+            ],
+            signer,
+        );
+        let second = RawTransaction::new(
+            vec![Input::new([1; 32], 0)],
+            vec![
+                Output::new_pubkey_hash(hash, (10_000 << 8) | 1),
+                Output::new(other, 5),
+            ],
+            signer,
+        );
+        let legacy_bytes = |raw: &RawTransaction| {
+            let mut hasher = Sha256::new();
+            for output in raw.outputs() {
+                output.add_to_hash(&mut hasher);
+            }
+            <Hash>::from(hasher.finalize())
+        };
+        assert_eq!(legacy_bytes(&first), legacy_bytes(&second));
+        assert_ne!(first.hash(), second.hash());
+
+        // so a signer cannot be handed one transaction for the other
+        let mut partial = PartiallySignedTransaction::new(first);
+        let mut swapped = PartiallySignedTransaction::new(second);
+        swapped.sign(&signing_key(1)).unwrap();
+        assert_eq!(
+            partial.combine(&swapped),
+            Err(PartialTransactionError::DifferentTransaction)
+        );
+        assert_eq!(partial.missing_signers(), [signer]);
+    }
+
+    #[test]
+    fn each_failure_has_its_own_error() {
+        use TransactionValidationError::*;
+        let (chain, inputs, amount) = funded_chain();
+        let utxos = chain.get_utxos();
+        let validate = |transaction| ValidatedTransaction::validate(transaction, utxos).err();
+
+        let valid = ValidatedTransaction::validate(pay(vec![inputs[0]], &[amount - 10], 1), utxos);
+        assert_eq!(valid.unwrap().get_fee(), 10);
+
+        let mut wrong_hash = pay(vec![inputs[0]], &[amount], 1);
+        wrong_hash.hash = [0; 32];
+        assert_eq!(
+            validate(wrong_hash.clone()),
+            Some(HashIncorrect {
+                expected: wrong_hash.raw.hash(),
+                actual: [0; 32],
+            })
+        );
+        let raw = pay(vec![inputs[0]], &[amount], 1).raw;
+        let signature = signing_key(2).sign(&raw.hash());
+        assert_eq!(
+            validate(SignedTransaction::with_signatures(raw, vec![signature])),
+            Some(SignatureIncorrect)
+        );
+        assert_eq!(
+            validate(pay(inputs.clone(), &[2 * amount + 1], 1)),
+            Some(InsufficientInput {
+                total_input: 2 * amount,
+                total_output: 2 * amount + 1,
+            })
+        );
+        let unknown = Input::new([0xee; 32], 0);
+        assert_eq!(
+            validate(pay(vec![inputs[0], unknown], &[amount], 1)),
+            Some(InputInvalid {
+                input_index: 1,
+                input: unknown,
+            })
+        );
+        assert_eq!(
+            validate(pay(vec![inputs[1]], &[amount], 2)),
+            Some(UnauthorizedInput { input_index: 0 })
+        );
+        assert_eq!(
+            validate(pay(vec![inputs[0], inputs[1], inputs[0]], &[amount], 1)),
+            Some(DuplicateInput { input_index: 2 })
+        );
+        assert_eq!(
+            validate(pay(vec![inputs[0]], &[u64::MAX, 1], 1)),
+            Some(AmountOverflow)
+        );
+
+        assert_eq!(
+            validate(pay(
+                vec![inputs[0]; MAX_INPUTS_PER_TRANSACTION + 1],
+                &[1],
+                1
+            )),
+            Some(TooManyInputs {
+                count: MAX_INPUTS_PER_TRANSACTION + 1,
+                max: MAX_INPUTS_PER_TRANSACTION,
+            })
+        );
+        assert_eq!(
+            validate(pay(
+                vec![inputs[0]],
+                &[1; MAX_OUTPUTS_PER_TRANSACTION + 1],
+                1
+            )),
+            Some(TooManyOutputs {
+                count: MAX_OUTPUTS_PER_TRANSACTION + 1,
+                max: MAX_OUTPUTS_PER_TRANSACTION,
+            })
+        );
+        let large = pay(
+            vec![inputs[0]; MAX_INPUTS_PER_TRANSACTION],
+            &[1; MAX_OUTPUTS_PER_TRANSACTION],
+            1,
+        );
+        let raw = RawTransaction::with_signers(
+            large.inputs().to_vec(),
+            large.outputs().to_vec(),
+            (0..=255)
+                .map(|seed| signing_key(seed).verifying_key())
+                .collect(),
+        );
+        let too_large =
+            SignedTransaction::with_signatures(raw, vec![Signature::from_bytes(&[0; 64]); 256]);
+        assert_eq!(
+            validate(too_large.clone()),
+            Some(TransactionTooLarge {
+                size: too_large.serialized_size(),
+                max: MAX_TRANSACTION_SIZE,
+            })
+        );
+        assert_eq!(
+            validate(SignedTransaction::with_signatures(raw_of(&[]), vec![])),
+            Some(NoSigner)
+        );
+        assert_eq!(
+            validate(SignedTransaction::with_signatures(raw_of(&[1, 1]), vec![])),
+            Some(DuplicateSigner { signer_index: 1 })
+        );
+    }
+
+    #[test]
+    fn coinbase_failures() {
+        let (chain, inputs, amount) = funded_chain();
+        let height = chain.len();
+        let validate_coinbase = |transaction: SignedTransaction| {
+            let checks = StatelessChecks::run(&transaction);
+            ValidatedTransaction::validate_coinbase_with_checks(transaction, checks, height)
+        };
+        let coinbase = ValidatedTransaction::get_coin_base(&chain, &signing_key(1));
+        let coinbase = validate_coinbase(coinbase.get_signed().clone()).unwrap();
+        assert_eq!(
+            coinbase.check_coinbase_amount(amount - 1),
+            Err(TransactionValidationError::CoinbaseTooLarge {
+                max: amount - 1,
+                actual: amount,
+            })
+        );
+        assert_eq!(coinbase.check_coinbase_amount(amount), Ok(()));
+
+        // a coinbase of another height, a regular transaction
+        let old_coinbase = chain.get_block(0).unwrap().get_transactions()[0].get_signed();
+        for transaction in [old_coinbase.clone(), pay(vec![inputs[0]], &[amount], 1)] {
+            assert_eq!(
+                validate_coinbase(transaction).err(),
+                Some(TransactionValidationError::InvalidCoinbase)
+            );
+        }
+    }
+
+    #[test]
+    fn corrupted_encodings_are_refused_without_panicking() {
+        use rand::{Rng, SeedableRng, rngs::StdRng};
+
+        let mut rng = StdRng::seed_from_u64(34);
+        let encodings = [raw_of(&[1]), raw_of(&[1, 2, 3]), raw_of(&[])].map(|raw| raw.to_bytes());
+        for round in 0..3_000 {
+            let mut bytes = encodings[round % encodings.len()].clone();
+            for _ in 0..rng.gen_range(1..4) {
+                let position = rng.gen_range(0..bytes.len());
+                match rng.gen_range(0..4) {
+                    0 => bytes[position] = rng.r#gen(),
+                    1 => bytes.truncate(position),
+                    2 => bytes.extend((0..rng.gen_range(1..80)).map(|_| rng.r#gen::<u8>())),
+                    // a count field claiming up to u32::MAX items
+                    _ => {
+                        let count = [0, 4 + 2 * Input::SERIALIZED_SIZE][rng.gen_range(0..2)];
+                        if let Some(field) = bytes.get_mut(count..count + 4) {
+                            field.copy_from_slice(&rng.r#gen::<u32>().to_be_bytes());
+                        }
+                    }
+                }
+                if bytes.is_empty() {
+                    break;
+                }
+            }
+            // whatever is decoded is the only encoding of its transaction
+            if let Some(raw) = RawTransaction::from_bytes(&bytes) {
+                assert_eq!(raw.to_bytes(), bytes, "round {round}");
+                assert_eq!(raw.serialized_size(), bytes.len());
+                let signed = SignedTransaction::with_signatures(raw, vec![]);
+                let _ = signed.check_limits();
+                assert!(ValidatedTransaction::validate(signed, &UTXOMap::new()).is_err());
+            }
+        }
+        let mut huge = u32::MAX.to_be_bytes().to_vec();
+        huge.extend([0; 64]);
+        assert!(RawTransaction::from_bytes(&huge).is_none());
+    }
+
+    #[test]
+    fn error_codes_are_stable() {
+        use TransactionValidationError::*;
+        let input = Input::new([0; 32], 0);
+        let codes = [
+            (SignatureIncorrect, 200),
+            (
+                HashIncorrect {
+                    expected: [0; 32],
+                    actual: [1; 32],
+                },
+                201,
+            ),
+            (
+                InsufficientInput {
+                    total_input: 0,
+                    total_output: 1,
+                },
+                202,
+            ),
+            (
+                InputInvalid {
+                    input_index: 0,
+                    input,
+                },
+                204,
+            ),
+            (UnauthorizedInput { input_index: 0 }, 205),
+            (DuplicateInput { input_index: 0 }, 206),
+            (AmountOverflow, 207),
+            (TooManyInputs { count: 0, max: 0 }, 208),
+            (TooManyOutputs { count: 0, max: 0 }, 209),
+            (TransactionTooLarge { size: 0, max: 0 }, 210),
+            (InvalidCoinbase, 211),
+            (CoinbaseTooLarge { max: 0, actual: 0 }, 212),
+            (NoSigner, 213),
+            (DuplicateSigner { signer_index: 0 }, 214),
+        ];
+        for (error, code) in codes {
+            assert_eq!(error.code(), code, "{error}");
+            // only an unknown input can be the fault of our own chain
+            assert_eq!(error.is_misbehavior(), code != 204, "{error}");
+        }
+    }
 }
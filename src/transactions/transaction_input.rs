@@ -2,12 +2,15 @@ use sha2::{Sha256, digest::Update};
 
 use crate::shared::Hash;
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Input {
     tx_id: Hash,
     tx_output_idx: usize,
 }
 impl Input {
+    // tx_id + output index as u64
+    pub const SERIALIZED_SIZE: usize = 32 + 8;
+
     /*pub fn new(transaction: &SignedTransaction, tx_output_idx: usize) -> Self {
         assert!(
             transaction.outputs().len() > tx_output_idx,
@@ -24,6 +27,16 @@ impl Input {
             tx_output_idx,
         }
     }
+    /// Input of a coinbase: it spends nothing, the index is the block height.
+    pub fn coinbase(height: usize) -> Self {
+        Self {
+            tx_id: Hash::default(),
+            tx_output_idx: height,
+        }
+    }
+    pub fn is_coinbase(&self) -> bool {
+        self.tx_id == Hash::default()
+    }
     pub fn add_to_hash(&self, hasher: &mut Sha256) {
         hasher.update(&self.tx_id);
         hasher.update(&self.tx_output_idx.to_be_bytes());
@@ -34,4 +47,19 @@ impl Input {
     pub fn get_tx_idx(&self) -> usize {
         self.tx_output_idx
     }
+    /// tx_id | output index (u64 BE)
+    pub fn to_bytes(&self) -> [u8; Self::SERIALIZED_SIZE] {
+        let mut bytes = [0u8; Self::SERIALIZED_SIZE];
+        bytes[..32].copy_from_slice(&self.tx_id);
+        bytes[32..].copy_from_slice(&(self.tx_output_idx as u64).to_be_bytes());
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8; Self::SERIALIZED_SIZE]) -> Option<Self> {
+        let (tx_id, tx_output_idx) = bytes.split_at(32);
+        let tx_output_idx = u64::from_be_bytes(tx_output_idx.try_into().unwrap());
+        Some(Self {
+            tx_id: tx_id.try_into().unwrap(),
+            tx_output_idx: tx_output_idx.try_into().ok()?,
+        })
+    }
 }
@@ -1,25 +1,92 @@
 use ed25519_dalek::VerifyingKey;
 use sha2::{Sha256, digest::Update};
 
+use crate::{
+    shared::Hash,
+    transactions::address::{Address, AddressPayload, PUBKEY_HASH_VERSION, PUBKEY_VERSION},
+};
+
+/// Amount paid to a pubkey, or to the hash of a pubkey which stays hidden until the
+/// output is spent.
 #[derive(Clone)]
 pub struct Output {
-    pubkey: VerifyingKey,
+    payee: AddressPayload,
     amount: u64,
 }
 impl Output {
+    // kind + pubkey or pubkey hash + amount
+    pub const SERIALIZED_SIZE: usize = 1 + 32 + 8;
+
     pub fn new(pubkey: VerifyingKey, amount: u64) -> Self {
-        Output { pubkey, amount }
+        Output {
+            payee: AddressPayload::PubKey(pubkey),
+            amount,
+        }
+    }
+    pub fn new_pubkey_hash(pubkey_hash: Hash, amount: u64) -> Self {
+        Output {
+            payee: AddressPayload::PubKeyHash(pubkey_hash),
+            amount,
+        }
+    }
+    pub fn pay_to(address: &Address, amount: u64) -> Self {
+        Output {
+            payee: *address.get_payload(),
+            amount,
+        }
     }
 
-    pub fn get_pubkey(&self) -> &VerifyingKey {
-        &self.pubkey
+    pub fn get_payee(&self) -> &AddressPayload {
+        &self.payee
+    }
+    /// The pubkey of a pay-to-pubkey output.
+    pub fn get_pubkey(&self) -> Option<&VerifyingKey> {
+        match &self.payee {
+            AddressPayload::PubKey(pubkey) => Some(pubkey),
+            AddressPayload::PubKeyHash(_) => None,
+        }
+    }
+    /// Whether `pubkey` can spend the output.
+    pub fn is_owned_by(&self, pubkey: &VerifyingKey) -> bool {
+        self.payee.is_owned_by(pubkey)
     }
 
     pub fn get_amount(&self) -> u64 {
         self.amount
     }
+    /// kind (the address version) | pubkey or pubkey hash | amount (u64 BE)
+    pub fn to_bytes(&self) -> [u8; Self::SERIALIZED_SIZE] {
+        let mut bytes = [0u8; Self::SERIALIZED_SIZE];
+        bytes[0] = match self.payee {
+            AddressPayload::PubKey(_) => PUBKEY_VERSION,
+            AddressPayload::PubKeyHash(_) => PUBKEY_HASH_VERSION,
+        };
+        bytes[1..33].copy_from_slice(self.payee.as_bytes());
+        bytes[33..].copy_from_slice(&self.amount.to_be_bytes());
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8; Self::SERIALIZED_SIZE]) -> Option<Self> {
+        let (kind, rest) = bytes.split_first()?;
+        let (payee, amount) = rest.split_first_chunk::<32>()?;
+        let payee = match *kind {
+            PUBKEY_VERSION => AddressPayload::PubKey(VerifyingKey::from_bytes(payee).ok()?),
+            PUBKEY_HASH_VERSION => AddressPayload::PubKeyHash(*payee),
+            _ => return None,
+        };
+        Some(Self {
+            payee,
+            amount: u64::from_be_bytes(amount.try_into().ok()?),
+        })
+    }
+    /// Pay-to-pubkey outputs hash as they always did, so the tx ids don't change.
     pub fn add_to_hash(&self, hasher: &mut Sha256) {
-        hasher.update(self.pubkey.as_bytes());
+        match &self.payee {
+            AddressPayload::PubKey(pubkey) => hasher.update(pubkey.as_bytes()),
+            AddressPayload::PubKeyHash(hash) => {
+                hasher.update(&[PUBKEY_HASH_VERSION]);
+                hasher.update(hash);
+            }
+        }
         hasher.update(&self.amount.to_be_bytes());
     }
 }
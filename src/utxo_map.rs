@@ -1,38 +1,294 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+
+use ed25519_dalek::VerifyingKey;
 
 use crate::{
     shared::Hash,
     transactions::{
-        transaction::ValidatedTransaction, transaction_input::Input, transaction_output::Output,
+        address::{AddressPayload, pubkey_hash},
+        transaction::ValidatedTransaction,
+        transaction_input::Input,
+        transaction_output::Output,
     },
 };
 
+/// Outputs spent by a block, to put them back when the block is disconnected.
+pub struct BlockUndo {
+    spent: Vec<(Input, Output)>,
+}
+
 pub struct UTXOMap {
     utxos: HashMap<Input, Output>,
+    // outpoints of the unspent outputs paying each pubkey or pubkey hash, updated with `utxos`
+    by_owner: HashMap<AddressPayload, HashSet<Input>>,
+}
+
+impl Default for UTXOMap {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl UTXOMap {
     pub fn new() -> Self {
         Self {
             utxos: HashMap::new(),
+            by_owner: HashMap::new(),
         }
     }
     pub fn update_transaction(&mut self, transaction: &ValidatedTransaction) {
         self.remove_utxos(transaction.inputs());
         self.add_utxos(transaction.outputs(), transaction.get_hash());
     }
+    pub fn connect_block(&mut self, transactions: &[ValidatedTransaction]) -> BlockUndo {
+        let mut spent = Vec::new();
+        for transaction in transactions {
+            spent.extend(self.remove_utxos(transaction.inputs()));
+            self.add_utxos(transaction.outputs(), transaction.get_hash());
+        }
+        BlockUndo { spent }
+    }
+    /// Reverts `connect_block`, `transactions` must be the ones of the last connected block.
+    pub fn disconnect_block(&mut self, transactions: &[ValidatedTransaction], undo: BlockUndo) {
+        // the spent outputs first: the ones created in the block are then removed with the rest
+        for (input, output) in undo.spent {
+            self.insert(input, output);
+        }
+        for transaction in transactions.iter().rev() {
+            for tx_output_idx in 0..transaction.outputs().len() {
+                self.remove(&Input::new(*transaction.get_hash(), tx_output_idx));
+            }
+        }
+    }
     pub fn try_find_matching_output(&self, input: &Input) -> Option<&Output> {
         self.utxos.get(input)
     }
-    fn remove_utxos(&mut self, inputs: &[Input]) {
-        for input in inputs {
-            self.utxos.remove(input);
-        }
+    /// Every unspent output with its outpoint, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Input, &Output)> {
+        self.utxos.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+    /// Unspent outputs paying `pubkey`, directly or by its hash, in no particular order.
+    pub fn unspent_of<'a>(
+        &'a self,
+        pubkey: &VerifyingKey,
+    ) -> impl Iterator<Item = (&'a Input, &'a Output)> + use<'a> {
+        self.unspent_to(&AddressPayload::PubKey(*pubkey))
+            .chain(self.unspent_to(&AddressPayload::PubKeyHash(pubkey_hash(pubkey))))
+    }
+    /// Unspent outputs paying exactly `payee`, in no particular order.
+    pub fn unspent_to<'a>(
+        &'a self,
+        payee: &AddressPayload,
+    ) -> impl Iterator<Item = (&'a Input, &'a Output)> + use<'a> {
+        self.by_owner
+            .get(payee)
+            .into_iter()
+            .flatten()
+            .map(|input| (input, &self.utxos[input]))
+    }
+    /// Total of the unspent outputs paying `pubkey`.
+    pub fn balance_of(&self, pubkey: &VerifyingKey) -> u64 {
+        self.unspent_of(pubkey)
+            .map(|(_, output)| output.get_amount())
+            .sum()
+    }
+    fn remove_utxos(&mut self, inputs: &[Input]) -> Vec<(Input, Output)> {
+        inputs
+            .iter()
+            .filter_map(|input| self.remove(input).map(|output| (*input, output)))
+            .collect()
     }
     fn add_utxos(&mut self, outputs: &[Output], tx_id: &Hash) {
         for (tx_output_idx, output) in outputs.iter().enumerate() {
-            let utxo_key = Input::new(tx_id.clone(), tx_output_idx);
-            self.utxos.insert(utxo_key, output.clone());
+            let utxo_key = Input::new(*tx_id, tx_output_idx);
+            self.insert(utxo_key, output.clone());
+        }
+    }
+    fn insert(&mut self, input: Input, output: Output) {
+        let payee = *output.get_payee();
+        if let Some(replaced) = self.utxos.insert(input, output) {
+            self.remove_owner(&input, replaced.get_payee());
+        }
+        self.by_owner.entry(payee).or_default().insert(input);
+    }
+    fn remove(&mut self, input: &Input) -> Option<Output> {
+        let output = self.utxos.remove(input)?;
+        self.remove_owner(input, output.get_payee());
+        Some(output)
+    }
+    fn remove_owner(&mut self, input: &Input, payee: &AddressPayload) {
+        if let Some(inputs) = self.by_owner.get_mut(payee) {
+            inputs.remove(input);
+            if inputs.is_empty() {
+                self.by_owner.remove(payee);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+    use super::*;
+    use crate::{
+        test_utils::signing_key,
+        transactions::transaction::{RawTransaction, StatelessChecks},
+    };
+
+    type Scan = HashMap<Input, Output>;
+
+    // unspent outputs found by going through every transaction of `blocks`
+    fn scan(blocks: &[(Vec<ValidatedTransaction>, BlockUndo)]) -> Scan {
+        let mut unspent = Scan::new();
+        for transaction in blocks.iter().flat_map(|(transactions, _)| transactions) {
+            for input in transaction.inputs() {
+                unspent.remove(input);
+            }
+            for (index, output) in transaction.outputs().iter().enumerate() {
+                unspent.insert(Input::new(*transaction.get_hash(), index), output.clone());
+            }
+        }
+        unspent
+    }
+
+    fn assert_matches_scan(map: &UTXOMap, scan: &Scan, keys: &[VerifyingKey]) {
+        let encoded = |utxos: &Scan| -> HashMap<Input, [u8; Output::SERIALIZED_SIZE]> {
+            utxos
+                .iter()
+                .map(|(input, output)| (*input, output.to_bytes()))
+                .collect()
+        };
+        assert_eq!(encoded(&map.utxos), encoded(scan));
+        // each outpoint is indexed once, under its payee
+        let indexed: usize = map.by_owner.values().map(HashSet::len).sum();
+        assert_eq!(indexed, scan.len());
+        for (payee, inputs) in &map.by_owner {
+            assert!(!inputs.is_empty());
+            for input in inputs {
+                assert_eq!(scan[input].get_payee(), payee);
+            }
+        }
+        for key in keys {
+            let owned: HashSet<Input> = scan
+                .iter()
+                .filter(|(_, output)| output.is_owned_by(key))
+                .map(|(input, _)| *input)
+                .collect();
+            let unspent: HashSet<Input> = map.unspent_of(key).map(|(input, _)| *input).collect();
+            assert_eq!(unspent, owned);
+            let balance: u64 = owned.iter().map(|input| scan[input].get_amount()).sum();
+            assert_eq!(map.balance_of(key), balance);
+        }
+    }
+
+    fn random_output(rng: &mut StdRng, keys: &[VerifyingKey], amount: u64) -> Output {
+        let key = keys.choose(rng).unwrap();
+        if rng.gen_bool(0.5) {
+            Output::new(*key, amount)
+        } else {
+            Output::new_pubkey_hash(pubkey_hash(key), amount)
+        }
+    }
+
+    // a block of a coinbase and transactions spending coins of `unspent`, also the ones
+    // created earlier in the block
+    fn random_block(
+        rng: &mut StdRng,
+        keys: &[VerifyingKey],
+        mut unspent: Scan,
+        height: usize,
+    ) -> Vec<ValidatedTransaction> {
+        let outputs = (0..rng.gen_range(1..=3))
+            .map(|_| {
+                let amount = rng.gen_range(1..=1_000);
+                random_output(rng, keys, amount)
+            })
+            .collect();
+        let coinbase = RawTransaction::new(vec![Input::coinbase(height)], outputs, keys[0])
+            .sign(&signing_key(1));
+        let checks = StatelessChecks::run(&coinbase);
+        let mut transactions = vec![
+            ValidatedTransaction::validate_coinbase_with_checks(coinbase, checks, height).unwrap(),
+        ];
+
+        for _ in 0..rng.gen_range(0..6) {
+            let signer = rng.gen_range(0..keys.len());
+            let mut coins: Vec<Input> = unspent
+                .iter()
+                .filter(|(_, output)| output.is_owned_by(&keys[signer]))
+                .map(|(input, _)| *input)
+                .collect();
+            if coins.is_empty() {
+                continue;
+            }
+            // in the same order at each run, the map has none
+            coins.sort_unstable_by_key(|input| (*input.get_tx_id(), input.get_tx_idx()));
+            coins.shuffle(rng);
+            coins.truncate(rng.gen_range(1..=3));
+            let total: u64 = coins.iter().map(|input| unspent[input].get_amount()).sum();
+            let fee = rng.gen_range(0..=total / 10);
+            let outputs = if total == fee {
+                vec![]
+            } else {
+                let split = rng.gen_range(0..=total - fee);
+                [split, total - fee - split]
+                    .into_iter()
+                    .filter(|amount| *amount > 0)
+                    .map(|amount| random_output(rng, keys, amount))
+                    .collect()
+            };
+            let transaction = RawTransaction::new(coins, outputs, keys[signer])
+                .sign(&signing_key(signer as u8 + 1));
+            let mut index = UTXOMap::new();
+            for (input, output) in &unspent {
+                index.insert(*input, output.clone());
+            }
+            let transaction = ValidatedTransaction::validate(transaction, &index).unwrap();
+            for input in transaction.inputs() {
+                unspent.remove(input);
+            }
+            for (index, output) in transaction.outputs().iter().enumerate() {
+                unspent.insert(Input::new(*transaction.get_hash(), index), output.clone());
+            }
+            transactions.push(transaction);
+        }
+        transactions
+    }
+
+    #[test]
+    fn the_index_matches_a_full_scan_through_connections_and_disconnections() {
+        let keys: Vec<VerifyingKey> = (1..=6)
+            .map(|seed| signing_key(seed).verifying_key())
+            .collect();
+        let mut rng = StdRng::seed_from_u64(44);
+        let mut map = UTXOMap::new();
+        let mut blocks: Vec<(Vec<ValidatedTransaction>, BlockUndo)> = vec![];
+        let mut disconnected_count = 0;
+        let mut connected_transaction_count = 0;
+        for height in 0..300 {
+            if !blocks.is_empty() && rng.gen_bool(0.3) {
+                for _ in 0..rng.gen_range(1..=3.min(blocks.len())) {
+                    let (transactions, undo) = blocks.pop().unwrap();
+                    map.disconnect_block(&transactions, undo);
+                    disconnected_count += 1;
+                }
+            } else {
+                // the height only grows, so that every coinbase is new
+                let transactions = random_block(&mut rng, &keys, scan(&blocks), height);
+                connected_transaction_count += transactions.len();
+                let undo = map.connect_block(&transactions);
+                blocks.push((transactions, undo));
+            }
+            assert_matches_scan(&map, &scan(&blocks), &keys);
         }
+        assert!(disconnected_count > 50);
+        assert!(connected_transaction_count > 300);
     }
 }
@@ -0,0 +1,153 @@
+//! Choice of the coins spent by a payment. Values are effective values: the amount of a
+//! coin minus the fee of spending it.
+
+// explored branches before branch and bound gives up
+const BRANCH_AND_BOUND_MAX_TRIES: usize = 100_000;
+
+/// Indexes of the `values` adding up to between `target` and `target + tolerance`,
+/// the closest to `target` found. The tolerance is what a change output would cost: an
+/// excess below it is better left to the fee. None when the search finds no such set.
+pub fn branch_and_bound(values: &[u64], target: u64, tolerance: u64) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_unstable_by(|a, b| values[*b].cmp(&values[*a]));
+    let value_at = |position: usize| values[order[position]] as u128;
+    let target = target as u128;
+    let upper_bound = target + tolerance as u128;
+
+    // positions in `order` of the selected values, the values before `position` not
+    // selected are left out, the ones from it are still to decide and sum to `available`
+    let mut selection: Vec<usize> = Vec::new();
+    let mut position = 0;
+    let mut sum = 0u128;
+    let mut available: u128 = values.iter().map(|value| *value as u128).sum();
+    let mut best: Option<(u128, Vec<usize>)> = None;
+    for _ in 0..BRANCH_AND_BOUND_MAX_TRIES {
+        let backtrack = if sum + available < target || sum > upper_bound {
+            true
+        } else if sum >= target {
+            let excess = sum - target;
+            if best
+                .as_ref()
+                .is_none_or(|(best_excess, _)| excess < *best_excess)
+            {
+                best = Some((excess, selection.clone()));
+                if excess == 0 {
+                    break;
+                }
+            }
+            true
+        } else {
+            false
+        };
+
+        if backtrack {
+            // leave out the last selected value and go on with the ones after it
+            let Some(last) = selection.pop() else {
+                break;
+            };
+            while position > last + 1 {
+                position -= 1;
+                available += value_at(position);
+            }
+            sum -= value_at(last);
+        } else {
+            let value = value_at(position);
+            available -= value;
+            // selecting a value equal to the previous one, left out, repeats a branch done
+            let repeated = position > 0
+                && value_at(position - 1) == value
+                && selection.last() != Some(&(position - 1));
+            if !repeated {
+                selection.push(position);
+                sum += value;
+            }
+            position += 1;
+        }
+    }
+    best.map(|(_, selection)| {
+        selection
+            .into_iter()
+            .map(|position| order[position])
+            .collect()
+    })
+}
+
+/// Indexes of the largest `values` until they reach `target`, at most `max_count` of them.
+/// None when they don't.
+pub fn largest_first(values: &[u64], target: u64, max_count: usize) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_unstable_by(|a, b| values[*b].cmp(&values[*a]));
+    let mut sum = 0u128;
+    let mut selection = Vec::new();
+    for index in order.into_iter().take(max_count) {
+        if sum >= target as u128 {
+            break;
+        }
+        sum += values[index] as u128;
+        selection.push(index);
+    }
+    (sum >= target as u128).then_some(selection)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    // smallest excess over `target` within the tolerance of any subset of `values`
+    fn best_excess(values: &[u64], target: u64, tolerance: u64) -> Option<u64> {
+        (0u32..1 << values.len())
+            .map(|subset| {
+                (0..values.len())
+                    .filter(|index| subset >> index & 1 == 1)
+                    .map(|index| values[index])
+                    .sum::<u64>()
+            })
+            .filter(|sum| *sum >= target && *sum - target <= tolerance)
+            .map(|sum| sum - target)
+            .min()
+    }
+
+    #[test]
+    fn branch_and_bound_finds_the_closest_set_of_a_brute_force_search() {
+        let mut rng = StdRng::seed_from_u64(43);
+        let mut found = 0;
+        for _ in 0..400 {
+            let count = rng.gen_range(0..=12);
+            // few distinct values in some rounds, to have repeated values
+            let max_value = [4, 100, 10_000][rng.gen_range(0..3)];
+            let values: Vec<u64> = (0..count).map(|_| rng.gen_range(1..=max_value)).collect();
+            let total: u64 = values.iter().sum();
+            let target = rng.gen_range(1..=total + 2);
+            let tolerance = rng.gen_range(0..=max_value / 4);
+
+            let selection = branch_and_bound(&values, target, tolerance);
+            let Some(best) = best_excess(&values, target, tolerance) else {
+                assert_eq!(selection, None, "{values:?} {target} {tolerance}");
+                continue;
+            };
+            let selection = selection.expect("a set within the tolerance exists");
+            let mut indexes = selection.clone();
+            indexes.sort_unstable();
+            indexes.dedup();
+            assert_eq!(indexes.len(), selection.len());
+            let sum: u64 = selection.iter().map(|index| values[*index]).sum();
+            assert_eq!(sum - target, best, "{values:?} {target} {tolerance}");
+            found += 1;
+        }
+        assert!(found > 100);
+    }
+
+    #[test]
+    fn largest_first_takes_the_largest_values() {
+        let values = [5, 1, 9, 3];
+        assert_eq!(largest_first(&values, 10, 4), Some(vec![2, 0]));
+        assert_eq!(largest_first(&values, 9, 4), Some(vec![2]));
+        assert_eq!(largest_first(&values, 18, 4), Some(vec![2, 0, 3, 1]));
+        assert_eq!(largest_first(&values, 19, 4), None);
+        // the count is capped
+        assert_eq!(largest_first(&values, 15, 2), None);
+        assert_eq!(largest_first(&[], 1, 4), None);
+    }
+}
@@ -0,0 +1,207 @@
+//! Deterministic derivation of ed25519 keys from a seed, following SLIP-10.
+//! Ed25519 only has hardened derivation, so every index of a path is hardened.
+use std::{fmt, str::FromStr};
+
+use ed25519_dalek::SigningKey;
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use zeroize::Zeroizing;
+
+pub const HARDENED: u32 = 1 << 31;
+const SEED_KEY: &[u8] = b"ed25519 seed";
+
+#[derive(Clone)]
+pub struct ExtendedKey {
+    key: Zeroizing<[u8; 32]>,
+    chain_code: [u8; 32],
+}
+impl ExtendedKey {
+    pub fn from_seed(seed: &[u8]) -> Self {
+        Self::from_hmac(SEED_KEY, &[seed])
+    }
+    // left half of HMAC-SHA512(key, data) is the private key, right half the chain code
+    fn from_hmac(key: &[u8], data: &[&[u8]]) -> Self {
+        let mut mac = Hmac::<Sha512>::new_from_slice(key).expect("HMAC accepts any key size");
+        for part in data {
+            mac.update(part);
+        }
+        let output = Zeroizing::new(<[u8; 64]>::from(mac.finalize().into_bytes()));
+        let mut extended_key = Self {
+            key: Zeroizing::new([0; 32]),
+            chain_code: [0; 32],
+        };
+        extended_key.key.copy_from_slice(&output[..32]);
+        extended_key.chain_code.copy_from_slice(&output[32..]);
+        extended_key
+    }
+    /// Hardened child `index` (`HARDENED` is added when missing).
+    pub fn derive_child(&self, index: u32) -> Self {
+        let index = index | HARDENED;
+        Self::from_hmac(
+            &self.chain_code,
+            &[&[0], self.key.as_ref(), &index.to_be_bytes()],
+        )
+    }
+    pub fn derive_path(&self, path: &DerivationPath) -> Self {
+        path.0
+            .iter()
+            .fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+    pub fn get_chain_code(&self) -> &[u8; 32] {
+        &self.chain_code
+    }
+    pub fn signing_key(&self) -> SigningKey {
+        SigningKey::from_bytes(&self.key)
+    }
+}
+
+/// Path of hardened indexes from the seed, written `m/44'/1'/0'`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DerivationPath(Vec<u32>);
+impl DerivationPath {
+    pub fn new(indexes: impl IntoIterator<Item = u32>) -> Self {
+        Self(indexes.into_iter().map(|index| index | HARDENED).collect())
+    }
+    pub fn child(&self, index: u32) -> Self {
+        let mut indexes = self.0.clone();
+        indexes.push(index | HARDENED);
+        Self(indexes)
+    }
+}
+impl FromStr for DerivationPath {
+    type Err = DerivationPathError;
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let mut parts = path.split('/');
+        if parts.next() != Some("m") {
+            return Err(DerivationPathError::MissingRoot);
+        }
+        parts
+            .map(|part| {
+                let index = part
+                    .strip_suffix(['\'', 'H', 'h'])
+                    .ok_or(DerivationPathError::NotHardened(part.to_string()))?;
+                match index.parse::<u32>() {
+                    Ok(index) if index < HARDENED => Ok(index | HARDENED),
+                    _ => Err(DerivationPathError::InvalidIndex(part.to_string())),
+                }
+            })
+            .collect::<Result<Vec<u32>, _>>()
+            .map(Self)
+    }
+}
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for index in &self.0 {
+            write!(f, "/{}'", index & !HARDENED)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DerivationPathError {
+    MissingRoot,
+    /// Ed25519 keys can only be derived with hardened indexes.
+    NotHardened(String),
+    InvalidIndex(String),
+}
+impl fmt::Display for DerivationPathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingRoot => write!(f, "a derivation path starts with m"),
+            Self::NotHardened(part) => write!(f, "index {part} is not hardened"),
+            Self::InvalidIndex(part) => write!(f, "invalid index {part}"),
+        }
+    }
+}
+impl std::error::Error for DerivationPathError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::from_hex;
+
+    // SLIP-10 test vector 1 for ed25519: path, chain code, private key, public key
+    const VECTOR_1: [(&str, &str, &str, &str); 6] = [
+        (
+            "m",
+            "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb",
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7",
+            "a4b2856bfec510abab89753fac1ac0e1112364e7d250545963f135f2a33188ed",
+        ),
+        (
+            "m/0'",
+            "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69",
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3",
+            "8c8a13df77a28f3445213a0f432fde644acaa215fc72dcdf300d5efaa85d350c",
+        ),
+        (
+            "m/0'/1'",
+            "a320425f77d1b5c2505a6b1b27382b37368ee640e3557c315416801243552f14",
+            "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2",
+            "1932a5270f335bed617d5b935c80aedb1a35bd9fc1e31acafd5372c30f5c1187",
+        ),
+        (
+            "m/0'/1'/2'",
+            "2e69929e00b5ab250f49c3fb1c12f252de4fed2c1db88387094a0f8c4c9ccd6c",
+            "92a5b23c0b8a99e37d07df3fb9966917f5d06e02ddbd909c7e184371463e9fc9",
+            "ae98736566d30ed0e9d2f4486a64bc95740d89c7db33f52121f8ea8f76ff0fc1",
+        ),
+        (
+            "m/0'/1'/2'/2'",
+            "8f6d87f93d750e0efccda017d662a1b31a266e4a6f5993b15f5c1f07f74dd5cc",
+            "30d1dc7e5fc04c31219ab25a27ae00b50f6fd66622f6e9c913253d6511d1e662",
+            "8abae2d66361c879b900d204ad2cc4984fa2aa344dd7ddc46007329ac76c429c",
+        ),
+        (
+            "m/0'/1'/2'/2'/1000000000'",
+            "68789923a0cac2cd5a29172a475fe9e0fb14cd6adb5ad98a3fa70333e7afa230",
+            "8f94d394a8e8fd6b1bc2f3f49f5c47e385281d5c17e65324b0f62483e37e8793",
+            "3c24da049451555d51a7014a37337aa4e12d41e485abccfa46b47dfb2af54b7a",
+        ),
+    ];
+
+    #[test]
+    fn slip10_test_vector_1() {
+        let master = ExtendedKey::from_seed(&from_hex("000102030405060708090a0b0c0d0e0f"));
+        for (path, chain_code, private_key, public_key) in VECTOR_1 {
+            let path: DerivationPath = path.parse().unwrap();
+            let key = master.derive_path(&path);
+            assert_eq!(key.get_chain_code()[..], from_hex(chain_code), "{path}");
+            let signing_key = key.signing_key();
+            assert_eq!(signing_key.as_bytes()[..], from_hex(private_key), "{path}");
+            assert_eq!(
+                signing_key.verifying_key().as_bytes()[..],
+                from_hex(public_key),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn paths_are_hardened_and_rooted() {
+        let path: DerivationPath = "m/44'/1H/0h".parse().unwrap();
+        assert_eq!(path, DerivationPath::new([44, 1, 0]));
+        assert_eq!(path.to_string(), "m/44'/1'/0'");
+        assert_eq!(path.child(7), DerivationPath::new([44, 1, 0, 7]));
+        assert_eq!("m".parse(), Ok(DerivationPath::new([])));
+
+        assert_eq!(
+            "44'/1'".parse::<DerivationPath>(),
+            Err(DerivationPathError::MissingRoot)
+        );
+        assert_eq!(
+            "m/44'/1".parse::<DerivationPath>(),
+            Err(DerivationPathError::NotHardened("1".to_string()))
+        );
+        assert_eq!(
+            "m/2147483648'".parse::<DerivationPath>(),
+            Err(DerivationPathError::InvalidIndex("2147483648'".to_string()))
+        );
+        assert_eq!(
+            "m/x'".parse::<DerivationPath>(),
+            Err(DerivationPathError::InvalidIndex("x'".to_string()))
+        );
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::{HashMap, HashSet};
+
+use ed25519_dalek::VerifyingKey;
+
+use crate::{
+    block_chain::BlockChain,
+    shared::Hash,
+    transactions::{
+        address::{AddressPayload, pubkey_hash},
+        transaction::ValidatedTransaction,
+        transaction_input::Input,
+    },
+    wallets::{wallet::Wallet, watch_only::WatchOnlyWallet},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Only pays the wallet, coinbases included.
+    Incoming,
+    /// Spends coins of the wallet, its change comes back as received.
+    Outgoing,
+}
+
+/// A transaction paying or spending from the wallet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HistoryEntry {
+    tx_id: Hash,
+    block_hash: Hash,
+    height: usize,
+    direction: Direction,
+    received: u64,
+    sent: u64,
+    fee: Option<u64>,
+}
+impl HistoryEntry {
+    pub fn get_tx_id(&self) -> &Hash {
+        &self.tx_id
+    }
+    pub fn get_block_hash(&self) -> &Hash {
+        &self.block_hash
+    }
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+    pub fn get_direction(&self) -> Direction {
+        self.direction
+    }
+    /// Total of the outputs paying the wallet.
+    pub fn get_received(&self) -> u64 {
+        self.received
+    }
+    /// Total of the wallet coins spent, fee included.
+    pub fn get_sent(&self) -> u64 {
+        self.sent
+    }
+    /// Fee paid by the wallet, None when other keys spent inputs of the transaction too.
+    pub fn get_fee(&self) -> Option<u64> {
+        self.fee
+    }
+    /// What the transaction changed to the balance of the wallet.
+    pub fn net_amount(&self) -> i128 {
+        self.received as i128 - self.sent as i128
+    }
+    /// Blocks on top of the transaction, its own included, 0 once its block left the chain.
+    pub fn confirmations(&self, chain: &BlockChain) -> usize {
+        match chain.get_block(self.height) {
+            Some(block) if block.get_hash() == &self.block_hash => chain.len() - self.height,
+            _ => 0,
+        }
+    }
+}
+
+/// Transactions of a wallet found in the chain, oldest first.
+#[derive(Default)]
+pub struct WalletHistory {
+    entries: Vec<HistoryEntry>,
+}
+impl WalletHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn get_entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+    pub fn get_entry(&self, tx_id: &Hash) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|entry| &entry.tx_id == tx_id)
+    }
+    /// Forgets the transactions from `from_height` and walks the blocks from there to the
+    /// tip of `chain` looking for the transactions of the keys of `wallet`. A wallet
+    /// restored from a backup rescans from 0.
+    pub fn rescan(&mut self, wallet: &Wallet, chain: &BlockChain, from_height: usize) {
+        self.rescan_payees(&payees_of(&wallet.get_pubkeys()), chain, from_height);
+    }
+    /// Same as `rescan` for the pubkeys and addresses watched by `wallet`.
+    pub fn rescan_watch_only(
+        &mut self,
+        wallet: &WatchOnlyWallet,
+        chain: &BlockChain,
+        from_height: usize,
+    ) {
+        self.rescan_payees(&wallet.get_payees(), chain, from_height);
+    }
+    fn rescan_payees(
+        &mut self,
+        payees: &HashSet<AddressPayload>,
+        chain: &BlockChain,
+        from_height: usize,
+    ) {
+        self.entries.retain(|entry| entry.height < from_height);
+        // the wallet coins created during the scan, the older ones are looked up in the chain
+        let mut coins: HashMap<Input, u64> = HashMap::new();
+        for height in from_height..chain.len() {
+            let Some(block) = chain.get_block(height) else {
+                break;
+            };
+            for (tx_index, transaction) in block.get_transactions().iter().enumerate() {
+                let spent = if tx_index == 0 {
+                    0
+                } else {
+                    wallet_spent(payees, transaction, &mut coins, chain)
+                };
+                for (output_index, output) in transaction.outputs().iter().enumerate() {
+                    if payees.contains(output.get_payee()) {
+                        coins.insert(
+                            Input::new(*transaction.get_hash(), output_index),
+                            output.get_amount(),
+                        );
+                    }
+                }
+                if let Some(entry) =
+                    wallet_entry(payees, transaction, spent, height, block.get_hash())
+                {
+                    self.entries.push(entry);
+                }
+            }
+        }
+    }
+}
+
+/// What pays the keys, directly or by hash.
+pub fn payees_of(pubkeys: &[VerifyingKey]) -> HashSet<AddressPayload> {
+    pubkeys
+        .iter()
+        .flat_map(|pubkey| {
+            [
+                AddressPayload::PubKey(*pubkey),
+                AddressPayload::PubKeyHash(pubkey_hash(pubkey)),
+            ]
+        })
+        .collect()
+}
+
+// total of the wallet coins spent by `transaction`, only a transaction signed by a wallet
+// key can spend some
+fn wallet_spent(
+    payees: &HashSet<AddressPayload>,
+    transaction: &ValidatedTransaction,
+    coins: &mut HashMap<Input, u64>,
+    chain: &BlockChain,
+) -> u64 {
+    let is_signer = transaction.get_signed().get_signers().iter().any(|signer| {
+        payees.contains(&AddressPayload::PubKey(*signer))
+            || payees.contains(&AddressPayload::PubKeyHash(pubkey_hash(signer)))
+    });
+    if !is_signer {
+        return 0;
+    }
+    transaction
+        .inputs()
+        .iter()
+        .filter_map(|input| {
+            coins.remove(input).or_else(|| {
+                let (block, tx_index) = chain.find_transaction(input.get_tx_id())?;
+                let output = block.get_transactions()[tx_index]
+                    .outputs()
+                    .get(input.get_tx_idx())?;
+                payees
+                    .contains(output.get_payee())
+                    .then(|| output.get_amount())
+            })
+        })
+        .sum()
+}
+
+// the wallet sent the transaction when it spent some of its coins, it then paid the whole
+// fee if all the inputs were its own
+fn wallet_entry(
+    payees: &HashSet<AddressPayload>,
+    transaction: &ValidatedTransaction,
+    spent: u64,
+    height: usize,
+    block_hash: &Hash,
+) -> Option<HistoryEntry> {
+    let received = transaction
+        .outputs()
+        .iter()
+        .filter(|output| payees.contains(output.get_payee()))
+        .map(|output| output.get_amount())
+        .sum();
+    let (direction, sent, fee) = if spent > 0 {
+        let total_output: u64 = transaction
+            .outputs()
+            .iter()
+            .map(|output| output.get_amount())
+            .sum();
+        let fee = transaction.get_fee();
+        // the other signers spent the rest of the inputs
+        let fee = (spent == total_output + fee).then_some(fee);
+        (Direction::Outgoing, spent, fee)
+    } else if received > 0 {
+        (Direction::Incoming, 0, None)
+    } else {
+        return None;
+    };
+    Some(HistoryEntry {
+        tx_id: *transaction.get_hash(),
+        block_hash: *block_hash,
+        height,
+        direction,
+        received,
+        sent,
+        fee,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{SigningKey, ed25519::signature::Signer};
+
+    use super::*;
+    use crate::{
+        test_utils::{add_block, extend, regtest_chain, signing_key},
+        transactions::{
+            transaction::{RawTransaction, SignedTransaction},
+            transaction_output::Output,
+        },
+    };
+
+    fn coin_of(chain: &BlockChain, block_hash: &Hash, tx_index: usize, output: usize) -> Input {
+        let block = chain.get_block_by_hash(block_hash).unwrap();
+        Input::new(*block.get_transactions()[tx_index].get_hash(), output)
+    }
+
+    fn sign_by(raw: RawTransaction, keys: &[&SigningKey]) -> SignedTransaction {
+        let signatures = keys.iter().map(|key| key.sign(&raw.hash())).collect();
+        SignedTransaction::with_signatures(raw, signatures)
+    }
+
+    fn entry(
+        tx_id: Hash,
+        block_hash: Hash,
+        height: usize,
+        (received, sent, fee): (u64, u64, Option<u64>),
+    ) -> HistoryEntry {
+        HistoryEntry {
+            tx_id,
+            block_hash,
+            height,
+            direction: if sent > 0 {
+                Direction::Outgoing
+            } else {
+                Direction::Incoming
+            },
+            received,
+            sent,
+            fee,
+        }
+    }
+
+    fn net_total(history: &WalletHistory) -> i128 {
+        history
+            .get_entries()
+            .iter()
+            .map(HistoryEntry::net_amount)
+            .sum()
+    }
+
+    #[test]
+    fn a_rescan_reproduces_the_ledger_of_the_wallet() {
+        let (first, second, other) = (signing_key(1), signing_key(2), signing_key(3));
+        let stranger = signing_key(9).verifying_key();
+        let mut wallet = Wallet::new();
+        wallet.add_key(first.clone());
+        wallet.add_key(second.clone());
+        let mut chain = regtest_chain();
+        let subsidy = chain.get_coin_base_amount();
+        let mut expected = vec![];
+
+        // 0: mined by the wallet
+        let block = add_block(&mut chain, &first, vec![]);
+        let coinbase = coin_of(&chain, &block, 0, 0);
+        expected.push(entry(*coinbase.get_tx_id(), block, 0, (subsidy, 0, None)));
+        let block = add_block(&mut chain, &other, vec![]);
+        let other_coinbase = coin_of(&chain, &block, 0, 0);
+
+        // 2: pays the stranger, the change goes to the hash of the second key
+        let change = subsidy - 100 - 10;
+        let payment = RawTransaction::new(
+            vec![coinbase],
+            vec![
+                Output::new(stranger, 100),
+                Output::new_pubkey_hash(pubkey_hash(&second.verifying_key()), change),
+            ],
+            first.verifying_key(),
+        )
+        .sign(&first);
+        let block = add_block(&mut chain, &other, vec![payment.clone()]);
+        expected.push(entry(
+            *payment.get_hash(),
+            block,
+            2,
+            (change, subsidy, Some(10)),
+        ));
+        let change = coin_of(&chain, &block, 1, 1);
+
+        // 3: paid by another key, next to a transaction of others only
+        let received = RawTransaction::new(
+            vec![other_coinbase],
+            vec![
+                Output::new(second.verifying_key(), 500),
+                Output::new(other.verifying_key(), subsidy - 505),
+            ],
+            other.verifying_key(),
+        )
+        .sign(&other);
+        let block = add_block(&mut chain, &other, vec![received.clone()]);
+        expected.push(entry(*received.get_hash(), block, 3, (500, 0, None)));
+        let others_change = coin_of(&chain, &block, 1, 1);
+        let unrelated = RawTransaction::new(
+            vec![coin_of(&chain, &block, 0, 0)],
+            vec![Output::new(stranger, subsidy + 5)],
+            other.verifying_key(),
+        )
+        .sign(&other);
+
+        // 4: spent together with another key, so the fee is not only the wallet's
+        let shared = sign_by(
+            RawTransaction::with_signers(
+                vec![change, others_change],
+                vec![Output::new(stranger, 2 * subsidy - 615 - 20)],
+                vec![other.verifying_key(), second.verifying_key()],
+            ),
+            &[&other, &second],
+        );
+        let block = add_block(&mut chain, &other, vec![unrelated, shared.clone()]);
+        expected.push(entry(
+            *shared.get_hash(),
+            block,
+            4,
+            (0, subsidy - 110, None),
+        ));
+
+        // 5: mined by the wallet, then confirmed twice
+        let block = add_block(&mut chain, &second, vec![]);
+        let coinbase = coin_of(&chain, &block, 0, 0);
+        expected.push(entry(*coinbase.get_tx_id(), block, 5, (subsidy, 0, None)));
+        extend(&mut chain, 2, &other);
+
+        let mut history = WalletHistory::new();
+        history.rescan(&wallet, &chain, 0);
+        assert_eq!(history.get_entries(), expected);
+        assert_eq!(
+            net_total(&history),
+            wallet.balance(chain.get_utxos()) as i128
+        );
+        let nets: Vec<i128> = expected.iter().map(HistoryEntry::net_amount).collect();
+        assert_eq!(
+            nets,
+            [
+                subsidy as i128,
+                -110,
+                500,
+                110 - subsidy as i128,
+                subsidy as i128
+            ]
+        );
+        let confirmations: Vec<usize> = expected
+            .iter()
+            .map(|entry| entry.confirmations(&chain))
+            .collect();
+        assert_eq!(confirmations, [8, 6, 5, 4, 3]);
+        assert_eq!(history.get_entry(shared.get_hash()), Some(&expected[3]));
+
+        // the coins created before the height of a rescan are looked up in the chain
+        let mut partial = WalletHistory::new();
+        partial.rescan(&wallet, &chain, 3);
+        assert_eq!(partial.get_entries(), &expected[2..]);
+        history.rescan(&wallet, &chain, 3);
+        assert_eq!(history.get_entries(), expected);
+        let mut watched = WalletHistory::new();
+        watched.rescan_watch_only(&wallet.to_watch_only(), &chain, 0);
+        assert_eq!(watched.get_entries(), expected);
+
+        // the blocks on top of the fourth leave the chain, then come back
+        let mut disconnected: Vec<_> = (0..3).map_while(|_| chain.disconnect_tip()).collect();
+        assert_eq!(expected[4].confirmations(&chain), 0);
+        assert_eq!(expected[0].confirmations(&chain), 5);
+        history.rescan(&wallet, &chain, 5);
+        assert_eq!(history.get_entries(), &expected[..4]);
+        assert_eq!(
+            net_total(&history),
+            wallet.balance(chain.get_utxos()) as i128
+        );
+        assert_eq!(net_total(&history), 500);
+        while let Some(block) = disconnected.pop() {
+            chain.update(block);
+        }
+        history.rescan(&wallet, &chain, 5);
+        assert_eq!(history.get_entries(), expected);
+        assert_eq!(expected[4].confirmations(&chain), 3);
+    }
+}
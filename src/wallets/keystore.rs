@@ -0,0 +1,225 @@
+//! Password encrypted storage of the wallet secrets.
+//!
+//! File layout: magic "CKS" | format version (u8) | argon2id memory cost, iterations and
+//! parallelism (u32 BE each) | salt (16 bytes) | nonce (12 bytes) | ChaCha20-Poly1305
+//! ciphertext. Everything before the ciphertext is authenticated as associated data.
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    ChaCha20Poly1305, Key, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use rand::{RngCore, rngs::OsRng};
+use zeroize::Zeroizing;
+
+const MAGIC: &[u8; 3] = b"CKS";
+pub const KEYSTORE_VERSION: u8 = 1;
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+const HEADER_SIZE: usize = MAGIC.len() + 1 + 3 * 4 + SALT_SIZE + NONCE_SIZE;
+// the costs are read before the file is authenticated, a forged file must not make the key
+// derivation take all the memory or all the time of the node
+const MAX_MEMORY_COST: u32 = 1024 * 1024; // in KiB, 1 GiB
+const MAX_TIME_COST: u32 = 64;
+const MAX_PARALLELISM: u32 = 16;
+
+/// Encrypts `plaintext` with a key derived from `password`, with a fresh salt and nonce.
+pub fn encrypt(plaintext: &[u8], password: &str) -> Result<Vec<u8>, KeystoreError> {
+    encrypt_with_params(plaintext, password, Params::default())
+}
+
+fn encrypt_with_params(
+    plaintext: &[u8],
+    password: &str,
+    params: Params,
+) -> Result<Vec<u8>, KeystoreError> {
+    let mut salt = [0u8; SALT_SIZE];
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut salt);
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + plaintext.len() + 16);
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(KEYSTORE_VERSION);
+    bytes.extend_from_slice(&params.m_cost().to_be_bytes());
+    bytes.extend_from_slice(&params.t_cost().to_be_bytes());
+    bytes.extend_from_slice(&params.p_cost().to_be_bytes());
+    bytes.extend_from_slice(&salt);
+    bytes.extend_from_slice(&nonce);
+
+    let cipher = cipher(password, &salt, params)?;
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &bytes,
+            },
+        )
+        .map_err(|_| KeystoreError::InvalidFormat)?;
+    bytes.extend_from_slice(&ciphertext);
+    Ok(bytes)
+}
+
+pub fn decrypt(bytes: &[u8], password: &str) -> Result<Zeroizing<Vec<u8>>, KeystoreError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(KeystoreError::InvalidFormat);
+    }
+    let (header, ciphertext) = bytes.split_at(HEADER_SIZE);
+    let (magic, rest) = header.split_at(MAGIC.len());
+    if magic != MAGIC {
+        return Err(KeystoreError::InvalidFormat);
+    }
+    let (version, rest) = rest.split_first().ok_or(KeystoreError::InvalidFormat)?;
+    if *version != KEYSTORE_VERSION {
+        return Err(KeystoreError::UnsupportedVersion(*version));
+    }
+    let (m_cost, rest) = read_u32(rest)?;
+    let (t_cost, rest) = read_u32(rest)?;
+    let (p_cost, rest) = read_u32(rest)?;
+    let (salt, nonce) = rest.split_at(SALT_SIZE);
+    if m_cost > MAX_MEMORY_COST || t_cost > MAX_TIME_COST || p_cost > MAX_PARALLELISM {
+        return Err(KeystoreError::CostTooHigh {
+            m_cost,
+            t_cost,
+            p_cost,
+        });
+    }
+    let params =
+        Params::new(m_cost, t_cost, p_cost, None).map_err(|_| KeystoreError::InvalidFormat)?;
+
+    let cipher = cipher(password, salt, params)?;
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        // a wrong password and a tampered file can't be told apart
+        .map_err(|_| KeystoreError::WrongPassword)?;
+    Ok(Zeroizing::new(plaintext))
+}
+
+fn read_u32(bytes: &[u8]) -> Result<(u32, &[u8]), KeystoreError> {
+    let (value, rest) = bytes
+        .split_first_chunk::<4>()
+        .ok_or(KeystoreError::InvalidFormat)?;
+    Ok((u32::from_be_bytes(*value), rest))
+}
+
+fn cipher(password: &str, salt: &[u8], params: Params) -> Result<ChaCha20Poly1305, KeystoreError> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, key.as_mut())
+        .map_err(|_| KeystoreError::InvalidFormat)?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(key.as_ref())))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeystoreError {
+    InvalidFormat,
+    UnsupportedVersion(u8),
+    /// Key derivation costs above what a keystore of ours ever asks for.
+    CostTooHigh {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+    /// Or a corrupted file.
+    WrongPassword,
+}
+impl std::fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidFormat => write!(f, "not a keystore file"),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported keystore version {version}")
+            }
+            Self::CostTooHigh {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => write!(
+                f,
+                "key derivation too costly (memory {m_cost} KiB, {t_cost} iterations, parallelism {p_cost})"
+            ),
+            Self::WrongPassword => write!(f, "wrong password or corrupted keystore"),
+        }
+    }
+}
+impl std::error::Error for KeystoreError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"seed of the wallet";
+
+    // cheap costs, the default ones take seconds without optimizations
+    fn encrypt_fast(plaintext: &[u8], password: &str) -> Vec<u8> {
+        let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+        encrypt_with_params(plaintext, password, params).unwrap()
+    }
+
+    // offset of the memory cost in the header
+    const M_COST_OFFSET: usize = MAGIC.len() + 1;
+
+    #[test]
+    fn round_trip() {
+        let bytes = encrypt_fast(SECRET, "correct horse");
+        assert_eq!(decrypt(&bytes, "correct horse").unwrap().as_slice(), SECRET);
+        // fresh salt and nonce each time
+        assert_ne!(encrypt_fast(SECRET, "correct horse"), bytes);
+    }
+
+    #[test]
+    fn wrong_password_is_rejected() {
+        let bytes = encrypt_fast(SECRET, "correct horse");
+        assert_eq!(
+            decrypt(&bytes, "battery staple"),
+            Err(KeystoreError::WrongPassword)
+        );
+    }
+
+    #[test]
+    fn tampered_files_are_rejected() {
+        let bytes = encrypt_fast(SECRET, "correct horse");
+        for index in [M_COST_OFFSET + 3, HEADER_SIZE - 1, bytes.len() - 1] {
+            let mut tampered = bytes.clone();
+            tampered[index] ^= 1;
+            assert_eq!(
+                decrypt(&tampered, "correct horse"),
+                Err(KeystoreError::WrongPassword),
+                "byte {index}"
+            );
+        }
+        assert_eq!(
+            decrypt(&bytes[..HEADER_SIZE - 1], "correct horse"),
+            Err(KeystoreError::InvalidFormat)
+        );
+        let mut wrong_version = bytes.clone();
+        wrong_version[MAGIC.len()] = KEYSTORE_VERSION + 1;
+        assert_eq!(
+            decrypt(&wrong_version, "correct horse"),
+            Err(KeystoreError::UnsupportedVersion(KEYSTORE_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn excessive_costs_are_refused_before_the_key_derivation() {
+        let bytes = encrypt_fast(SECRET, "correct horse");
+        for (offset, cost) in [
+            (M_COST_OFFSET, MAX_MEMORY_COST + 1),
+            (M_COST_OFFSET + 4, MAX_TIME_COST + 1),
+            (M_COST_OFFSET + 8, u32::MAX),
+        ] {
+            let mut forged = bytes.clone();
+            forged[offset..offset + 4].copy_from_slice(&cost.to_be_bytes());
+            assert!(matches!(
+                decrypt(&forged, "correct horse"),
+                Err(KeystoreError::CostTooHigh { .. })
+            ));
+        }
+    }
+}
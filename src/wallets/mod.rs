@@ -0,0 +1,9 @@
+pub mod coin_selection;
+pub mod hd_key;
+pub mod history;
+pub mod keystore;
+pub mod reserve_proof;
+pub mod signed_message;
+pub mod transaction_builder;
+pub mod wallet;
+pub mod watch_only;
@@ -0,0 +1,401 @@
+//! Proof that the keys of a wallet control a set of unspent outputs: every key owning one
+//! of them signs a hash of the outpoints and of a challenge message, under its own prefix so
+//! the signatures cannot be replayed as message or transaction signatures. The proof holds
+//! no amount, they are read from the unspent outputs when it is verified.
+use std::{collections::HashSet, fmt, str::FromStr};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ed25519_dalek::{Signature, VerifyingKey, ed25519::signature::Signer};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    shared::Hash, transactions::transaction_input::Input, utxo_map::UTXOMap,
+    wallets::wallet::Wallet,
+};
+
+const RESERVE_PROOF_PREFIX: &[u8] = b"Crypto Reserve Proof:\n";
+
+pub struct ReserveProof {
+    message: Vec<u8>,
+    inputs: Vec<Input>,
+    signatures: Vec<(VerifyingKey, Signature)>,
+}
+impl ReserveProof {
+    /// Signs `inputs`, which must be unspent in `utxos` and owned by keys of `wallet`.
+    pub fn create(
+        wallet: &Wallet,
+        utxos: &UTXOMap,
+        inputs: Vec<Input>,
+        message: &[u8],
+    ) -> Result<Self, ReserveProofError> {
+        check_inputs(&inputs)?;
+        let pubkeys = wallet.get_pubkeys();
+        let mut signers: Vec<VerifyingKey> = Vec::new();
+        for (input_index, input) in inputs.iter().enumerate() {
+            let output = utxos
+                .try_find_matching_output(input)
+                .ok_or(ReserveProofError::SpentOutput { input_index })?;
+            let owner = pubkeys
+                .iter()
+                .find(|pubkey| output.is_owned_by(pubkey))
+                .ok_or(ReserveProofError::ForeignOutput { input_index })?;
+            if !signers.contains(owner) {
+                signers.push(*owner);
+            }
+        }
+        let hash = proof_hash(message, &inputs);
+        let signatures = signers
+            .into_iter()
+            .map(|pubkey| {
+                let key = wallet
+                    .get_signing_key(&pubkey)
+                    .expect("the owner is a wallet key");
+                (pubkey, key.sign(&hash))
+            })
+            .collect();
+        Ok(Self {
+            message: message.to_vec(),
+            inputs,
+            signatures,
+        })
+    }
+    pub fn get_message(&self) -> &[u8] {
+        &self.message
+    }
+    pub fn get_inputs(&self) -> &[Input] {
+        &self.inputs
+    }
+    /// Checks the proof against the current unspent outputs, returns the amount proven.
+    pub fn verify(&self, utxos: &UTXOMap) -> Result<u64, ReserveProofError> {
+        check_inputs(&self.inputs)?;
+        let hash = proof_hash(&self.message, &self.inputs);
+        for (pubkey, signature) in &self.signatures {
+            pubkey
+                .verify_strict(&hash, signature)
+                .map_err(|_| ReserveProofError::InvalidSignature(pubkey.to_bytes()))?;
+        }
+        let mut used_signers = HashSet::new();
+        let mut total = 0;
+        for (input_index, input) in self.inputs.iter().enumerate() {
+            let output = utxos
+                .try_find_matching_output(input)
+                .ok_or(ReserveProofError::SpentOutput { input_index })?;
+            let (owner, _) = self
+                .signatures
+                .iter()
+                .find(|(pubkey, _)| output.is_owned_by(pubkey))
+                .ok_or(ReserveProofError::ForeignOutput { input_index })?;
+            used_signers.insert(*owner);
+            total += output.get_amount();
+        }
+        if let Some((pubkey, _)) = self
+            .signatures
+            .iter()
+            .find(|(pubkey, _)| !used_signers.contains(pubkey))
+        {
+            return Err(ReserveProofError::UnusedSignature(pubkey.to_bytes()));
+        }
+        Ok(total)
+    }
+
+    /// message length (u32 BE) | message | input count (u32 BE) | inputs | signature count
+    /// (u32 BE) | signatures, each: pubkey | signature
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(self.message.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.message);
+        bytes.extend_from_slice(&(self.inputs.len() as u32).to_be_bytes());
+        for input in &self.inputs {
+            bytes.extend_from_slice(&input.to_bytes());
+        }
+        bytes.extend_from_slice(&(self.signatures.len() as u32).to_be_bytes());
+        for (pubkey, signature) in &self.signatures {
+            bytes.extend_from_slice(pubkey.as_bytes());
+            bytes.extend_from_slice(&signature.to_bytes());
+        }
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (message_length, rest) = bytes.split_first_chunk::<4>()?;
+        let (message, rest) = rest.split_at_checked(u32::from_be_bytes(*message_length) as usize)?;
+
+        let (input_count, mut rest) = rest.split_first_chunk::<4>()?;
+        let mut inputs = Vec::new();
+        for _ in 0..u32::from_be_bytes(*input_count) {
+            let (input, after) = rest.split_first_chunk::<{ Input::SERIALIZED_SIZE }>()?;
+            inputs.push(Input::from_bytes(input)?);
+            rest = after;
+        }
+
+        let (signature_count, mut rest) = rest.split_first_chunk::<4>()?;
+        let mut signatures = Vec::new();
+        for _ in 0..u32::from_be_bytes(*signature_count) {
+            let (pubkey, after) = rest.split_first_chunk::<32>()?;
+            let (signature, after) = after.split_first_chunk::<{ Signature::BYTE_SIZE }>()?;
+            signatures.push((
+                VerifyingKey::from_bytes(pubkey).ok()?,
+                Signature::from_bytes(signature),
+            ));
+            rest = after;
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(Self {
+            message: message.to_vec(),
+            inputs,
+            signatures,
+        })
+    }
+}
+
+impl fmt::Display for ReserveProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&BASE64_STANDARD.encode(self.to_bytes()))
+    }
+}
+impl FromStr for ReserveProof {
+    type Err = ReserveProofError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        BASE64_STANDARD
+            .decode(text.trim())
+            .ok()
+            .and_then(|bytes| Self::from_bytes(&bytes))
+            .ok_or(ReserveProofError::InvalidEncoding)
+    }
+}
+
+// sha256(prefix | message length (u64 BE) | message | input count (u64 BE) | inputs)
+fn proof_hash(message: &[u8], inputs: &[Input]) -> Hash {
+    let mut hasher = Sha256::new()
+        .chain_update(RESERVE_PROOF_PREFIX)
+        .chain_update((message.len() as u64).to_be_bytes())
+        .chain_update(message)
+        .chain_update((inputs.len() as u64).to_be_bytes());
+    for input in inputs {
+        Digest::update(&mut hasher, input.to_bytes());
+    }
+    hasher.finalize().into()
+}
+
+fn check_inputs(inputs: &[Input]) -> Result<(), ReserveProofError> {
+    if inputs.is_empty() {
+        return Err(ReserveProofError::NoOutputs);
+    }
+    let mut seen = HashSet::with_capacity(inputs.len());
+    match inputs.iter().position(|input| !seen.insert(input)) {
+        Some(input_index) => Err(ReserveProofError::DuplicateOutput { input_index }),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReserveProofError {
+    InvalidEncoding,
+    NoOutputs,
+    /// The same outpoint twice, it would count twice.
+    DuplicateOutput {
+        input_index: usize,
+    },
+    /// The outpoint is spent, or never existed.
+    SpentOutput {
+        input_index: usize,
+    },
+    /// No key of the proof owns the output.
+    ForeignOutput {
+        input_index: usize,
+    },
+    InvalidSignature([u8; 32]),
+    /// Signature of a key owning none of the outputs.
+    UnusedSignature([u8; 32]),
+}
+impl fmt::Display for ReserveProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidEncoding => write!(f, "not a reserve proof"),
+            Self::NoOutputs => write!(f, "the proof covers no output"),
+            Self::DuplicateOutput { input_index } => {
+                write!(f, "output {input_index} is listed twice")
+            }
+            Self::SpentOutput { input_index } => write!(f, "output {input_index} is spent"),
+            Self::ForeignOutput { input_index } => {
+                write!(
+                    f,
+                    "output {input_index} is not owned by the keys of the proof"
+                )
+            }
+            Self::InvalidSignature(pubkey) => {
+                write!(f, "invalid signature of {}", BASE64_STANDARD.encode(pubkey))
+            }
+            Self::UnusedSignature(pubkey) => write!(
+                f,
+                "{} owns none of the outputs",
+                BASE64_STANDARD.encode(pubkey)
+            ),
+        }
+    }
+}
+impl std::error::Error for ReserveProofError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_chain::BlockChain,
+        test_utils::{add_block, chain_of, signing_key},
+        transactions::{
+            address::pubkey_hash, transaction::RawTransaction, transaction_output::Output,
+        },
+        wallets::signed_message::MessageSignature,
+    };
+
+    // outputs of 400 to the key 1, 300 to the hash of the key 2 and 200 to the key 3, the
+    // wallet holds the keys 1 and 2
+    fn funded() -> (BlockChain, Wallet, Vec<Input>) {
+        let mut chain = chain_of(1, &signing_key(1));
+        let coinbase = *chain.get_block(0).unwrap().get_transactions()[0].get_hash();
+        let funding = RawTransaction::new(
+            vec![Input::new(coinbase, 0)],
+            vec![
+                Output::new(signing_key(1).verifying_key(), 400),
+                Output::new_pubkey_hash(pubkey_hash(&signing_key(2).verifying_key()), 300),
+                Output::new(signing_key(3).verifying_key(), 200),
+            ],
+            signing_key(1).verifying_key(),
+        )
+        .sign(&signing_key(1));
+        let coins = (0..3)
+            .map(|index| Input::new(*funding.get_hash(), index))
+            .collect();
+        add_block(&mut chain, &signing_key(3), vec![funding]);
+        let mut wallet = Wallet::new();
+        wallet.add_key(signing_key(1));
+        wallet.add_key(signing_key(2));
+        (chain, wallet, coins)
+    }
+
+    #[test]
+    fn a_proof_covers_the_unspent_outputs_of_the_wallet() {
+        let (chain, wallet, coins) = funded();
+        let utxos = chain.get_utxos();
+        let proof = ReserveProof::create(&wallet, utxos, coins[..2].to_vec(), b"audit").unwrap();
+        assert_eq!(proof.signatures.len(), 2);
+        assert_eq!(proof.verify(utxos), Ok(700));
+        let decoded: ReserveProof = proof.to_string().parse().unwrap();
+        assert_eq!(decoded.to_bytes(), proof.to_bytes());
+        assert_eq!(decoded.verify(utxos), Ok(700));
+        assert_eq!(
+            "not a proof".parse::<ReserveProof>().err(),
+            Some(ReserveProofError::InvalidEncoding)
+        );
+
+        let only_hash = ReserveProof::create(&wallet, utxos, vec![coins[1]], b"audit").unwrap();
+        assert_eq!(only_hash.signatures.len(), 1);
+        assert_eq!(only_hash.verify(utxos), Ok(300));
+    }
+
+    #[test]
+    fn spent_or_foreign_outputs_are_refused() {
+        use ReserveProofError::*;
+        let (mut chain, wallet, coins) = funded();
+        let create = |chain: &BlockChain, inputs: Vec<Input>| {
+            ReserveProof::create(&wallet, chain.get_utxos(), inputs, b"audit").err()
+        };
+        let unknown = Input::new([0xee; 32], 0);
+        assert_eq!(create(&chain, vec![]), Some(NoOutputs));
+        assert_eq!(
+            create(&chain, vec![coins[0], coins[2]]),
+            Some(ForeignOutput { input_index: 1 })
+        );
+        assert_eq!(
+            create(&chain, vec![coins[0], unknown]),
+            Some(SpentOutput { input_index: 1 })
+        );
+        assert_eq!(
+            create(&chain, vec![coins[0], coins[1], coins[0]]),
+            Some(DuplicateOutput { input_index: 2 })
+        );
+
+        // a proof made by the keys of the outputs, with the foreign one listed too
+        let mut forged =
+            ReserveProof::create(&wallet, chain.get_utxos(), coins[..2].to_vec(), b"audit")
+                .unwrap();
+        forged.inputs.push(coins[2]);
+        let hash = proof_hash(&forged.message, &forged.inputs);
+        for (pubkey, signature) in &mut forged.signatures {
+            *signature = wallet.get_signing_key(pubkey).unwrap().sign(&hash);
+        }
+        assert_eq!(
+            forged.verify(chain.get_utxos()),
+            Err(ForeignOutput { input_index: 2 })
+        );
+        // with a signature of the foreign key, it is a proof of the three
+        let other = signing_key(3);
+        forged
+            .signatures
+            .push((other.verifying_key(), other.sign(&hash)));
+        assert_eq!(forged.verify(chain.get_utxos()), Ok(900));
+        // but not with a key owning none of them
+        forged.inputs.pop();
+        let hash = proof_hash(&forged.message, &forged.inputs);
+        for (pubkey, signature) in &mut forged.signatures {
+            let key = wallet.get_signing_key(pubkey).unwrap_or(&other);
+            *signature = key.sign(&hash);
+        }
+        assert_eq!(
+            forged.verify(chain.get_utxos()),
+            Err(UnusedSignature(other.verifying_key().to_bytes()))
+        );
+
+        // once an output is spent the proof no longer holds
+        let proof = ReserveProof::create(&wallet, chain.get_utxos(), coins[..2].to_vec(), b"audit")
+            .unwrap();
+        let spend = RawTransaction::new(
+            vec![coins[1]],
+            vec![Output::new(other.verifying_key(), 300)],
+            signing_key(2).verifying_key(),
+        )
+        .sign(&signing_key(2));
+        add_block(&mut chain, &other, vec![spend]);
+        assert_eq!(
+            proof.verify(chain.get_utxos()),
+            Err(SpentOutput { input_index: 1 })
+        );
+    }
+
+    #[test]
+    fn a_proof_is_bound_to_its_message_and_outputs() {
+        let (chain, wallet, coins) = funded();
+        let utxos = chain.get_utxos();
+        let proof = ReserveProof::create(&wallet, utxos, coins[..2].to_vec(), b"audit").unwrap();
+        let signer = proof.signatures[0].0.to_bytes();
+
+        let mut other_message = ReserveProof::from_bytes(&proof.to_bytes()).unwrap();
+        other_message.message = b"audit 2".to_vec();
+        assert_eq!(
+            other_message.verify(utxos),
+            Err(ReserveProofError::InvalidSignature(signer))
+        );
+        let mut fewer_outputs = ReserveProof::from_bytes(&proof.to_bytes()).unwrap();
+        fewer_outputs.inputs.pop();
+        assert_eq!(
+            fewer_outputs.verify(utxos),
+            Err(ReserveProofError::InvalidSignature(signer))
+        );
+
+        // the signatures of a message and of a proof are not interchangeable
+        let key = wallet.get_signing_key(&proof.signatures[0].0).unwrap();
+        let mut from_message = ReserveProof::from_bytes(&proof.to_bytes()).unwrap();
+        from_message.signatures[0].1 = *MessageSignature::sign(key, b"audit").get_signature();
+        assert_eq!(
+            from_message.verify(utxos),
+            Err(ReserveProofError::InvalidSignature(signer))
+        );
+        let hash = proof_hash(b"audit", &proof.inputs);
+        let bytes = [signer.as_slice(), &proof.signatures[0].1.to_bytes()].concat();
+        let from_proof = MessageSignature::from_bytes(&bytes.try_into().unwrap()).unwrap();
+        assert!(from_proof.verify_pubkey(b"audit").is_err());
+        assert!(from_proof.verify_pubkey(&hash).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! Proves the control of a key, or of an address, without moving funds. The key signs the
+//! hash of a prefix and the message, so a message signature can never pass for the
+//! signature of a transaction or of another kind of proof.
+use std::{fmt, str::FromStr};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey, ed25519::signature::Signer};
+use sha2::{Digest, Sha256};
+
+use crate::{shared::Hash, transactions::address::Address};
+
+const MESSAGE_PREFIX: &[u8] = b"Crypto Signed Message:\n";
+
+/// Hash signed for `message`: sha256(prefix | message length (u64 BE) | message).
+pub fn message_hash(message: &[u8]) -> Hash {
+    Sha256::new()
+        .chain_update(MESSAGE_PREFIX)
+        .chain_update((message.len() as u64).to_be_bytes())
+        .chain_update(message)
+        .finalize()
+        .into()
+}
+
+/// Signature of a message with the pubkey that made it, which a pay-to-pubkey-hash address
+/// does not reveal. Its text form is the base64 of pubkey | signature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageSignature {
+    pubkey: VerifyingKey,
+    signature: Signature,
+}
+impl MessageSignature {
+    const SERIALIZED_SIZE: usize = 32 + Signature::BYTE_SIZE;
+
+    pub fn sign(key: &SigningKey, message: &[u8]) -> Self {
+        Self {
+            pubkey: key.verifying_key(),
+            signature: key.sign(&message_hash(message)),
+        }
+    }
+    pub fn get_pubkey(&self) -> &VerifyingKey {
+        &self.pubkey
+    }
+    pub fn get_signature(&self) -> &Signature {
+        &self.signature
+    }
+    /// Checks the signature of `message` by the key `address` pays to.
+    pub fn verify(&self, address: &Address, message: &[u8]) -> Result<(), MessageError> {
+        if !address.is_owned_by(&self.pubkey) {
+            return Err(MessageError::WrongKey);
+        }
+        self.verify_pubkey(message)
+    }
+    /// Checks the signature of `message` by the key of the signature.
+    pub fn verify_pubkey(&self, message: &[u8]) -> Result<(), MessageError> {
+        self.pubkey
+            .verify_strict(&message_hash(message), &self.signature)
+            .map_err(|_| MessageError::InvalidSignature)
+    }
+    pub fn to_bytes(&self) -> [u8; Self::SERIALIZED_SIZE] {
+        let mut bytes = [0u8; Self::SERIALIZED_SIZE];
+        bytes[..32].copy_from_slice(self.pubkey.as_bytes());
+        bytes[32..].copy_from_slice(&self.signature.to_bytes());
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8; Self::SERIALIZED_SIZE]) -> Option<Self> {
+        let (pubkey, signature) = bytes.split_first_chunk::<32>()?;
+        Some(Self {
+            pubkey: VerifyingKey::from_bytes(pubkey).ok()?,
+            signature: Signature::from_slice(signature).ok()?,
+        })
+    }
+}
+
+impl fmt::Display for MessageSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&BASE64_STANDARD.encode(self.to_bytes()))
+    }
+}
+impl FromStr for MessageSignature {
+    type Err = MessageError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        BASE64_STANDARD
+            .decode(text.trim())
+            .ok()
+            .and_then(|bytes| Self::from_bytes(bytes.as_slice().try_into().ok()?))
+            .ok_or(MessageError::InvalidEncoding)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageError {
+    InvalidEncoding,
+    /// The key of the signature is not the one of the address.
+    WrongKey,
+    /// Not a signature of this message by the key.
+    InvalidSignature,
+}
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidEncoding => write!(f, "not a message signature"),
+            Self::WrongKey => write!(f, "the message is signed by another key"),
+            Self::InvalidSignature => write!(f, "invalid signature of the message"),
+        }
+    }
+}
+impl std::error::Error for MessageError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blocks::checkpoints::Network,
+        test_utils::signing_key,
+        transactions::{
+            transaction::{RawTransaction, SignedTransaction, TransactionValidationError},
+            transaction_input::Input,
+            transaction_output::Output,
+        },
+        wallets::wallet::{Wallet, WalletError},
+    };
+
+    #[test]
+    fn messages_are_signed_by_the_key_of_an_address() {
+        let mut wallet = Wallet::new();
+        let key = wallet.add_key(signing_key(1));
+        let other = signing_key(2).verifying_key();
+        for address in [
+            Address::from_pubkey(Network::Regtest, &key),
+            Address::from_pubkey_hash(Network::Regtest, &key),
+        ] {
+            let signature = wallet.sign_message(&address, b"hello").unwrap();
+            assert_eq!(signature.get_pubkey(), &key);
+            assert_eq!(signature.verify(&address, b"hello"), Ok(()));
+            let decoded: MessageSignature = signature.to_string().parse().unwrap();
+            assert_eq!(decoded, signature);
+            assert_eq!(
+                signature.verify(&address, b"hello!"),
+                Err(MessageError::InvalidSignature)
+            );
+            assert_eq!(
+                signature.verify(&address, b""),
+                Err(MessageError::InvalidSignature)
+            );
+        }
+        for address in [
+            Address::from_pubkey(Network::Regtest, &other),
+            Address::from_pubkey_hash(Network::Regtest, &other),
+        ] {
+            assert!(matches!(
+                wallet.sign_message(&address, b"hello"),
+                Err(WalletError::UnknownKey(payee)) if &payee == address.get_payload().as_bytes()
+            ));
+            let signature = MessageSignature::sign(&signing_key(1), b"hello");
+            assert_eq!(
+                signature.verify(&address, b"hello"),
+                Err(MessageError::WrongKey)
+            );
+        }
+
+        // the pubkey of the signature cannot be swapped for the one of the address
+        let mut bytes = MessageSignature::sign(&signing_key(1), b"hello").to_bytes();
+        bytes[..32].copy_from_slice(other.as_bytes());
+        let swapped = MessageSignature::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            swapped.verify(&Address::from_pubkey(Network::Regtest, &other), b"hello"),
+            Err(MessageError::InvalidSignature)
+        );
+        for text in ["", "not base64!", "aGVsbG8="] {
+            assert_eq!(
+                text.parse::<MessageSignature>(),
+                Err(MessageError::InvalidEncoding)
+            );
+        }
+    }
+
+    #[test]
+    fn the_hash_of_a_message_is_prefixed() {
+        let mut bytes = b"Crypto Signed Message:\n".to_vec();
+        bytes.extend_from_slice(&5u64.to_be_bytes());
+        bytes.extend_from_slice(b"hello");
+        assert_eq!(message_hash(b"hello"), <Hash>::from(Sha256::digest(&bytes)));
+        // the length keeps apart messages sharing the bytes of a prefix and a length
+        assert_ne!(message_hash(b""), message_hash(&0u64.to_be_bytes()));
+    }
+
+    #[test]
+    fn a_message_signature_never_signs_a_transaction() {
+        let key = signing_key(1);
+        let raw = RawTransaction::new(
+            vec![Input::new([1; 32], 0)],
+            vec![Output::new(signing_key(9).verifying_key(), 10)],
+            key.verifying_key(),
+        );
+        let tx_hash = raw.hash();
+
+        // a message of the bytes of the tx id
+        let from_message = MessageSignature::sign(&key, &tx_hash);
+        let signed =
+            SignedTransaction::with_signatures(raw.clone(), vec![*from_message.get_signature()]);
+        assert_eq!(
+            signed.check_signature(),
+            Err(TransactionValidationError::SignatureIncorrect)
+        );
+        // and the other way round
+        let signed = raw.sign(&key);
+        let from_transaction = MessageSignature::from_bytes(
+            &[
+                key.verifying_key().as_bytes().as_slice(),
+                &signed.get_signatures()[0].to_bytes(),
+            ]
+            .concat()
+            .try_into()
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            from_transaction.verify_pubkey(&tx_hash),
+            Err(MessageError::InvalidSignature)
+        );
+    }
+}
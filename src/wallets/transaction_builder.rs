@@ -0,0 +1,517 @@
+use ed25519_dalek::VerifyingKey;
+
+use crate::{
+    transactions::{
+        address::{Address, pubkey_hash},
+        partially_signed::PartiallySignedTransaction,
+        transaction::{MAX_INPUTS_PER_TRANSACTION, RawTransaction, SignedTransaction},
+        transaction_input::Input,
+        transaction_output::Output,
+    },
+    utxo_map::UTXOMap,
+    wallets::{
+        coin_selection::{branch_and_bound, largest_first},
+        wallet::Wallet,
+        watch_only::WatchOnlyWallet,
+    },
+};
+
+/// Fee per byte of transaction.
+pub const DEFAULT_FEE_RATE: u64 = 1;
+/// Smallest change output created, a smaller change is left to the fee.
+pub const DUST_LIMIT: u64 = 1_000;
+
+/// Pays recipients from the coins of a wallet: selects them, adds a change output when
+/// needed and signs. The coins of a single key are preferred, as each key spent adds its
+/// signature; the change goes back to the first key spent. A watch-only wallet only builds
+/// unsigned transactions, for the holder of the keys to sign.
+pub struct TransactionBuilder<'a> {
+    pubkeys: Vec<VerifyingKey>,
+    signer: Option<&'a Wallet>,
+    utxos: &'a UTXOMap,
+    recipients: Vec<Output>,
+    fee_rate: u64,
+}
+
+/// A signed transaction and the fee it pays.
+pub struct BuiltTransaction {
+    pub transaction: SignedTransaction,
+    pub fee: u64,
+}
+
+/// A transaction to sign, with the outputs it spends, and the fee it pays.
+pub struct UnsignedTransaction {
+    pub transaction: PartiallySignedTransaction,
+    pub fee: u64,
+}
+
+// coins of one wallet key worth spending, with their amount
+struct KeyCoins {
+    pubkey: VerifyingKey,
+    coins: Vec<(Input, u64)>,
+}
+impl KeyCoins {
+    fn select(&self, selection: &[usize]) -> impl Iterator<Item = (VerifyingKey, (Input, u64))> {
+        selection
+            .iter()
+            .map(|index| (self.pubkey, self.coins[*index]))
+    }
+}
+
+impl<'a> TransactionBuilder<'a> {
+    pub fn new(wallet: &'a Wallet, utxos: &'a UTXOMap) -> Self {
+        Self::with_pubkeys(wallet.get_pubkeys(), Some(wallet), utxos)
+    }
+    /// Builder of unsigned transactions spending the coins of the imported pubkeys, the
+    /// addresses imported alone cannot be spent as their pubkey is not known.
+    pub fn watch_only(wallet: &WatchOnlyWallet, utxos: &'a UTXOMap) -> Self {
+        Self::with_pubkeys(wallet.get_pubkeys().to_vec(), None, utxos)
+    }
+    fn with_pubkeys(
+        pubkeys: Vec<VerifyingKey>,
+        signer: Option<&'a Wallet>,
+        utxos: &'a UTXOMap,
+    ) -> Self {
+        Self {
+            pubkeys,
+            signer,
+            utxos,
+            recipients: Vec::new(),
+            fee_rate: DEFAULT_FEE_RATE,
+        }
+    }
+    pub fn with_recipient(mut self, address: &Address, amount: u64) -> Self {
+        self.recipients.push(Output::pay_to(address, amount));
+        self
+    }
+    pub fn with_fee_rate(mut self, fee_rate: u64) -> Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+    /// Builds and signs the transaction, refused for a watch-only wallet.
+    pub fn build(&self) -> Result<BuiltTransaction, BuildError> {
+        let wallet = self.signer.ok_or(BuildError::WatchOnly)?;
+        let (raw, fee) = self.select()?;
+        let transaction = wallet
+            .sign(raw)
+            .expect("the coins are selected from the wallet keys");
+        debug_assert!(fee >= transaction.serialized_size() as u64 * self.fee_rate);
+        Ok(BuiltTransaction { transaction, fee })
+    }
+    /// Builds the transaction without signing it, with the outputs it spends so that an
+    /// offline signer can check the fee.
+    pub fn build_unsigned(&self) -> Result<UnsignedTransaction, BuildError> {
+        let (raw, fee) = self.select()?;
+        let mut transaction = PartiallySignedTransaction::new(raw);
+        transaction.fill_utxos(self.utxos);
+        Ok(UnsignedTransaction { transaction, fee })
+    }
+    /// Looks for a set of coins of one key paying the recipients and the fee without change
+    /// (branch and bound), else takes the largest coins of one key and adds a change output
+    /// unless it is dust. When no key can pay alone, takes the largest coins of all the keys.
+    fn select(&self) -> Result<(RawTransaction, u64), BuildError> {
+        let payment = self
+            .recipients
+            .iter()
+            .try_fold(0u64, |total, output| total.checked_add(output.get_amount()))
+            .ok_or(BuildError::AmountOverflow)?;
+        if payment == 0 {
+            return Err(BuildError::NoPayment);
+        }
+        let input_fee = self.fee_of(Input::SERIALIZED_SIZE)?;
+        let change_fee = self.fee_of(Output::SERIALIZED_SIZE)?;
+        // the fee of the transaction without inputs, each input then pays its own
+        let target = self
+            .fee_of(SignedTransaction::size_for(0, self.recipients.len(), 1))?
+            .checked_add(payment)
+            .ok_or(BuildError::AmountOverflow)?;
+
+        let wallet_coins = self.wallet_coins(input_fee);
+        let effective_values: Vec<Vec<u64>> = wallet_coins
+            .iter()
+            .map(|key_coins| {
+                key_coins
+                    .coins
+                    .iter()
+                    .map(|(_, amount)| amount - input_fee)
+                    .collect()
+            })
+            .collect();
+
+        // an excess lower than the cost of creating and later spending a change goes to the fee
+        let cost_of_change = change_fee.saturating_add(input_fee);
+        for (key_coins, values) in wallet_coins.iter().zip(&effective_values) {
+            if let Some(selection) = branch_and_bound(values, target, cost_of_change)
+                && selection.len() <= MAX_INPUTS_PER_TRANSACTION
+            {
+                return Ok(self.finish(key_coins.select(&selection), None, payment));
+            }
+        }
+        // the change left when `selection` pays `required`, none when it would be dust
+        let change_of = |values: &[u64], selection: &[usize], required: u64| {
+            let selected: u64 = selection.iter().map(|index| values[*index]).sum();
+            (selected - required)
+                .checked_sub(change_fee)
+                .filter(|change| *change >= DUST_LIMIT)
+        };
+        for (key_coins, values) in wallet_coins.iter().zip(&effective_values) {
+            if let Some(selection) = largest_first(values, target, MAX_INPUTS_PER_TRANSACTION) {
+                let change = change_of(values, &selection, target);
+                return Ok(self.finish(key_coins.select(&selection), change, payment));
+            }
+        }
+
+        // the coins of all the keys, each key spent adds a signer and its signature
+        let signer_fee =
+            self.fee_of(SignedTransaction::size_for(0, 0, 1) - RawTransaction::size_for(0, 0, 0))?;
+        let coins: Vec<(VerifyingKey, (Input, u64))> = wallet_coins
+            .iter()
+            .flat_map(|key_coins| key_coins.coins.iter().map(|coin| (key_coins.pubkey, *coin)))
+            .collect();
+        let values: Vec<u64> = coins
+            .iter()
+            .map(|(_, (_, amount))| amount - input_fee)
+            .collect();
+        let with_signers = |signer_count: u64| {
+            signer_fee
+                .checked_mul(signer_count - 1)
+                .and_then(|signers_fee| target.checked_add(signers_fee))
+                .ok_or(BuildError::AmountOverflow)
+        };
+        // more coins may need more signers, which need more coins
+        let mut signer_count = 1;
+        while let Some(selection) = largest_first(
+            &values,
+            with_signers(signer_count)?,
+            MAX_INPUTS_PER_TRANSACTION,
+        ) {
+            let mut signers: Vec<&VerifyingKey> =
+                selection.iter().map(|index| &coins[*index].0).collect();
+            signers.sort_unstable_by_key(|pubkey| pubkey.as_bytes());
+            signers.dedup();
+            if signers.len() as u64 <= signer_count {
+                let change = change_of(&values, &selection, with_signers(signers.len() as u64)?);
+                let selected = selection.iter().map(|index| coins[*index]);
+                return Ok(self.finish(selected, change, payment));
+            }
+            signer_count = signers.len() as u64;
+        }
+
+        let key_count = wallet_coins
+            .iter()
+            .filter(|key_coins| !key_coins.coins.is_empty())
+            .count() as u64;
+        Err(BuildError::InsufficientFunds {
+            available: coins.iter().map(|(_, (_, amount))| amount).sum(),
+            required: target
+                .saturating_add(input_fee.saturating_mul(coins.len() as u64))
+                .saturating_add(signer_fee.saturating_mul(key_count.saturating_sub(1))),
+        })
+    }
+    fn fee_of(&self, size: usize) -> Result<u64, BuildError> {
+        (size as u64)
+            .checked_mul(self.fee_rate)
+            .ok_or(BuildError::AmountOverflow)
+    }
+    // the coins whose amount is above the fee of spending them, by key
+    fn wallet_coins(&self, input_fee: u64) -> Vec<KeyCoins> {
+        self.pubkeys
+            .iter()
+            .map(|pubkey| {
+                let coins = self
+                    .utxos
+                    .unspent_of(pubkey)
+                    .filter(|(_, output)| output.get_amount() > input_fee)
+                    .map(|(input, output)| (*input, output.get_amount()))
+                    .collect();
+                KeyCoins {
+                    pubkey: *pubkey,
+                    coins,
+                }
+            })
+            .collect()
+    }
+    // the transaction spending `coins`, signed by each key owning some of them
+    fn finish(
+        &self,
+        coins: impl Iterator<Item = (VerifyingKey, (Input, u64))>,
+        change: Option<u64>,
+        payment: u64,
+    ) -> (RawTransaction, u64) {
+        let mut signers: Vec<VerifyingKey> = vec![];
+        let mut inputs = vec![];
+        let mut total_input = 0;
+        for (pubkey, (input, amount)) in coins {
+            if !signers.contains(&pubkey) {
+                signers.push(pubkey);
+            }
+            inputs.push(input);
+            total_input += amount;
+        }
+        let mut outputs = self.recipients.clone();
+        if let Some(change) = change {
+            outputs.push(Output::new_pubkey_hash(pubkey_hash(&signers[0]), change));
+        }
+        let fee = total_input - payment - change.unwrap_or(0);
+        (RawTransaction::with_signers(inputs, outputs, signers), fee)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    /// No recipient, or only zero amounts.
+    NoPayment,
+    AmountOverflow,
+    /// `available` is the total of the wallet coins worth spending, `required` what paying
+    /// with all of them would cost.
+    InsufficientFunds {
+        available: u64,
+        required: u64,
+    },
+    /// A watch-only wallet has no key to sign with.
+    WatchOnly,
+}
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoPayment => write!(f, "nothing to pay"),
+            Self::AmountOverflow => write!(f, "amounts overflow"),
+            Self::InsufficientFunds {
+                available,
+                required,
+            } => write!(
+                f,
+                "insufficient funds: {available} available, {required} required"
+            ),
+            Self::WatchOnly => write!(f, "a watch-only wallet cannot sign"),
+        }
+    }
+}
+impl std::error::Error for BuildError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_chain::BlockChain,
+        blocks::checkpoints::Network,
+        test_utils::{add_block, chain_of, signing_key},
+        transactions::{address::pubkey_hash, transaction::ValidatedTransaction},
+    };
+
+    const INPUT_FEE: u64 = Input::SERIALIZED_SIZE as u64;
+    const CHANGE_FEE: u64 = Output::SERIALIZED_SIZE as u64;
+
+    // a chain where each key seed of `coins` has a coin of the amount, and a wallet of
+    // those keys
+    fn funded(coins: &[(u8, u64)]) -> (BlockChain, Wallet) {
+        let mut chain = chain_of(1, &signing_key(1));
+        let coinbase = *chain.get_block(0).unwrap().get_transactions()[0].get_hash();
+        let outputs = coins
+            .iter()
+            .map(|(seed, amount)| Output::new(signing_key(*seed).verifying_key(), *amount))
+            .collect();
+        let funding = RawTransaction::new(
+            vec![Input::new(coinbase, 0)],
+            outputs,
+            signing_key(1).verifying_key(),
+        )
+        .sign(&signing_key(1));
+        add_block(&mut chain, &signing_key(1), vec![funding]);
+        let mut wallet = Wallet::new();
+        for (seed, _) in coins {
+            wallet.add_key(signing_key(*seed));
+        }
+        (chain, wallet)
+    }
+
+    fn recipient() -> Address {
+        Address::from_pubkey_hash(Network::Regtest, &signing_key(9).verifying_key())
+    }
+
+    // the fee of a transaction paying one recipient, before its inputs
+    fn base_fee() -> u64 {
+        SignedTransaction::size_for(0, 1, 1) as u64
+    }
+
+    fn pay(
+        chain: &BlockChain,
+        wallet: &Wallet,
+        amount: u64,
+    ) -> Result<BuiltTransaction, BuildError> {
+        let built = TransactionBuilder::new(wallet, chain.get_utxos())
+            .with_recipient(&recipient(), amount)
+            .build()?;
+        let validated =
+            ValidatedTransaction::validate(built.transaction.clone(), chain.get_utxos()).unwrap();
+        assert_eq!(validated.get_fee(), built.fee);
+        assert!(built.fee >= built.transaction.serialized_size() as u64);
+        Ok(built)
+    }
+
+    #[test]
+    fn a_coin_matching_the_payment_is_spent_without_change() {
+        let (chain, wallet) = funded(&[(2, 300_000), (2, 120_000), (2, 50_000)]);
+        let exact = 120_000 - INPUT_FEE - base_fee();
+        let built = pay(&chain, &wallet, exact).unwrap();
+        assert_eq!(built.transaction.inputs().len(), 1);
+        assert_eq!(built.transaction.outputs().len(), 1);
+        assert_eq!(built.fee, built.transaction.serialized_size() as u64);
+
+        // an excess below the cost of a change goes to the fee
+        let excess = INPUT_FEE + CHANGE_FEE;
+        let built = pay(&chain, &wallet, exact - excess).unwrap();
+        assert_eq!(built.transaction.outputs().len(), 1);
+        assert_eq!(
+            built.fee,
+            built.transaction.serialized_size() as u64 + excess
+        );
+
+        // two coins adding up to the payment
+        let built = pay(&chain, &wallet, 170_000 - 2 * INPUT_FEE - base_fee()).unwrap();
+        assert_eq!(built.transaction.inputs().len(), 2);
+        assert_eq!(built.fee, built.transaction.serialized_size() as u64);
+    }
+
+    #[test]
+    fn change_below_the_dust_limit_goes_to_the_fee() {
+        let (chain, wallet) = funded(&[(2, 300_000)]);
+        // what is left for the change after the fee of the change output
+        let payment_leaving = |change: u64| 300_000 - INPUT_FEE - base_fee() - CHANGE_FEE - change;
+
+        let built = pay(&chain, &wallet, 100_000).unwrap();
+        let outputs = built.transaction.outputs();
+        assert_eq!(outputs.len(), 2);
+        assert!(outputs[1].is_owned_by(&signing_key(2).verifying_key()));
+        assert_eq!(outputs[1].get_amount(), 300_000 - 100_000 - built.fee);
+        assert_eq!(built.fee, built.transaction.serialized_size() as u64);
+
+        let built = pay(&chain, &wallet, payment_leaving(DUST_LIMIT)).unwrap();
+        assert_eq!(built.transaction.outputs()[1].get_amount(), DUST_LIMIT);
+
+        let payment = payment_leaving(DUST_LIMIT - 1);
+        let built = pay(&chain, &wallet, payment).unwrap();
+        assert_eq!(built.transaction.outputs().len(), 1);
+        assert_eq!(built.fee, 300_000 - payment);
+    }
+
+    #[test]
+    fn a_payment_prefers_the_coins_of_a_single_key() {
+        let (chain, wallet) = funded(&[(2, 100_000), (2, 50_000), (3, 120_000)]);
+        // the key 2 has enough with both its coins
+        let built = pay(&chain, &wallet, 140_000).unwrap();
+        assert_eq!(built.transaction.inputs().len(), 2);
+        assert_eq!(
+            built.transaction.get_signers(),
+            [signing_key(2).verifying_key()]
+        );
+
+        // no key has enough alone: the largest coins of both, each key signing
+        let signer_fee =
+            (SignedTransaction::size_for(0, 0, 2) - SignedTransaction::size_for(0, 0, 1)) as u64;
+        let built = pay(&chain, &wallet, 200_000).unwrap();
+        let transaction = &built.transaction;
+        assert_eq!(transaction.inputs().len(), 2);
+        assert_eq!(
+            transaction.get_signers(),
+            [
+                signing_key(3).verifying_key(),
+                signing_key(2).verifying_key()
+            ]
+        );
+        assert_eq!(transaction.get_signatures().len(), 2);
+        assert_eq!(built.fee, transaction.serialized_size() as u64);
+        assert_eq!(
+            built.fee,
+            base_fee() + 2 * INPUT_FEE + signer_fee + CHANGE_FEE
+        );
+        // the change goes back to the first key spent
+        assert!(transaction.outputs()[1].is_owned_by(&signing_key(3).verifying_key()));
+
+        let all_coins = base_fee() + 3 * INPUT_FEE + signer_fee;
+        let built = pay(&chain, &wallet, 270_000 - all_coins).unwrap();
+        assert_eq!(built.transaction.inputs().len(), 3);
+        assert_eq!(built.transaction.outputs().len(), 1);
+        assert_eq!(
+            pay(&chain, &wallet, 270_000 - all_coins + 1).err(),
+            Some(BuildError::InsufficientFunds {
+                available: 270_000,
+                required: 270_000 + 1,
+            })
+        );
+
+        assert_eq!(
+            TransactionBuilder::new(&wallet, chain.get_utxos())
+                .build()
+                .err(),
+            Some(BuildError::NoPayment)
+        );
+        assert_eq!(
+            pay(&chain, &Wallet::new(), 1).err(),
+            Some(BuildError::InsufficientFunds {
+                available: 0,
+                required: base_fee() + 1,
+            })
+        );
+    }
+
+    #[test]
+    fn a_watch_only_wallet_builds_the_transaction_unsigned() {
+        let (chain, wallet) = funded(&[(2, 100_000), (3, 300_000)]);
+        let utxos = chain.get_utxos();
+        let watch_only = wallet.to_watch_only();
+        let builder = TransactionBuilder::watch_only(&watch_only, utxos)
+            .with_recipient(&recipient(), 150_000);
+        assert_eq!(builder.build().err(), Some(BuildError::WatchOnly));
+
+        // the same coins as the full wallet, the key holder signs them
+        let built = pay(&chain, &wallet, 150_000).unwrap();
+        let UnsignedTransaction {
+            mut transaction,
+            fee,
+        } = builder.build_unsigned().unwrap();
+        assert_eq!(fee, built.fee);
+        assert_eq!(transaction.fee(), Some(fee));
+        assert_eq!(transaction.get_raw().hash(), *built.transaction.get_hash());
+        assert_eq!(
+            transaction.missing_signers(),
+            [signing_key(3).verifying_key()]
+        );
+        transaction.sign(&signing_key(3)).unwrap();
+        transaction.finalize().unwrap();
+        let signed = transaction.extract().unwrap();
+        let validated = ValidatedTransaction::validate(signed, utxos).unwrap();
+        assert_eq!(validated.get_fee(), fee);
+
+        // the coins of an address imported alone are followed, not spent
+        let (mut chain, wallet) = funded(&[(2, 100_000)]);
+        let key = signing_key(2).verifying_key();
+        let coin = chain
+            .get_utxos()
+            .unspent_of(&key)
+            .next()
+            .map(|(input, _)| *input);
+        let to_hash = RawTransaction::new(
+            vec![coin.unwrap()],
+            vec![Output::new_pubkey_hash(pubkey_hash(&key), 99_000)],
+            key,
+        )
+        .sign(&signing_key(2));
+        add_block(&mut chain, &signing_key(1), vec![to_hash]);
+        let mut by_address = WatchOnlyWallet::new();
+        by_address.import_address(&Address::from_pubkey_hash(Network::Regtest, &key));
+        assert_eq!(by_address.balance(chain.get_utxos()), 99_000);
+        assert_eq!(wallet.balance(chain.get_utxos()), 99_000);
+        assert_eq!(
+            TransactionBuilder::watch_only(&by_address, chain.get_utxos())
+                .with_recipient(&recipient(), 1)
+                .build_unsigned()
+                .err(),
+            Some(BuildError::InsufficientFunds {
+                available: 0,
+                required: base_fee() + 1,
+            })
+        );
+        assert!(pay(&chain, &wallet, 50_000).is_ok());
+    }
+}
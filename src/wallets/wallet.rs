@@ -0,0 +1,492 @@
+use std::{collections::HashSet, fs, io, path::Path};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use bip39::Mnemonic;
+use ed25519_dalek::{SigningKey, VerifyingKey, ed25519::signature::Signer};
+use rand::{RngCore, rngs::OsRng};
+use zeroize::Zeroizing;
+
+use crate::{
+    block_chain::BlockChain,
+    blocks::checkpoints::Network,
+    transactions::{
+        address::{Address, pubkey_hash},
+        partially_signed::{PartialTransactionError, PartiallySignedTransaction},
+        transaction::{RawTransaction, SignedTransaction},
+    },
+    utxo_map::UTXOMap,
+    wallets::{
+        hd_key::{DerivationPath, ExtendedKey},
+        keystore::{self, KeystoreError},
+        signed_message::MessageSignature,
+        watch_only::WatchOnlyWallet,
+    },
+};
+
+// tags of the entries of the keystore plaintext: tag (u8) | length (u16 BE) | data
+const SIGNING_KEY_TAG: u8 = 1;
+const SEED_TAG: u8 = 2;
+const NEXT_INDEX_TAG: u8 = 3;
+
+// SLIP-44 coin type 1 is shared by the test networks of every coin
+const COIN_TYPE: u32 = 1;
+pub const MNEMONIC_WORD_COUNT: usize = 24;
+/// Number of unused keys in a row after which a recovery stops looking.
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+/// Path of the account whose children `m/44'/1'/0'/0'/i'` are the wallet keys.
+pub fn account_path() -> DerivationPath {
+    DerivationPath::new([44, COIN_TYPE, 0, 0])
+}
+
+// keys derived from the seed of the mnemonic
+struct HdChain {
+    seed: Zeroizing<Vec<u8>>,
+    account: ExtendedKey,
+    next_index: u32,
+}
+impl HdChain {
+    fn new(seed: &[u8], next_index: u32) -> Self {
+        Self {
+            seed: Zeroizing::new(seed.to_vec()),
+            account: ExtendedKey::from_seed(seed).derive_path(&account_path()),
+            next_index,
+        }
+    }
+    fn derive(&self, index: u32) -> SigningKey {
+        self.account.derive_child(index).signing_key()
+    }
+}
+
+/// Signing keys of the user, saved in a password encrypted keystore file.
+/// Keys of a wallet with a seed are derived from it, the others are imported.
+#[derive(Default)]
+pub struct Wallet {
+    keys: Vec<SigningKey>,
+    hd: Option<HdChain>,
+}
+impl Wallet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// New deterministic wallet with a fresh mnemonic of `word_count` words (12 to 24),
+    /// the mnemonic is its backup.
+    pub fn new_with_mnemonic(
+        word_count: usize,
+        passphrase: &str,
+    ) -> Result<(Self, Zeroizing<String>), WalletError> {
+        if !matches!(word_count, 12 | 15 | 18 | 21 | 24) {
+            return Err(WalletError::InvalidMnemonic(bip39::Error::BadWordCount(
+                word_count,
+            )));
+        }
+        let mut entropy = Zeroizing::new([0u8; 32]);
+        let entropy = &mut entropy[..word_count / 3 * 4];
+        OsRng.fill_bytes(entropy);
+        let mnemonic = Mnemonic::from_entropy(entropy).map_err(WalletError::InvalidMnemonic)?;
+        let phrase = Zeroizing::new(mnemonic.to_string());
+        Ok((Self::from_mnemonic(&phrase, passphrase)?, phrase))
+    }
+    pub fn from_mnemonic(phrase: &str, passphrase: &str) -> Result<Self, WalletError> {
+        let mnemonic = Mnemonic::parse(phrase).map_err(WalletError::InvalidMnemonic)?;
+        let seed = Zeroizing::new(mnemonic.to_seed(passphrase));
+        Ok(Self::from_seed(seed.as_ref()))
+    }
+    /// Deterministic wallet without any key yet.
+    pub fn from_seed(seed: &[u8]) -> Self {
+        Self {
+            keys: vec![],
+            hd: Some(HdChain::new(seed, 0)),
+        }
+    }
+    /// Restores the wallet of a mnemonic with the keys `chain` shows were used: keys are
+    /// derived until `gap_limit` of them in a row never appear in a transaction.
+    pub fn recover(
+        phrase: &str,
+        passphrase: &str,
+        chain: &BlockChain,
+        gap_limit: u32,
+    ) -> Result<Self, WalletError> {
+        let mut wallet = Self::from_mnemonic(phrase, passphrase)?;
+        let used_payees = used_payees(chain);
+        let Some(hd) = &wallet.hd else {
+            return Ok(wallet);
+        };
+        let mut used_count = 0;
+        let mut index = 0;
+        while index < used_count + gap_limit {
+            let pubkey = hd.derive(index).verifying_key();
+            if used_payees.contains(pubkey.as_bytes())
+                || used_payees.contains(&pubkey_hash(&pubkey))
+            {
+                used_count = index + 1;
+            }
+            index += 1;
+        }
+        for _ in 0..used_count {
+            wallet.generate_key();
+        }
+        Ok(wallet)
+    }
+    /// Loads the wallet at `path`, or creates a deterministic one when there is no file.
+    /// The mnemonic of a created wallet is returned, to be written down by the user.
+    pub fn load_or_create(
+        path: &Path,
+        password: &str,
+    ) -> Result<(Self, Option<Zeroizing<String>>), WalletError> {
+        match Self::load(path, password) {
+            Err(WalletError::Io(error)) if error.kind() == io::ErrorKind::NotFound => {
+                let (mut wallet, phrase) = Self::new_with_mnemonic(MNEMONIC_WORD_COUNT, "")?;
+                wallet.generate_key();
+                wallet.save(path, password)?;
+                Ok((wallet, Some(phrase)))
+            }
+            result => result.map(|wallet| (wallet, None)),
+        }
+    }
+    pub fn load(path: &Path, password: &str) -> Result<Self, WalletError> {
+        let bytes = fs::read(path).map_err(WalletError::Io)?;
+        let plaintext = keystore::decrypt(&bytes, password)?;
+        Self::from_secrets(&plaintext)
+    }
+    /// Writes the keystore next to `path` then renames it, so a crash never leaves
+    /// a half written wallet.
+    pub fn save(&self, path: &Path, password: &str) -> Result<(), WalletError> {
+        let bytes = keystore::encrypt(&self.secrets(), password)?;
+        let temporary_path = path.with_extension("tmp");
+        fs::write(&temporary_path, bytes).map_err(WalletError::Io)?;
+        fs::rename(&temporary_path, path).map_err(WalletError::Io)
+    }
+    fn secrets(&self) -> Zeroizing<Vec<u8>> {
+        let mut secrets = Zeroizing::new(Vec::with_capacity(self.keys.len() * (3 + 32)));
+        for key in &self.keys {
+            secrets.push(SIGNING_KEY_TAG);
+            secrets.extend_from_slice(&32u16.to_be_bytes());
+            secrets.extend_from_slice(key.as_bytes());
+        }
+        if let Some(hd) = &self.hd {
+            secrets.push(SEED_TAG);
+            secrets.extend_from_slice(&(hd.seed.len() as u16).to_be_bytes());
+            secrets.extend_from_slice(&hd.seed);
+            secrets.push(NEXT_INDEX_TAG);
+            secrets.extend_from_slice(&4u16.to_be_bytes());
+            secrets.extend_from_slice(&hd.next_index.to_be_bytes());
+        }
+        secrets
+    }
+    fn from_secrets(mut secrets: &[u8]) -> Result<Self, WalletError> {
+        let mut wallet = Self::new();
+        while let Some((tag, rest)) = secrets.split_first() {
+            let (length, rest) = rest
+                .split_first_chunk::<2>()
+                .ok_or(KeystoreError::InvalidFormat)?;
+            let length = u16::from_be_bytes(*length) as usize;
+            if rest.len() < length {
+                return Err(KeystoreError::InvalidFormat.into());
+            }
+            let (data, rest) = rest.split_at(length);
+            match *tag {
+                SIGNING_KEY_TAG => {
+                    let secret: &[u8; 32] =
+                        data.try_into().map_err(|_| KeystoreError::InvalidFormat)?;
+                    wallet.add_key(SigningKey::from_bytes(secret));
+                }
+                SEED_TAG => wallet.hd = Some(HdChain::new(data, 0)),
+                NEXT_INDEX_TAG => {
+                    let next_index: [u8; 4] =
+                        data.try_into().map_err(|_| KeystoreError::InvalidFormat)?;
+                    let hd = wallet.hd.as_mut().ok_or(KeystoreError::InvalidFormat)?;
+                    hd.next_index = u32::from_be_bytes(next_index);
+                }
+                _ => return Err(KeystoreError::InvalidFormat.into()),
+            }
+            secrets = rest;
+        }
+        Ok(wallet)
+    }
+
+    /// Next derived key of a deterministic wallet, a random one otherwise.
+    pub fn generate_key(&mut self) -> VerifyingKey {
+        let key = match &mut self.hd {
+            Some(hd) => {
+                let key = hd.derive(hd.next_index);
+                hd.next_index += 1;
+                key
+            }
+            None => SigningKey::generate(&mut OsRng),
+        };
+        self.add_key(key)
+    }
+    pub fn is_deterministic(&self) -> bool {
+        self.hd.is_some()
+    }
+    pub fn add_key(&mut self, key: SigningKey) -> VerifyingKey {
+        let pubkey = key.verifying_key();
+        if !self.contains(&pubkey) {
+            self.keys.push(key);
+        }
+        pubkey
+    }
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+    pub fn contains(&self, pubkey: &VerifyingKey) -> bool {
+        self.get_signing_key(pubkey).is_some()
+    }
+    pub fn get_pubkeys(&self) -> Vec<VerifyingKey> {
+        self.keys.iter().map(SigningKey::verifying_key).collect()
+    }
+    /// Addresses of the keys on `network`, to be paid to. They pay to the hash of the keys.
+    pub fn get_addresses(&self, network: Network) -> Vec<Address> {
+        self.keys
+            .iter()
+            .map(|key| Address::from_pubkey_hash(network, &key.verifying_key()))
+            .collect()
+    }
+    /// Whether one of the keys of the wallet is paid by `address`.
+    pub fn owns(&self, address: &Address) -> bool {
+        self.keys
+            .iter()
+            .any(|key| address.is_owned_by(&key.verifying_key()))
+    }
+    /// Total of the unspent outputs paying the keys of the wallet.
+    pub fn balance(&self, utxos: &UTXOMap) -> u64 {
+        self.keys
+            .iter()
+            .map(|key| utxos.balance_of(&key.verifying_key()))
+            .sum()
+    }
+    /// The wallet without its private keys, for a machine that should only follow the coins.
+    pub fn to_watch_only(&self) -> WatchOnlyWallet {
+        WatchOnlyWallet::from_pubkeys(self.get_pubkeys())
+    }
+    /// Key receiving the mining rewards: the first one.
+    pub fn get_mining_key(&self) -> Option<&SigningKey> {
+        self.keys.first()
+    }
+    pub fn get_signing_key(&self, pubkey: &VerifyingKey) -> Option<&SigningKey> {
+        self.keys.iter().find(|key| &key.verifying_key() == pubkey)
+    }
+    /// Signs `raw` with the keys of all its signers, which must be keys of the wallet.
+    pub fn sign(&self, raw: RawTransaction) -> Result<SignedTransaction, WalletError> {
+        let hash = raw.hash();
+        let signatures = raw
+            .get_signers()
+            .iter()
+            .map(|pubkey| {
+                self.get_signing_key(pubkey)
+                    .map(|key| key.sign(&hash))
+                    .ok_or(WalletError::UnknownKey(pubkey.to_bytes()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(SignedTransaction::with_signatures(raw, signatures))
+    }
+    /// Signs `message` with the key `address` pays to.
+    pub fn sign_message(
+        &self,
+        address: &Address,
+        message: &[u8],
+    ) -> Result<MessageSignature, WalletError> {
+        let key = self
+            .keys
+            .iter()
+            .find(|key| address.is_owned_by(&key.verifying_key()))
+            .ok_or(WalletError::UnknownKey(*address.get_payload().as_bytes()))?;
+        Ok(MessageSignature::sign(key, message))
+    }
+    /// Adds the signatures of the wallet keys among the signers of `partial`, returns the
+    /// number added.
+    pub fn sign_partial(
+        &self,
+        partial: &mut PartiallySignedTransaction,
+    ) -> Result<usize, PartialTransactionError> {
+        let mut signed = 0;
+        for pubkey in partial.required_signers() {
+            if let Some(key) = self.get_signing_key(&pubkey) {
+                partial.sign(key)?;
+                signed += 1;
+            }
+        }
+        Ok(signed)
+    }
+}
+
+#[derive(Debug)]
+pub enum WalletError {
+    Io(io::Error),
+    Keystore(KeystoreError),
+    /// Compressed pubkey the wallet has no signing key for.
+    UnknownKey([u8; 32]),
+    InvalidMnemonic(bip39::Error),
+}
+impl From<KeystoreError> for WalletError {
+    fn from(error: KeystoreError) -> Self {
+        Self::Keystore(error)
+    }
+}
+impl std::fmt::Display for WalletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "cannot access the wallet file: {error}"),
+            Self::Keystore(error) => write!(f, "{error}"),
+            Self::InvalidMnemonic(error) => write!(f, "invalid mnemonic: {error}"),
+            Self::UnknownKey(pubkey) => write!(
+                f,
+                "the wallet has no key for {}",
+                BASE64_STANDARD.encode(pubkey)
+            ),
+        }
+    }
+}
+impl std::error::Error for WalletError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::Keystore(error) => Some(error),
+            Self::InvalidMnemonic(error) => Some(error),
+            Self::UnknownKey(_) => None,
+        }
+    }
+}
+
+// pubkeys and pubkey hashes appearing in the transactions of `chain`, as owner or in an output
+fn used_payees(chain: &BlockChain) -> HashSet<[u8; 32]> {
+    let mut payees = HashSet::new();
+    for block in (0..chain.len()).filter_map(|height| chain.get_block(height)) {
+        for transaction in block.get_transactions() {
+            payees.extend(
+                transaction
+                    .get_signed()
+                    .get_signers()
+                    .iter()
+                    .map(VerifyingKey::to_bytes),
+            );
+            payees.extend(
+                transaction
+                    .outputs()
+                    .iter()
+                    .map(|output| *output.get_payee().as_bytes()),
+            );
+        }
+    }
+    payees
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::{add_block, chain_of, from_hex, signing_key},
+        transactions::{transaction_input::Input, transaction_output::Output},
+    };
+
+    const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    // BIP-39 test vectors, with the passphrase TREZOR: entropy, mnemonic, seed
+    const BIP39_VECTORS: [(&str, &str, &str); 4] = [
+        (
+            "00000000000000000000000000000000",
+            PHRASE,
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+        ),
+        (
+            "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+        ),
+        (
+            "808080808080808080808080808080808080808080808080",
+            "letter advice cage absurd amount doctor acoustic avoid letter advice cage absurd amount doctor acoustic avoid letter always",
+            "107d7c02a5aa6f38c58083ff74f04c607c2d2c0ecc55501dadd72d025b751bc27fe913ffb796f841c49b1d33b610cf0e91d3aa239027f5e99fe4ce9e5088cd65",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote",
+            "dd48c104698c30cfe2b6142103248622fb7bb0ff692eebb00089b32d22484e1613912f0a5b694407be899ffd31ed3992c456cdf60f5d4564b8ba3f05a69890ad",
+        ),
+    ];
+
+    // key `index` of the wallet of `seed`, derived by hand
+    fn derived_key(seed: &[u8], index: u32) -> VerifyingKey {
+        ExtendedKey::from_seed(seed)
+            .derive_path(&account_path().child(index))
+            .signing_key()
+            .verifying_key()
+    }
+
+    #[test]
+    fn bip39_test_vectors() {
+        for (entropy, phrase, seed) in BIP39_VECTORS {
+            let mnemonic = Mnemonic::from_entropy(&from_hex(entropy)).unwrap();
+            assert_eq!(mnemonic.to_string(), phrase);
+            assert_eq!(mnemonic.to_seed("TREZOR")[..], from_hex(seed));
+
+            let mut wallet = Wallet::from_mnemonic(phrase, "TREZOR").unwrap();
+            assert!(wallet.is_deterministic());
+            for index in 0..3 {
+                assert_eq!(wallet.generate_key(), derived_key(&from_hex(seed), index));
+            }
+        }
+
+        // the last word holds the checksum
+        let wrong_checksum = PHRASE.replace("about", "abandon");
+        assert!(matches!(
+            Wallet::from_mnemonic(&wrong_checksum, ""),
+            Err(WalletError::InvalidMnemonic(bip39::Error::InvalidChecksum))
+        ));
+        assert!(matches!(
+            Wallet::from_mnemonic(&PHRASE.replace("about", "aboot"), ""),
+            Err(WalletError::InvalidMnemonic(bip39::Error::UnknownWord(11)))
+        ));
+        assert!(matches!(
+            Wallet::new_with_mnemonic(13, ""),
+            Err(WalletError::InvalidMnemonic(bip39::Error::BadWordCount(13)))
+        ));
+
+        let (mut wallet, phrase) = Wallet::new_with_mnemonic(12, "").unwrap();
+        assert_eq!(phrase.split_whitespace().count(), 12);
+        let mut restored = Wallet::from_mnemonic(&phrase, "").unwrap();
+        assert_eq!(wallet.generate_key(), restored.generate_key());
+    }
+
+    #[test]
+    fn recovery_finds_the_keys_used_up_to_the_gap_limit() {
+        let seed = Mnemonic::parse(PHRASE).unwrap().to_seed("");
+        let key = |index| derived_key(&seed, index);
+        let mut chain = chain_of(1, &signing_key(1));
+        let coinbase = *chain.get_block(0).unwrap().get_transactions()[0].get_hash();
+        // the keys 0 and 23 are paid directly, the key 3 by its hash, the key 45 too late
+        let payment = RawTransaction::new(
+            vec![Input::new(coinbase, 0)],
+            vec![
+                Output::new(key(0), 10),
+                Output::new_pubkey_hash(pubkey_hash(&key(3)), 10),
+                Output::new(key(23), 10),
+                Output::new(key(45), 10),
+            ],
+            signing_key(1).verifying_key(),
+        )
+        .sign(&signing_key(1));
+        add_block(&mut chain, &signing_key(1), vec![payment]);
+
+        let mut wallet = Wallet::recover(PHRASE, "", &chain, DEFAULT_GAP_LIMIT).unwrap();
+        assert_eq!(wallet.len(), 24);
+        assert!(wallet.contains(&key(23)));
+        assert!(!wallet.contains(&key(45)));
+        assert_eq!(wallet.balance(chain.get_utxos()), 30);
+        // the next key follows the recovered ones
+        assert_eq!(wallet.generate_key(), key(24));
+
+        // the key 23 is past the gap after the key 3
+        let wallet = Wallet::recover(PHRASE, "", &chain, DEFAULT_GAP_LIMIT - 1).unwrap();
+        assert_eq!(wallet.len(), 4);
+        assert!(!wallet.contains(&key(23)));
+
+        let wallet = Wallet::recover(PHRASE, "", &chain_of(3, &signing_key(1)), 20).unwrap();
+        assert!(wallet.is_empty());
+        assert!(wallet.is_deterministic());
+    }
+}
@@ -0,0 +1,302 @@
+//! A wallet holding no private key: it follows the coins of imported pubkeys and addresses,
+//! and builds the transactions spending them unsigned, for the holder of the keys to sign.
+//! Its file is one address per line, pay-to-pubkey for the imported pubkeys since their
+//! addresses carry them.
+use std::{collections::HashSet, fmt, fs, io, path::Path};
+
+use ed25519_dalek::VerifyingKey;
+
+use crate::{
+    blocks::checkpoints::Network,
+    transactions::{
+        address::{Address, AddressError, AddressPayload, pubkey_hash},
+        transaction_input::Input,
+        transaction_output::Output,
+    },
+    utxo_map::UTXOMap,
+    wallets::history::payees_of,
+};
+
+#[derive(Default)]
+pub struct WatchOnlyWallet {
+    pubkeys: Vec<VerifyingKey>,
+    // imported without their pubkey, the coins paying them can be followed but not spent
+    addresses: Vec<AddressPayload>,
+}
+impl WatchOnlyWallet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn from_pubkeys(pubkeys: impl IntoIterator<Item = VerifyingKey>) -> Self {
+        let mut wallet = Self::new();
+        for pubkey in pubkeys {
+            wallet.import_pubkey(pubkey);
+        }
+        wallet
+    }
+    pub fn load(path: &Path, network: Network) -> Result<Self, WatchOnlyError> {
+        let text = fs::read_to_string(path).map_err(WatchOnlyError::Io)?;
+        let mut wallet = Self::new();
+        for (line_index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let address =
+                Address::parse(line, network).map_err(|error| WatchOnlyError::InvalidAddress {
+                    line: line_index + 1,
+                    error,
+                })?;
+            wallet.import_address(&address);
+        }
+        Ok(wallet)
+    }
+    /// Writes the addresses next to `path` then renames the file, so a crash never leaves
+    /// a half written wallet.
+    pub fn save(&self, path: &Path, network: Network) -> Result<(), WatchOnlyError> {
+        let mut text = String::new();
+        let pubkeys = self
+            .pubkeys
+            .iter()
+            .map(|pubkey| AddressPayload::PubKey(*pubkey));
+        for payload in pubkeys.chain(self.addresses.iter().copied()) {
+            text.push_str(&Address::new(network, payload).to_string());
+            text.push('\n');
+        }
+        let temporary_path = path.with_extension("tmp");
+        fs::write(&temporary_path, text).map_err(WatchOnlyError::Io)?;
+        fs::rename(&temporary_path, path).map_err(WatchOnlyError::Io)
+    }
+    /// Returns false when the pubkey was already imported.
+    pub fn import_pubkey(&mut self, pubkey: VerifyingKey) -> bool {
+        if self.pubkeys.contains(&pubkey) {
+            return false;
+        }
+        // its address is now watched with the pubkey
+        let hash = AddressPayload::PubKeyHash(pubkey_hash(&pubkey));
+        self.addresses.retain(|address| address != &hash);
+        self.pubkeys.push(pubkey);
+        true
+    }
+    /// Imports the pubkey of a pay-to-pubkey address, else watches the address alone.
+    /// Returns false when it was already watched.
+    pub fn import_address(&mut self, address: &Address) -> bool {
+        match address.get_payload() {
+            AddressPayload::PubKey(pubkey) => self.import_pubkey(*pubkey),
+            payload => {
+                if self.watches(address) {
+                    return false;
+                }
+                self.addresses.push(*payload);
+                true
+            }
+        }
+    }
+    /// Number of pubkeys and addresses watched.
+    pub fn len(&self) -> usize {
+        self.pubkeys.len() + self.addresses.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pubkeys.is_empty() && self.addresses.is_empty()
+    }
+    pub fn get_pubkeys(&self) -> &[VerifyingKey] {
+        &self.pubkeys
+    }
+    /// The pay-to-pubkey-hash addresses of the pubkeys, then the addresses imported alone.
+    pub fn get_addresses(&self, network: Network) -> Vec<Address> {
+        self.pubkeys
+            .iter()
+            .map(|pubkey| Address::from_pubkey_hash(network, pubkey))
+            .chain(
+                self.addresses
+                    .iter()
+                    .map(|payload| Address::new(network, *payload)),
+            )
+            .collect()
+    }
+    /// What pays the wallet: its pubkeys, directly or by hash, and its addresses.
+    pub fn get_payees(&self) -> HashSet<AddressPayload> {
+        let mut payees = payees_of(&self.pubkeys);
+        payees.extend(self.addresses.iter().copied());
+        payees
+    }
+    pub fn watches(&self, address: &Address) -> bool {
+        self.get_payees().contains(address.get_payload())
+    }
+    /// Unspent outputs paying the wallet, in no particular order.
+    pub fn unspent<'a>(&self, utxos: &'a UTXOMap) -> Vec<(&'a Input, &'a Output)> {
+        self.get_payees()
+            .iter()
+            .flat_map(|payee| utxos.unspent_to(payee))
+            .collect()
+    }
+    pub fn balance(&self, utxos: &UTXOMap) -> u64 {
+        self.unspent(utxos)
+            .iter()
+            .map(|(_, output)| output.get_amount())
+            .sum()
+    }
+}
+
+#[derive(Debug)]
+pub enum WatchOnlyError {
+    Io(io::Error),
+    InvalidAddress { line: usize, error: AddressError },
+}
+impl fmt::Display for WatchOnlyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "cannot access the watch-only wallet file: {error}"),
+            Self::InvalidAddress { line, error } => write!(f, "line {line}: {error}"),
+        }
+    }
+}
+impl std::error::Error for WatchOnlyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            Self::InvalidAddress { error, .. } => Some(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+
+    use super::*;
+    use crate::{
+        test_utils::{add_block, regtest_chain, signing_key},
+        transactions::transaction::{RawTransaction, SignedTransaction},
+        wallets::wallet::Wallet,
+    };
+
+    fn random_output(rng: &mut StdRng, amount: u64) -> Output {
+        let key = signing_key(rng.gen_range(1..=6)).verifying_key();
+        if rng.gen_bool(0.5) {
+            Output::new(key, amount)
+        } else {
+            Output::new_pubkey_hash(pubkey_hash(&key), amount)
+        }
+    }
+
+    // transactions of random keys spending some of their coins to random keys
+    fn random_payments(rng: &mut StdRng, utxos: &UTXOMap) -> Vec<SignedTransaction> {
+        let mut spent = HashSet::new();
+        let mut transactions = vec![];
+        for _ in 0..rng.gen_range(0..4) {
+            let signer: SigningKey = signing_key(rng.gen_range(1..=6));
+            let mut coins: Vec<(Input, u64)> = utxos
+                .unspent_of(&signer.verifying_key())
+                .filter(|(input, _)| !spent.contains(*input))
+                .map(|(input, output)| (*input, output.get_amount()))
+                .collect();
+            coins.sort_unstable_by_key(|(input, _)| (*input.get_tx_id(), input.get_tx_idx()));
+            coins.shuffle(rng);
+            coins.truncate(2);
+            if coins.is_empty() {
+                continue;
+            }
+            let total: u64 = coins.iter().map(|(_, amount)| amount).sum();
+            let fee = rng.gen_range(0..=total / 100);
+            let split = rng.gen_range(1..total - fee);
+            let outputs = vec![
+                random_output(rng, split),
+                random_output(rng, total - fee - split),
+            ];
+            let inputs = coins.iter().map(|(input, _)| *input).collect();
+            spent.extend(coins.iter().map(|(input, _)| *input));
+            transactions
+                .push(RawTransaction::new(inputs, outputs, signer.verifying_key()).sign(&signer));
+        }
+        transactions
+    }
+
+    #[test]
+    fn the_balance_is_the_one_of_the_full_wallet() {
+        let mut rng = StdRng::seed_from_u64(50);
+        let mut wallet = Wallet::new();
+        for seed in 1..=3 {
+            wallet.add_key(signing_key(seed));
+        }
+        let watch_only = wallet.to_watch_only();
+        // the addresses first, their pubkeys then replace them
+        let mut by_address = WatchOnlyWallet::new();
+        for address in wallet.get_addresses(Network::Regtest) {
+            assert!(by_address.import_address(&address));
+            assert!(!by_address.import_address(&address));
+        }
+        let mut hashes_only = WatchOnlyWallet::new();
+        for address in wallet.get_addresses(Network::Regtest) {
+            hashes_only.import_address(&address);
+        }
+        for pubkey in wallet.get_pubkeys() {
+            assert!(by_address.import_address(&Address::from_pubkey(Network::Regtest, &pubkey)));
+        }
+        assert_eq!(by_address.len(), 3);
+        assert_eq!(by_address.get_pubkeys(), watch_only.get_pubkeys());
+
+        let mut chain = regtest_chain();
+        let mut paid_by_hash = false;
+        let mut transaction_count = 0;
+        for _ in 0..40 {
+            let miner = signing_key(rng.gen_range(1..=6));
+            let transactions = random_payments(&mut rng, chain.get_utxos());
+            transaction_count += transactions.len();
+            add_block(&mut chain, &miner, transactions);
+
+            let utxos = chain.get_utxos();
+            let owned: HashSet<Input> = wallet
+                .get_pubkeys()
+                .iter()
+                .flat_map(|pubkey| utxos.unspent_of(pubkey))
+                .map(|(input, _)| *input)
+                .collect();
+            for watching in [&watch_only, &by_address] {
+                assert_eq!(watching.balance(utxos), wallet.balance(utxos));
+                let unspent: HashSet<Input> = watching
+                    .unspent(utxos)
+                    .iter()
+                    .map(|(input, _)| **input)
+                    .collect();
+                assert_eq!(unspent, owned);
+            }
+            // the addresses alone follow the coins paid by hash only
+            let by_hash: u64 = hashes_only
+                .unspent(utxos)
+                .iter()
+                .map(|(_, output)| {
+                    assert!(matches!(output.get_payee(), AddressPayload::PubKeyHash(_)));
+                    output.get_amount()
+                })
+                .sum();
+            assert_eq!(hashes_only.balance(utxos), by_hash);
+            paid_by_hash |= by_hash > 0;
+        }
+        assert!(paid_by_hash);
+        assert!(transaction_count > 30);
+        assert!(wallet.balance(chain.get_utxos()) > hashes_only.balance(chain.get_utxos()));
+    }
+
+    #[test]
+    fn a_saved_wallet_is_renamed_into_place() {
+        let directory = std::env::temp_dir().join(format!("watch-only-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("watch.txt");
+        let mut wallet = WatchOnlyWallet::from_pubkeys([signing_key(1).verifying_key()]);
+        wallet.import_address(&Address::new(
+            Network::Regtest,
+            AddressPayload::PubKeyHash(pubkey_hash(&signing_key(2).verifying_key())),
+        ));
+        wallet.save(&path, Network::Regtest).unwrap();
+        // an earlier save is replaced as a whole
+        wallet.import_pubkey(signing_key(3).verifying_key());
+        wallet.save(&path, Network::Regtest).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+        let loaded = WatchOnlyWallet::load(&path, Network::Regtest).unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded.get_pubkeys(), wallet.get_pubkeys());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}